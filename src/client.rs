@@ -17,23 +17,29 @@
 extern crate log;
 extern crate tokio;
 extern crate tokio_native_tls;
+use crate::UTF8_POLICY;
 use crate::io::{ReadHalfWrap, WriteHalfWrap};
 use crate::irc::error::Error as ircError;
 use crate::irc::reply::Reply as ircReply;
 use crate::irc::reply as reply;
-use crate::irc::{self, Core, User, NamedEntity};
+use crate::irc::rfc_defs as rfc;
+use crate::irc::{self, Core, User};
 use crate::parser::{parse_message, ParseError};
 use crate::irc::chan::ChanError;
+use std::collections::BTreeSet;
 use std::error;
 use std::fmt;
 use std::io::Error as ioError;
+use std::io::ErrorKind as ioErrorKind;
 use std::net::IpAddr;
 use std::sync::{Arc, Weak, Mutex};
-use log::{debug, warn};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::{mpsc, Notify};
 use tokio::sync::mpsc::error::SendError as mpscSendErr;
 use tokio::task::JoinError as tokJoinErr;
+use tokio::time;
 use tokio_native_tls::native_tls::Error as tntTlsErr;
 
 /* There are 3 main types of errors we can have here...
@@ -155,7 +161,7 @@ pub enum ClientType {
     Dead,
     Unregistered,
     User(Arc<irc::User>),
-    //Server(Arc<Mutex<irc::Server>>), leave serv implmentation for much later
+    Server(Arc<irc::Server>),
     ProtoUser(Arc<Mutex<irc::ProtoUser>>),
 }
 
@@ -165,6 +171,7 @@ impl Clone for ClientType {
             ClientType::Dead => ClientType::Dead,
             ClientType::Unregistered => ClientType::Unregistered,
             ClientType::User(user_ptr) => ClientType::User(Arc::clone(user_ptr)),
+            ClientType::Server(server_ptr) => ClientType::Server(Arc::clone(server_ptr)),
             ClientType::ProtoUser(proto_user_ptr) => {
                 ClientType::ProtoUser(Arc::clone(proto_user_ptr))
             }
@@ -177,17 +184,63 @@ pub type ClientReply = Result<ircReply, ircError>;
 pub type ClientReplies = Vec<ClientReply>;
 
 pub async fn run_write_task(sock: WriteHalfWrap, mut rx: MsgRecvr) -> Result<(), ioError> {
-    /* apparently we can't have ? after await on any of these
-     * functions, because await returns (), but recv() and
-     * write_all()/flush() shouldn't return (), should they? */
+    /* lines pile up in the BufWriter, which writes out what it's already
+     * holding when the next line won't fit, then gets flushed once there's
+     * nothing else waiting on the channel */
     let mut stream = BufWriter::new(sock);
     while let Some(msg) = rx.recv().await {
-        stream.write(msg.as_bytes()).await?;
+        let mut next = Some(msg);
+        while let Some(msg) = next {
+            stream.write_all(fit_line(&msg).as_bytes()).await?;
+            next = rx.try_recv().ok();
+        }
         stream.flush().await?;
     }
     Ok(())
 }
 
+/* a single line over MAX_MSG_SIZE means something upstream didn't split it
+ * properly (e.g. a relayed PRIVMSG that grew a prefix), so complain and cut
+ * it down to size rather than send the client something illegal */
+fn fit_line(msg: &str) -> String {
+    if msg.len() <= rfc::MAX_MSG_SIZE {
+        return msg.to_string();
+    }
+    warn!("outbound line of {} bytes is over the {} byte limit, truncating", msg.len(), rfc::MAX_MSG_SIZE);
+    let mut end = rfc::MAX_MSG_SIZE - 2;
+    while !msg.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\r\n", &msg[..end])
+}
+
+/* every `interval`, PING anyone who has been quiet for that long, and hang
+ * up on anyone who has stayed quiet for a further `timeout` after that */
+pub async fn run_ping_task(irc: Arc<Core>, interval: Duration, timeout: Duration) {
+    let mut timer = time::interval(interval);
+    let ping = format!("PING :{}", irc.get_host());
+    loop {
+        timer.tick().await;
+        irc.expire_parked().await;
+        let timed_out = irc.check_idle_clients(interval + timeout);
+        for id in timed_out.iter() {
+            if let Some(client) = irc.get_client(id).and_then(|weak| Weak::upgrade(&weak)) {
+                let reason = format!("Ping timeout: {} seconds", (interval + timeout).as_secs());
+                client.send_error_and_close(&reason).await;
+            }
+        }
+
+        /* no point PINGing anyone we've just said goodbye to */
+        for id in irc.check_idle_clients(interval).iter().filter(|id| !timed_out.contains(id)) {
+            if let Some(client) = irc.get_client(id).and_then(|weak| Weak::upgrade(&weak)) {
+                if let Err(err) = client.send_line(&ping).await {
+                    debug!("failed to PING client {}: {}", id, err);
+                }
+            }
+        }
+    }
+}
+
 pub async fn run_client_handler(
     id: u64,
     host: Host,
@@ -198,6 +251,9 @@ pub async fn run_client_handler(
     let mut handler = ClientHandler::new(id, host, &irc, tx, sock);
     irc.insert_client(handler.id, Arc::downgrade(&handler.client));
     debug!("assigned client id {}", handler.id);
+    for observer in irc.get_observers().iter() {
+        observer.on_connect(&handler.client);
+    }
 
     /* would it be ridic to spawn a new process for every
      * message received from the user, and if we did that
@@ -212,6 +268,18 @@ pub async fn run_client_handler(
      * asynchronously or not? */
     let res = process_lines(&mut handler, &irc).await;
 
+    /* a registered user who didn't QUIT may be back with a RESUME, and a
+     * server link that just dropped (rather than SQUIT) still takes its
+     * users with it, everyone sharing a channel with them needs telling */
+    match handler.client.get_client_type() {
+        ClientType::User(user) => irc.park_user(user),
+        ClientType::Server(server) => {
+            handler.client.set_client_type(ClientType::Dead);
+            irc.split_server(&server).await;
+        },
+        _ => (),
+    }
+
     /* the main listener loop doesn't .await for the return
      * of this function, so it doesn't make sense to have any
      * return value, instead some diagnostics should be printed
@@ -219,7 +287,7 @@ pub async fn run_client_handler(
     if let Err(err) = res {
         debug!("Client {} exited with error {}", handler.id, err);
     } else {
-        debug!("{}", "Unexpected EOF".to_string());
+        debug!("Client {} closed the connection", handler.id);
     }
     /* All the cleanup stuff should just happen on Drop, so I've commented
      * a bunch out for now */
@@ -286,11 +354,106 @@ pub async fn run_client_handler(
     }*/
 }
 
+/* what to do about a line from a client that isn't valid UTF-8 */
+#[derive(Debug, Clone, Copy)]
+pub enum Utf8Policy {
+    Lossy,
+    Strict,
+}
+
+/* chop off the line ending and decode, the parser only ever gets a String
+ * so this is the one place invalid UTF-8 has to be dealt with */
+fn decode_line(mut bytes: &[u8], policy: Utf8Policy) -> Option<String> {
+    if let Some(rest) = bytes.strip_suffix(b"\n") {
+        bytes = rest;
+    }
+    if let Some(rest) = bytes.strip_suffix(b"\r") {
+        bytes = rest;
+    }
+    match policy {
+        Utf8Policy::Lossy => Some(String::from_utf8_lossy(bytes).into_owned()),
+        Utf8Policy::Strict => String::from_utf8(bytes.to_vec()).ok(),
+    }
+}
+
+/* how reading the next line off the socket went */
+enum LineRead {
+    Line,
+    TooLong,
+    Closed,
+}
+
+/* read up to the next LF, but without buffering more than a line could
+ * legally be - a line that goes past that is thrown away, up to and
+ * including its LF, and reported as too long instead of being half parsed */
+async fn read_line(stream: &mut BufReader<ReadHalfWrap>, buf: &mut Vec<u8>) -> Result<LineRead, ioError> {
+    let limit = rfc::MAX_MSG_SIZE + rfc::MAX_TAGS_SIZE;
+    let n_read = (&mut *stream).take(limit as u64).read_until(b'\n', buf).await?;
+    if n_read == 0 {
+        return Ok(LineRead::Closed);
+    }
+    if n_read == limit && !buf.ends_with(b"\n") {
+        let mut discard = Vec::new();
+        loop {
+            discard.clear();
+            let n_read = (&mut *stream).take(limit as u64).read_until(b'\n', &mut discard).await?;
+            if n_read == 0 || discard.ends_with(b"\n") {
+                break;
+            }
+        }
+        return Ok(LineRead::TooLong);
+    }
+    /* tags get their own allowance, the rest has to fit the usual 512 */
+    let message = match buf.strip_prefix(b"@") {
+        Some(tagged) => match tagged.iter().position(|byte| *byte == b' ') {
+            Some(idx) => &tagged[idx + 1..],
+            None => tagged,
+        },
+        None => &buf[..],
+    };
+    if message.len() > rfc::MAX_MSG_SIZE {
+        return Ok(LineRead::TooLong);
+    }
+    Ok(LineRead::Line)
+}
+
 /* Receive and process IRC messages */
 async fn process_lines(handler: &mut ClientHandler, irc: &Arc<Core>) -> Result<(), GenError> {
-    while let Some(line) = handler.stream.next_line().await? {
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let bytes = tokio::select! {
+            line_read = read_line(&mut handler.stream, &mut buf) => match line_read? {
+                LineRead::Closed => break,
+                LineRead::TooLong => {
+                    handler.client.touch();
+                    handler.client.send_err(ircError::InputTooLong).await?;
+                    continue;
+                },
+                LineRead::Line => &buf,
+            },
+            _ = handler.client.hangup.notified() => {
+                return Err(GenError::Io(ioError::new(ioErrorKind::ConnectionAborted, "Link closed by server")));
+            },
+        };
+        /* any traffic at all counts as a sign of life */
+        handler.client.touch();
+        let line = match decode_line(bytes, UTF8_POLICY) {
+            Some(line) => line,
+            None => {
+                debug!("client {} sent a line that isn't valid UTF-8, dropped", handler.id);
+                handler.client.send_notice("Line dropped, it isn't valid UTF-8").await?;
+                continue;
+            }
+        };
         if line.is_empty() { continue }
-        match error_wrapper(&handler.client, irc, &line).await {
+        let result = error_wrapper(&handler.client, irc, &line).await;
+        if let Err(err) = &result {
+            if !matches!(err, GenError::IRC(_)) {
+                info!("{} command={} error: {}", ClientContext(&handler.client), command_name(&line), err);
+            }
+        }
+        match result {
             Err(GenError::IRC(err)) => handler.client.send_err(err).await?,
             Err(GenError::Parse(err)) => handler.client.send_err(ircError::from(err)).await?,
             Err(GenError::Chan(_err)) => (), /* non-fatal, will figure out how to handle later */
@@ -299,7 +462,7 @@ async fn process_lines(handler: &mut ClientHandler, irc: &Arc<Core>) -> Result<(
             Err(GenError::DeadClient(user)) => attempt_cleanup(irc, user),
             Err(GenError::DeadUser(nick)) => {
                 let _res = irc.search_user_chans_purge(&nick);
-                if let Err(err) = irc.remove_name(&nick) {
+                if let Err(err) = irc.remove_user(&nick) {
                     warn!("received error {} trying to remove dead user {}", err, nick.to_string());
                 }
             },
@@ -314,10 +477,37 @@ async fn process_lines(handler: &mut ClientHandler, irc: &Arc<Core>) -> Result<(
                 }
             },
         }
+
+        /* QUIT (or anything else that killed the client off) ends the session */
+        if let ClientType::Dead = handler.client.get_client_type() {
+            break;
+        }
     }
     Ok(())
 }
 
+/* who a log line is about, as `client=<id> nick=<nick> host=<host>`, the
+ * nick being `*` before registration */
+pub struct ClientContext<'a>(pub &'a Client);
+
+impl fmt::Display for ClientContext<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nick = match self.0.get_client_type() {
+            ClientType::User(user) => user.get_nick(),
+            _ => String::from("*"),
+        };
+        write!(f, "client={} nick={} host={}", self.0.get_id(), nick, self.0.get_host_string())
+    }
+}
+
+/* the command word of a raw line, past any tags and prefix */
+fn command_name(line: &str) -> &str {
+    line.split(' ')
+        .filter(|word| !word.is_empty())
+        .find(|word| !word.starts_with('@') && !word.starts_with(':'))
+        .unwrap_or("*")
+}
+
 /* wrapping these two fn calls in this function allows easy error composition,
  * and let's the caller process_lines() catch any errors, relaying parser or
  * IRC errors back to the client, or dropping the client on I/O error */
@@ -346,7 +536,7 @@ pub fn attempt_cleanup(irc: &Core, user: Arc<User>) {
         
     /* irc Core namespace HashMap */
     let nick = user.get_nick();
-    if let Ok(NamedEntity::User(_user_weak)) = irc.remove_name(&nick) {
+    if let Ok(_user_weak) = irc.remove_user(&nick) {
         debug!("remove user ptr of {} from IRC namespace hashmap", nick);
     } else {
         debug!("user ptr for {} has already been removed from IRC namespace/hash table", nick);
@@ -366,7 +556,7 @@ pub fn attempt_cleanup(irc: &Core, user: Arc<User>) {
 
 #[derive(Debug)]
 pub struct ClientHandler {
-    stream: Lines<BufReader<ReadHalfWrap>>,
+    stream: BufReader<ReadHalfWrap>,
     client: Arc<Client>,
     id: u64,
 }
@@ -374,7 +564,7 @@ pub struct ClientHandler {
 impl ClientHandler {
     pub fn new(id: u64, host: Host, irc: &Arc<Core>, tx: MsgSendr, sock: ReadHalfWrap) -> Self {
         ClientHandler {
-            stream: BufReader::new(sock).lines(),
+            stream: BufReader::new(sock),
             client: Client::new(id, host, irc, tx),
             id,
         }
@@ -390,6 +580,36 @@ pub struct Client {
     host: Host,
     irc: Arc<Core>,
     tx: MsgSendr,
+    last_active: Mutex<Instant>,
+    hangup: Notify,
+    caps: Mutex<BTreeSet<String>>,
+    /* a CAP LS or REQ came in before registering, and no CAP END yet */
+    cap_pending: Mutex<bool>,
+    password: Mutex<Option<String>>,
+    flood: Mutex<FloodBucket>,
+}
+
+/* token bucket for flood control, topped up at the configured rate a
+ * second up to the burst size, every line takes one token. strikes counts lines dropped
+ * in a row for want of a token */
+#[derive(Debug, Clone)]
+struct FloodBucket {
+    tokens: f64,
+    last_refill: Instant,
+    strikes: u32,
+}
+
+impl FloodBucket {
+    fn new(burst: f64) -> Self {
+        FloodBucket { tokens: burst, last_refill: Instant::now(), strikes: 0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloodCheck {
+    Allowed,
+    Throttled,
+    Excess,
 }
 
 impl Clone for Client {
@@ -400,13 +620,22 @@ impl Clone for Client {
             host: self.host.clone(),
             irc: Arc::clone(&self.irc),
             tx: self.tx.clone(),
+            last_active: Mutex::new(*self.last_active.lock().unwrap()),
+            hangup: Notify::new(),
+            caps: Mutex::new(self.caps.lock().unwrap().clone()),
+            cap_pending: Mutex::new(*self.cap_pending.lock().unwrap()),
+            password: Mutex::new(self.password.lock().unwrap().clone()),
+            flood: Mutex::new(self.flood.lock().unwrap().clone()),
         }
     }
 }
 
 impl Drop for Client {
     fn drop (&mut self) {
-        *self.client_type.lock().unwrap() = ClientType::Dead;
+        let client_type = std::mem::replace(&mut *self.client_type.lock().unwrap(), ClientType::Dead);
+        if let ClientType::Server(server) = client_type {
+            self.irc.remove_server(&server.get_name());
+        }
         self.irc.remove_client(&self.id);
     }
 }
@@ -419,6 +648,12 @@ impl Client {
             host,
             irc: Arc::clone(irc),
             tx,
+            last_active: Mutex::new(Instant::now()),
+            hangup: Notify::new(),
+            caps: Mutex::new(BTreeSet::new()),
+            cap_pending: Mutex::new(false),
+            password: Mutex::new(None),
+            flood: Mutex::new(FloodBucket::new(irc.flood_limits().burst)),
         })
     }
 
@@ -438,6 +673,8 @@ impl Client {
         match self.get_client_type() {
             ClientType::Dead => false,
             ClientType::User(_p) => true,
+            /* as far as user commands go, anyway */
+            ClientType::Server(_p) => false,
             ClientType::ProtoUser(_p) => false,
             ClientType::Unregistered => false,
         }
@@ -467,6 +704,38 @@ impl Client {
         &self.irc
     }
 
+    /* note that the client has just shown signs of life */
+    pub fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    pub fn idle_time(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+
+    /* wake up this client's handler task and have it drop the connection */
+    pub fn hang_up(&self) {
+        self.hangup.notify_one();
+    }
+
+    /* tell the client why we're letting go of them, then hang up. Whether
+     * the user gets parked is still down to the client type, so callers who
+     * don't want that mark the client Dead first */
+    pub async fn send_error_and_close(&self, reason: &str) {
+        let text = format!("Closing Link: {} ({})", self.get_host_string(), reason);
+        self.close_with_error(&text).await;
+    }
+
+    /* the same with the ERROR text as given, e.g. `ERROR :Bad password` */
+    pub async fn close_with_error(&self, text: &str) {
+        debug!("closing link to client {}: {}", self.id, text);
+        let line = format!("ERROR :{}", text);
+        if let Err(err) = self.send_line(&line).await {
+            debug!("couldn't send ERROR to client {}: {}", self.id, err);
+        }
+        self.hang_up();
+    }
+
     pub async fn send_err(&self, err: ircError) -> Result<(), GenError> {
         let line = format!(":{} {}", self.irc.get_host(), err);
         /* passing to an async fn and awaiting on it is gonna
@@ -475,10 +744,88 @@ impl Client {
         Ok(())
     }
     
+    /* numerics and notices go to `*` until there's a nick to address them to */
+    fn get_recipient(&self) -> String {
+        match self.get_client_type() {
+            ClientType::User(user) => user.get_nick(),
+            _ => String::from("*"),
+        }
+    }
+
+    /* whatever was given with PASS, checked when registration completes */
+    pub fn get_password(&self) -> Option<String> {
+        self.password.lock().unwrap().clone()
+    }
+
+    pub fn set_password(&self, password: Option<String>) {
+        *self.password.lock().unwrap() = password;
+    }
+
+    /* spend a token on the line that's just come in, if there's one to
+     * spend. The refill and the spend happen under the one lock */
+    pub fn check_flood(&self) -> FloodCheck {
+        let limits = self.irc.flood_limits();
+        let mut bucket = self.flood.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.last_refill).as_secs_f64() * limits.rate;
+        bucket.tokens = (bucket.tokens + refill).min(limits.burst);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.strikes = 0;
+            return FloodCheck::Allowed;
+        }
+        bucket.strikes += 1;
+        if bucket.strikes >= limits.excess {
+            FloodCheck::Excess
+        } else {
+            FloodCheck::Throttled
+        }
+    }
+
+    /* the capabilities this connection has switched on */
+    pub fn get_caps(&self) -> Vec<String> {
+        self.caps.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn set_cap(&self, cap: &str, on: bool) {
+        let mut caps = self.caps.lock().unwrap();
+        if on {
+            caps.insert(cap.to_string());
+        } else {
+            caps.remove(cap);
+        }
+    }
+
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.lock().unwrap().contains(cap)
+    }
+
+    pub fn is_cap_pending(&self) -> bool {
+        *self.cap_pending.lock().unwrap()
+    }
+
+    pub fn set_cap_pending(&self, pending: bool) {
+        *self.cap_pending.lock().unwrap() = pending;
+    }
+
+    pub async fn send_cap(&self, subcommand: &str, caps: &[String]) -> Result<(), GenError> {
+        let line = format!(":{} CAP {} {} :{}", self.irc.get_host(), self.get_recipient(), subcommand, caps.join(" "));
+        self.send_line(&line).await?;
+        Ok(())
+    }
+
+    pub async fn send_notice(&self, text: &str) -> Result<(), GenError> {
+        let line = format!(":{} NOTICE {} :{}", self.irc.get_host(), self.get_recipient(), text);
+        self.send_line(&line).await?;
+        Ok(())
+    }
+
     pub async fn send_rpl(&self, reply: ircReply) -> Result<(), GenError> { /* GDB+ */
         /* passing to an async fn and awaiting on it is gonna
          * cause lifetime problems with a &str... */
-        let mut line = reply.format(&self.irc.get_host(), &self.get_user().get_nick());
+        let nick = self.get_recipient();
+        let mut line = reply.format(&self.irc.get_host(), &nick);
         /* break up long messages if neccessary,
          * reply::split essentially returns line, None when
          * line is not larger than MAX_MSG_SIZE */
@@ -510,4 +857,81 @@ pub fn create_host_string(host_var: &Host) -> String {
         Host::Hostname(hostname_str) => hostname_str.to_string(),
         Host::HostAddr(ip_addr) => ip_addr.to_string(),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::split;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn fit_line_leaves_short_lines_alone() {
+        let line = format!(":irc.test NOTICE bob :{}\r\n", "x".repeat(400));
+        assert_eq!(fit_line(&line), line);
+        let line = format!("{}\r\n", "x".repeat(rfc::MAX_MSG_SIZE - 2));
+        assert_eq!(fit_line(&line), line);
+    }
+
+    #[test]
+    fn fit_line_cuts_an_oversized_line_down() {
+        let line = format!(":irc.test NOTICE bob :{}\r\n", "x".repeat(600));
+        let fitted = fit_line(&line);
+        assert_eq!(fitted.len(), rfc::MAX_MSG_SIZE);
+        assert!(fitted.ends_with("x\r\n"));
+        assert!(line.starts_with(&fitted[..fitted.len() - 2]));
+    }
+
+    /* the cut mustn't land in the middle of a char */
+    #[test]
+    fn fit_line_cuts_on_a_char_boundary() {
+        let line = format!("{}\r\n", "é".repeat(400));
+        let fitted = fit_line(&line);
+        assert!(fitted.len() <= rfc::MAX_MSG_SIZE);
+        assert!(fitted.trim_end_matches("\r\n").chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn decode_line_lossy_patches_bad_bytes() {
+        assert_eq!(decode_line(b"PRIVMSG #a :caf\xe9!\r\n", Utf8Policy::Lossy).as_deref(), Some("PRIVMSG #a :caf\u{fffd}!"));
+        assert_eq!(decode_line(b"PRIVMSG #a :caf\xc3\xa9\n", Utf8Policy::Lossy).as_deref(), Some("PRIVMSG #a :caf\u{e9}"));
+    }
+
+    #[test]
+    fn decode_line_strict_refuses_bad_bytes() {
+        assert_eq!(decode_line(b"PRIVMSG #a :caf\xe9!\r\n", Utf8Policy::Strict), None);
+        assert_eq!(decode_line(b"\xff\xfe\r\n", Utf8Policy::Strict), None);
+        assert_eq!(decode_line(b"PRIVMSG #a :caf\xc3\xa9\r\n", Utf8Policy::Strict).as_deref(), Some("PRIVMSG #a :caf\u{e9}"));
+    }
+
+    /* far more than the BufWriter holds, so it has to write out what it's
+     * got part way through, and every line still arrives whole and in
+     * order */
+    #[tokio::test]
+    async fn write_task_gets_every_line_through_a_full_buffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (sock, _addr) = listener.accept().await.unwrap();
+        let (_read, write) = split(sock);
+        let (tx, rx) = mpsc::channel(32);
+        let writer = tokio::spawn(run_write_task(WriteHalfWrap::ClearText(write), rx));
+        let lines: Vec<String> = (0..200).map(|n| format!("{:03} {}", n, "y".repeat(400))).collect();
+        let sender = {
+            let lines = lines.clone();
+            tokio::spawn(async move {
+                for line in lines.iter() {
+                    tx.send(format!("{}\r\n", line)).await.unwrap();
+                }
+                tx.send(format!("{}\r\n", "z".repeat(700))).await.unwrap();
+            })
+        };
+
+        let mut received = BufReader::new(stream).lines();
+        for line in lines.iter() {
+            assert_eq!(received.next_line().await.unwrap().as_ref(), Some(line));
+        }
+        let last = received.next_line().await.unwrap().unwrap();
+        assert_eq!(last, "z".repeat(rfc::MAX_MSG_SIZE - 2));
+        sender.await.unwrap();
+        assert!(writer.await.unwrap().is_ok());
+    }
+}
@@ -14,25 +14,41 @@
 *  You should have received a copy of the GNU Lesser General Public License
 *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
+pub mod audit;
 pub mod chan;
 pub mod error;
+pub mod event;
+pub mod password;
 pub mod reply;
 pub mod rfc_defs;
-use crate::{USER_MODES, CHAN_MODES};
+pub mod state;
+#[cfg(test)]
+pub mod testing;
+#[cfg(test)]
+mod tests;
+use crate::{USER_MODES, CHAN_MODES, NETWORK_NAME, SERVER_INFO, NICK_CHANGE_LIMIT, NICK_CHANGE_WINDOW_SECS, KNOCK_LIMIT, KNOCK_WINDOW_SECS, KNOCK_CHANNEL_DELAY_SECS, MAX_SILENCE, WHOWAS_HISTORY, ADMIN_EMAIL, ADMIN_LOCATION, ADMIN_LOCATION2, CAPABILITIES, MAX_CHANNELS, MAX_TARGETS, CLOAK_HOSTS, CLOAK_SECRET, OPERATORS, OPERS_SEE_INVISIBLE, TRUNCATE_LONG_NICKS, QUIT_PREFIX, RESUME_WINDOW_SECS, SERVER_PASSWORD, SERVER_LINKS, AUDIT_LOG_PATH, MOTD_PATH, INVITE_EXPIRY_SECS, FLOOD_RATE, FLOOD_BURST, FLOOD_EXCESS, CONFIG_PATH};
 use crate::client;
-use crate::client::{Client, ClientType, ClientReply, ClientReplies, GenError, Host};
+use crate::client::{Client, ClientType, ClientReply, ClientReplies, FloodCheck, GenError, Host};
 use crate::irc::chan::{ChanFlags, Channel, ChanTopic};
 use crate::irc::error::Error as ircError;
+use crate::irc::event::Observer;
 use crate::irc::reply::Reply as ircReply;
 use crate::irc::rfc_defs as rfc;
-use crate::parser::ParsedMsg;
+use crate::parser::{MsgPrefix, ParsedMsg};
 extern crate log;
 extern crate chrono;
-use chrono::Utc;
-use log::{debug, warn, trace};
+use chrono::{Local, Utc};
+use log::{debug, error, info, warn, trace};
+use sha2::{Digest, Sha256};
 use std::clone::Clone;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex, Weak};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 
 
 macro_rules! gef {
@@ -56,7 +72,10 @@ impl Clone for NamedEntity {
 
 #[derive(Debug, Clone)]
 pub struct UserFlags {
-    registered: bool
+    registered: bool,
+    oper: bool,
+    invisible: bool,
+    wallops: bool,
 }
 
 #[derive(Debug)]
@@ -66,11 +85,21 @@ pub struct User {
     username: String,
     real_name: Mutex<String>,
     host: Host,
+    cloak: Option<String>,
     server: String,
     channel_list: Mutex<HashMap<String, Weak<Channel>>>,
     flags: Mutex<UserFlags>,
+    resume_token: Mutex<Option<String>>,
+    away_msg: Mutex<Option<String>>,
+    silence: Mutex<Vec<String>>,
+    /* when the last few NICK changes happened, for the rate limit */
+    nick_changes: Mutex<VecDeque<Instant>>,
+    /* the same again for KNOCK */
+    knocks: Mutex<VecDeque<Instant>>,
+    /* when they first turned up, wherever that was, for nick collisions */
+    signon: i64,
     irc: Arc<Core>,
-    client: Weak<Client>,
+    client: Mutex<Weak<Client>>,
 }
 
 impl Clone for User {
@@ -81,11 +110,18 @@ impl Clone for User {
             username: self.username.clone(),
             real_name: Mutex::new(self.real_name.lock().unwrap().clone()),
             host: self.host.clone(),
+            cloak: self.cloak.clone(),
             server: self.server.clone(),
             channel_list: Mutex::new(self.channel_list.lock().unwrap().clone()),
             flags: Mutex::new(self.flags.lock().unwrap().clone()),
+            resume_token: Mutex::new(self.resume_token.lock().unwrap().clone()),
+            away_msg: Mutex::new(self.away_msg.lock().unwrap().clone()),
+            silence: Mutex::new(self.silence.lock().unwrap().clone()),
+            nick_changes: Mutex::new(self.nick_changes.lock().unwrap().clone()),
+            knocks: Mutex::new(self.knocks.lock().unwrap().clone()),
+            signon: self.signon,
             irc: Arc::clone(&self.irc),
-            client: Weak::clone(&self.client)
+            client: Mutex::new(Weak::clone(&self.client.lock().unwrap()))
         }
     }
 }
@@ -114,11 +150,84 @@ impl User {
             nick: Mutex::new(nick),
             username,
             real_name: Mutex::new(real_name),
+            cloak: irc.cloak_host(&host),
             host,
             server,
             channel_list: Mutex::new(HashMap::new()),
-            client: Arc::downgrade(client),
-            flags: Mutex::new(UserFlags { registered: true }), /*channel_list: Mutex::new(Vec::new())*/
+            client: Mutex::new(Arc::downgrade(client)),
+            flags: Mutex::new(UserFlags { registered: true, oper: false, invisible: false, wallops: false }), /*channel_list: Mutex::new(Vec::new())*/
+            resume_token: Mutex::new(None),
+            away_msg: Mutex::new(None),
+            silence: Mutex::new(Vec::new()),
+            nick_changes: Mutex::new(VecDeque::new()),
+            knocks: Mutex::new(VecDeque::new()),
+            signon: Utc::now().timestamp(),
+        })
+    }
+
+    /* a user brought back from a state blob, with no connection of their
+     * own until they RESUME */
+    pub fn restore(
+        id: u64,
+        irc: &Arc<Core>,
+        nick: String,
+        username: String,
+        real_name: String,
+        host: client::Host,
+        server: String,
+    ) -> Arc<Self> {
+        Arc::new(User {
+            id,
+            irc: Arc::clone(irc),
+            nick: Mutex::new(nick),
+            username,
+            real_name: Mutex::new(real_name),
+            cloak: irc.cloak_host(&host),
+            host,
+            server,
+            channel_list: Mutex::new(HashMap::new()),
+            client: Mutex::new(Weak::new()),
+            flags: Mutex::new(UserFlags { registered: true, oper: false, invisible: false, wallops: false }),
+            resume_token: Mutex::new(None),
+            away_msg: Mutex::new(None),
+            silence: Mutex::new(Vec::new()),
+            nick_changes: Mutex::new(VecDeque::new()),
+            knocks: Mutex::new(VecDeque::new()),
+            signon: Utc::now().timestamp(),
+        })
+    }
+
+    /* a user introduced by a linked server. Their connection's the link,
+     * so anything sent to them goes down it for the peer to deliver */
+    pub fn remote(irc: &Arc<Core>, link: &Arc<Client>, server: &Server, intro: RemoteIntro) -> Arc<Self> {
+        let host = match intro.host.parse::<IpAddr>() {
+            Ok(ip_addr) => Host::HostAddr(ip_addr),
+            Err(_err) => Host::Hostname(intro.host),
+        };
+        let flags = UserFlags {
+            registered: true,
+            oper: intro.umodes.contains('o'),
+            invisible: intro.umodes.contains('i'),
+            wallops: intro.umodes.contains('w'),
+        };
+        Arc::new(User {
+            id: irc.assign_id(),
+            irc: Arc::clone(irc),
+            nick: Mutex::new(intro.nick),
+            username: intro.username,
+            real_name: Mutex::new(intro.real_name),
+            cloak: irc.cloak_host(&host),
+            host,
+            server: server.get_name(),
+            channel_list: Mutex::new(HashMap::new()),
+            client: Mutex::new(Arc::downgrade(link)),
+            flags: Mutex::new(flags),
+            resume_token: Mutex::new(None),
+            away_msg: Mutex::new(None),
+            silence: Mutex::new(Vec::new()),
+            nick_changes: Mutex::new(VecDeque::new()),
+            knocks: Mutex::new(VecDeque::new()),
+            signon: intro.signon,
         })
     }
 
@@ -133,28 +242,158 @@ impl User {
                 /* but is it bad to silently ignore the refs that won't upgrade... */
             }).for_each(|chan|{
                 chan.rm_key(&self.get_nick());
-                if chan.is_empty() {
-                    if let Err(err) = self.irc.remove_name(&chan.get_name()) {
+                if chan.is_disposable() {
+                    if let Err(err) = self.irc.remove_empty_chan(&chan) {
                         warn!("error {} removing non-existant channel {}", err, &chan.get_name());
                     }
                 }
             });
-        if let Err(err) = self.irc.remove_name(&self.get_nick()) {
-            warn!("error {} removing non-existant nick {}", err, &self.get_nick());
+        /* only the first time through gets to leave a WHOWAS entry */
+        match self.irc.remove_user(&self.get_nick()) {
+            Ok(_user_weak) => self.irc.remember_user(self),
+            Err(err) => warn!("error {} removing non-existant nick {}", err, &self.get_nick()),
+        }
+    }
+
+    /* whether they've got a KNOCK left in the current window, and if so
+     * it's used up. Opers don't get limited */
+    pub fn try_knock(&self) -> bool {
+        let mut knocks = self.knocks.lock().unwrap();
+        let (limit, window) = {
+            let config = self.irc.config.read().unwrap();
+            (config.knock_limit, Duration::from_secs(config.knock_window_secs))
+        };
+        while knocks.front().is_some_and(|when| when.elapsed() >= window) {
+            knocks.pop_front();
         }
+        if !self.is_oper() && knocks.len() >= limit {
+            return false;
+        }
+        knocks.push_back(Instant::now());
+        true
+    }
+
+    /* whether the user's connection has a capability switched on, false
+     * if they haven't got one right now */
+    pub fn has_cap(&self, cap: &str) -> bool {
+        Weak::upgrade(&self.client.lock().unwrap()).is_some_and(|client| client.has_cap(cap))
     }
 
     /* attempt to find and upgrade a pointer to the user's client,
      * if that fails, so some cleanup and return an error indicating
-     * dead client or similar */
-    pub fn fetch_client(self: &Arc<Self>) -> Result<Arc<Client>, GenError> { /* GDB++ */
-        Weak::upgrade(&self.client).ok_or_else(|| {
-            self.clear_up();
-            debug!("fetch_client(): got a dead client @ user {}", self.get_nick());
-            /* can't iterate here as chan.notify_quit() will call
-             * user.send_line() and make this fn recursive */
-            GenError::DeadClient(Arc::clone(&self))
-        })
+     * dead client or similar - unless the user is parked waiting for
+     * a RESUME, in which case there's just nobody to send to for now */
+    pub fn fetch_client(self: &Arc<Self>) -> Result<Option<Arc<Client>>, GenError> { /* GDB++ */
+        if let Some(client) = Weak::upgrade(&self.client.lock().unwrap()) {
+            return Ok(Some(client));
+        }
+        if self.irc.is_parked(self.id) {
+            return Ok(None);
+        }
+        self.clear_up();
+        debug!("fetch_client(): got a dead client @ user {}", self.get_nick());
+        /* can't iterate here as chan.notify_quit() will call
+         * user.send_line() and make this fn recursive */
+        Err(GenError::DeadClient(Arc::clone(&self)))
+    }
+
+    /* hand the user over to a new connection, for RESUME */
+    pub fn set_client(&self, client: &Arc<Client>) {
+        *self.client.lock().unwrap() = Arc::downgrade(client);
+    }
+
+    pub fn get_away(&self) -> Option<String> {
+        self.away_msg.lock().unwrap().clone()
+    }
+
+    pub fn set_away(&self, away_msg: Option<String>) {
+        *self.away_msg.lock().unwrap() = away_msg;
+    }
+
+    pub fn get_silence_list(&self) -> Vec<String> {
+        self.silence.lock().unwrap().clone()
+    }
+
+    /* Ok(false) if the mask was already on the list */
+    pub fn add_silence(&self, mask: &str) -> Result<bool, ircError> {
+        let mut silence = self.silence.lock().unwrap();
+        if silence.iter().any(|entry| rfc::to_irc_lower(entry) == rfc::to_irc_lower(mask)) {
+            return Ok(false);
+        }
+        if silence.len() >= self.irc.config.read().unwrap().max_silence {
+            return Err(ircError::SilenceListFull(mask.to_string()));
+        }
+        silence.push(mask.to_string());
+        Ok(true)
+    }
+
+    pub fn remove_silence(&self, mask: &str) -> bool {
+        let mut silence = self.silence.lock().unwrap();
+        let len = silence.len();
+        silence.retain(|entry| rfc::to_irc_lower(entry) != rfc::to_irc_lower(mask));
+        silence.len() != len
+    }
+
+    /* has this user SILENCEd anyone the sender's prefix matches */
+    pub fn is_silencing(&self, sender: &User) -> bool {
+        let prefix = sender.get_prefix();
+        self.silence.lock().unwrap().iter().any(|mask| rfc::wildcard_match(mask, &prefix))
+    }
+
+    pub fn get_resume_token(&self) -> Option<String> {
+        self.resume_token.lock().unwrap().clone()
+    }
+
+    pub fn set_resume_token(&self, token: Option<String>) {
+        *self.resume_token.lock().unwrap() = token;
+    }
+
+    /* let the observers and everyone we share a channel with know we're off */
+    pub async fn announce_quit(&self, quit_msg: &str) {
+        for observer in self.irc.get_observers().iter() {
+            observer.on_quit(self, quit_msg);
+        }
+        let line = format!(":{} QUIT :{}", self.get_prefix(), quit_msg);
+        for peer in self.get_peers().iter() {
+            if let Err(err) = peer.send_line(&line).await {
+                debug!("failed to send QUIT to {}: {}", peer.get_nick(), err);
+            }
+        }
+    }
+
+    /* tell ourselves and everyone we share a channel with about a nick
+     * change, once each. Has to happen after try_nick_change() has let go
+     * of its locks, hence old_prefix being passed in */
+    pub async fn announce_nick(self: &Arc<Self>, old_prefix: &str) {
+        let line = format!("{} NICK :{}", old_prefix, self.get_nick());
+        if let Err(err) = self.send_line(&line).await {
+            debug!("failed to send NICK to {}: {}", self.get_nick(), err);
+        }
+        for peer in self.get_peers().iter() {
+            if let Err(err) = peer.send_line(&line).await {
+                debug!("failed to send NICK to {}: {}", peer.get_nick(), err);
+            }
+        }
+    }
+
+    /* replay the JOIN burst for every channel we're in to a client, e.g.
+     * a new connection that has just been attached to this User, so that
+     * it ends up with the same view of our channels as the one it replaces.
+     * Only sends lines, channel membership itself is left alone */
+    pub async fn resync_state(&self, client: &Client) -> Result<(), GenError> {
+        let prefix = self.get_prefix();
+        for chan in self.get_channel_list().iter().filter_map(Weak::upgrade) {
+            client.send_line(&format!(":{} JOIN {}", prefix, chan.get_name())).await?;
+            client.send_rpl(ircReply::ChannelModeIs(chan.get_name(), chan.gen_mode_string(true))).await?;
+            client.send_rpl(ircReply::CreationTime(chan.get_name(), chan.get_created())).await?;
+            for result_t in chan.gen_join_burst(client.has_cap("multi-prefix")) {
+                match result_t {
+                    Ok(reply) => client.send_rpl(reply).await?,
+                    Err(err) => client.send_err(err).await?
+                }
+            }
+        }
+        Ok(())
     }
 
     /* nick changes need to be done carefully and atomically, or they'll
@@ -168,6 +407,15 @@ impl User {
         self.id
     }
 
+    pub fn get_signon(&self) -> i64 {
+        self.signon
+    }
+
+    /* introduced by a linked server rather than connected to us */
+    pub fn is_remote(&self) -> bool {
+        self.server != self.irc.get_host()
+    }
+
     pub fn get_channel_list(&self) -> Vec<Weak<Channel>> {
         let mut values = Vec::new();
         for val in self.channel_list.lock().unwrap().values() {
@@ -176,6 +424,28 @@ impl User {
         values
     }
 
+    /* how many channels we're in, dropping any that have gone away
+     * from under us while we're at it */
+    pub fn count_channels(&self) -> usize {
+        let mut channel_list = self.channel_list.lock().unwrap();
+        channel_list.retain(|_name, chan_ptr| chan_ptr.strong_count() > 0);
+        channel_list.len()
+    }
+
+    /* everyone sharing at least one channel with this user, each appearing
+     * just once no matter how many channels are shared */
+    pub fn get_peers(&self) -> Vec<Arc<User>> {
+        let mut peers = BTreeMap::new();
+        for chan in self.get_channel_list().iter().filter_map(Weak::upgrade) {
+            for user in chan.gen_user_ptr_vec() {
+                if user.id != self.id {
+                    peers.insert(user.id, user);
+                }
+            }
+        }
+        peers.into_values().collect()
+    }
+
     pub fn get_nick(&self) -> String {
         self.nick.lock().unwrap().clone()
     }
@@ -191,7 +461,15 @@ impl User {
         }
     }
 
+    /* what everyone else gets to see, the cloak if there is one */
     pub fn get_host_string(&self) -> String {
+        match &self.cloak {
+            Some(cloak) => cloak.clone(),
+            None => self.get_real_host_string(),
+        }
+    }
+
+    pub fn get_real_host_string(&self) -> String {
         match &self.host {
             Host::Hostname(name) => name.to_string(),
             Host::HostAddr(ip_addr) => ip_addr.to_string(),
@@ -215,6 +493,58 @@ impl User {
         self.server.clone()
     }
 
+    pub fn is_oper(&self) -> bool {
+        self.flags.lock().unwrap().oper
+    }
+
+    pub fn set_oper(&self, oper: bool) {
+        self.flags.lock().unwrap().oper = oper;
+    }
+
+    pub fn is_invisible(&self) -> bool {
+        self.flags.lock().unwrap().invisible
+    }
+
+    pub fn set_invisible(&self, invisible: bool) {
+        self.flags.lock().unwrap().invisible = invisible;
+    }
+
+    pub fn is_wallops(&self) -> bool {
+        self.flags.lock().unwrap().wallops
+    }
+
+    pub fn set_wallops(&self, wallops: bool) {
+        self.flags.lock().unwrap().wallops = wallops;
+    }
+
+    /* the RPL_UMODEIS form, e.g. `+iw` */
+    pub fn gen_mode_string(&self) -> String {
+        let flags = self.flags.lock().unwrap().clone();
+        let mut letters = String::from("+");
+        for (set, letter) in [(flags.invisible, 'i'), (flags.oper, 'o'), (flags.wallops, 'w')].iter() {
+            if *set {
+                letters.push(*letter);
+            }
+        }
+        letters
+    }
+
+    pub fn shares_channel(&self, other: &User) -> bool {
+        self.get_channel_list()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .any(|chan| chan.is_joined(&other.get_nick()))
+    }
+
+    /* +i users only show up for themselves and people they share a
+     * channel with, and opers if opers_see_invisible is on */
+    pub fn is_visible_to(&self, viewer: &User) -> bool {
+        !self.is_invisible()
+            || self.id == viewer.id
+            || (viewer.is_oper() && self.irc.config.read().unwrap().opers_see_invisible)
+            || self.shares_channel(viewer)
+    }
+
     pub async fn send_msg(
         self: &Arc<Self>,
         src: &User,
@@ -227,7 +557,10 @@ impl User {
         /* instead of unwrap(), fetch_client() tries to upgrade the pointer,
          * if that fails it does some cleaning up and returns a GenError::Io(unexpected Eof)
          */
-        let my_client = self.fetch_client()?;
+        let my_client = match self.fetch_client()? {
+            Some(client) => client,
+            None => return Ok(Ok(ircReply::None)), /* parked, nobody to tell */
+        };
         /* passing to an async fn and awaiting on it is gonna
          * cause lifetime problems with a &str... */
         my_client.send_line(&line).await?;
@@ -236,7 +569,10 @@ impl User {
 
     pub async fn send_err(self: &Arc<Self>, err: ircError) -> Result<ircReply, GenError> { /* GDB+ */
         let line = format!(":{} {}", self.irc.get_host(), err);
-        let my_client = self.fetch_client()?;
+        let my_client = match self.fetch_client()? {
+            Some(client) => client,
+            None => return Ok(ircReply::None), /* parked, nobody to tell */
+        };
         /* passing to an async fn and awaiting on it is gonna
          * cause lifetime problems with a &str... */
         my_client.send_line(&line).await?;
@@ -247,7 +583,10 @@ impl User {
         /* passing to an async fn and awaiting on it is gonna
          * cause lifetime problems with a &str... */
         let mut line = reply.format(&self.get_server(), &self.get_nick());
-        let my_client = self.fetch_client()?;
+        let my_client = match self.fetch_client()? {
+            Some(client) => client,
+            None => return Ok(ircReply::None), /* parked, nobody to tell */
+        };
         /* break up long messages if neccessary,
          * reply::split essentially returns line, None when
          * line is not larger than MAX_MSG_SIZE */
@@ -263,8 +602,18 @@ impl User {
         Ok(ircReply::None)
     }
 
+    /* a NOTICE from the server itself, e.g. a warning about something
+     * that's about to happen to the user */
+    pub async fn send_notice(self: &Arc<Self>, text: &str) -> Result<ircReply, GenError> {
+        let line = format!(":{} NOTICE {} :{}", self.irc.get_host(), self.get_nick(), text);
+        self.send_line(&line).await
+    }
+
     pub async fn send_line(self: &Arc<Self>, line: &str) -> Result<ircReply, GenError> { /* GDB++ */
-        let my_client = self.fetch_client()?;
+        let my_client = match self.fetch_client()? {
+            Some(client) => client,
+            None => return Ok(ircReply::None), /* parked, nobody to tell */
+        };
         /* passing to an async fn and awaiting on it is gonna
          * cause lifetime problems with a &str... */
         my_client.send_line(line).await?;
@@ -287,51 +636,591 @@ pub struct ProtoUser {
     real_name: Option<String>,
 }
 
+/* a peer server linked directly to us over one of our connections */
+#[derive(Debug)]
+pub struct Server {
+    name: String,
+    hopcount: u32,
+    info: String,
+    client_id: u64,
+    /* the users it's introduced, which live as long as the link does */
+    users: Mutex<HashMap<u64, Arc<User>>>,
+}
+
+/* what a linked server tells us about one of its users in a burst:
+ * NICK <nick> <hopcount> <signon> <umodes> <username> <host> <server> :<real name> */
+#[derive(Debug)]
+pub struct RemoteIntro {
+    nick: String,
+    signon: i64,
+    umodes: String,
+    username: String,
+    host: String,
+    real_name: String,
+}
+
+impl RemoteIntro {
+    pub fn parse(params: &[String]) -> Option<RemoteIntro> {
+        if params.len() < 8 || !rfc::valid_nick(&params[0]) {
+            return None;
+        }
+        Some(RemoteIntro {
+            nick: params[0].clone(),
+            signon: params[2].parse::<i64>().ok()?,
+            umodes: params[3].clone(),
+            username: params[4].clone(),
+            host: params[5].clone(),
+            real_name: params[7].clone(),
+        })
+    }
+}
+
+impl Server {
+    pub fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn get_hopcount(&self) -> u32 {
+        self.hopcount
+    }
+
+    pub fn get_info(&self) -> String {
+        self.info.clone()
+    }
+
+    pub fn get_client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    pub fn add_user(&self, user: &Arc<User>) {
+        self.users.lock().unwrap().insert(user.get_id(), Arc::clone(user));
+    }
+
+    pub fn remove_user(&self, id: u64) -> Option<Arc<User>> {
+        self.users.lock().unwrap().remove(&id)
+    }
+
+    /* everyone it introduced, e.g. when the link goes */
+    pub fn take_users(&self) -> Vec<Arc<User>> {
+        self.users.lock().unwrap().drain().map(|(_id, user)| user).collect()
+    }
+}
+
+/* a user whose connection dropped, kept alive until `expires` in case
+ * they come back with a RESUME */
+#[derive(Debug)]
+pub struct ParkedUser {
+    user: Arc<User>,
+    expires: Instant,
+}
+
 #[derive(Debug)]
 pub struct Core {
-    namespace: Mutex<HashMap<String, NamedEntity>>,
+    namespace: RwLock<HashMap<String, NamedEntity>>,
+    parked: Mutex<HashMap<String, ParkedUser>>,
     clients: Mutex<HashMap<u64, Weak<Client>>>,
-    id_counter: Mutex<u64>, //servers: Mutex<HashMap<u64, Arc<Server>>>,
+    id_counter: Mutex<u64>,
+    /* linked servers, keyed by their name in lower case */
+    servers: Mutex<HashMap<String, Arc<Server>>>,
     hostname: String,
     version: String,
     date: String,
     user_modes: String,
-    chan_modes: String
+    chan_modes: String,
+    config: RwLock<Config>,
+    start_time: Instant,
+    whowas: Mutex<VecDeque<WhowasEntry>>,
+    /* one counter per command we know about, for STATS m */
+    cmd_counts: HashMap<&'static str, AtomicU64>,
+    observers: Mutex<Vec<Arc<dyn Observer>>>,
+    /* set by DIE or RESTART once everyone's been told, see
+     * wait_for_shutdown() */
+    shutdown_kind: Mutex<Option<ShutdownKind>>,
+    shutdown_notify: Notify,
+}
+
+/* what the binary should do once an oper has asked us to go away */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownKind {
+    Die,
+    Restart,
+}
+
+/* what WHOWAS has to go on once a user's gone */
+#[derive(Debug, Clone)]
+struct WhowasEntry {
+    nick: String,
+    username: String,
+    host: String,
+    real_host: String,
+    real_name: String,
+    server: String,
+    signoff: String,
+}
+
+/* the settings REHASH can change without a restart, read in one go so
+ * they get swapped into Core all together. Each starts off as the const
+ * of the same name in main.rs, and anything set in CONFIG_PATH wins */
+#[derive(Debug, Clone)]
+pub struct Config {
+    motd_path: String,
+    motd: Vec<String>,
+    audit_log_path: String,
+    network_name: String,
+    server_info: String,
+    cloak_hosts: bool,
+    cloak_secret: Option<String>,
+    password: Option<String>,
+    opers: HashMap<String, String>,
+    links: HashMap<String, (String, String)>,
+    admin_loc1: Option<String>,
+    admin_loc2: Option<String>,
+    admin_email: Option<String>,
+    invite_expiry_secs: u64,
+    opers_see_invisible: bool,
+    truncate_long_nicks: bool,
+    max_targets: usize,
+    max_channels: usize,
+    max_silence: usize,
+    whowas_history: usize,
+    nick_change_limit: usize,
+    nick_change_window_secs: u64,
+    knock_limit: usize,
+    knock_window_secs: u64,
+    knock_channel_delay_secs: u64,
+    quit_prefix: String,
+    flood_rate: f64,
+    flood_burst: f64,
+    flood_excess: u32,
+    resume_window_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FloodLimits {
+    pub rate: f64,
+    pub burst: f64,
+    pub excess: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            motd_path: MOTD_PATH.to_string(),
+            motd: Vec::new(),
+            audit_log_path: AUDIT_LOG_PATH.to_string(),
+            network_name: NETWORK_NAME.to_string(),
+            server_info: SERVER_INFO.to_string(),
+            cloak_hosts: CLOAK_HOSTS,
+            cloak_secret: CLOAK_SECRET.map(String::from),
+            password: SERVER_PASSWORD.map(String::from),
+            opers: OPERATORS
+                .iter()
+                .map(|(name, hash)| (name.to_string(), hash.to_string()))
+                .collect(),
+            links: SERVER_LINKS
+                .iter()
+                .map(|(name, hash, send)| (name.to_ascii_lowercase(), (hash.to_string(), send.to_string())))
+                .collect(),
+            admin_loc1: ADMIN_LOCATION.map(String::from),
+            admin_loc2: ADMIN_LOCATION2.map(String::from),
+            admin_email: ADMIN_EMAIL.map(String::from),
+            invite_expiry_secs: INVITE_EXPIRY_SECS,
+            opers_see_invisible: OPERS_SEE_INVISIBLE,
+            truncate_long_nicks: TRUNCATE_LONG_NICKS,
+            max_targets: MAX_TARGETS,
+            max_channels: MAX_CHANNELS,
+            max_silence: MAX_SILENCE,
+            whowas_history: WHOWAS_HISTORY,
+            nick_change_limit: NICK_CHANGE_LIMIT,
+            nick_change_window_secs: NICK_CHANGE_WINDOW_SECS,
+            knock_limit: KNOCK_LIMIT,
+            knock_window_secs: KNOCK_WINDOW_SECS,
+            knock_channel_delay_secs: KNOCK_CHANNEL_DELAY_SECS,
+            quit_prefix: QUIT_PREFIX.to_string(),
+            flood_rate: FLOOD_RATE,
+            flood_burst: FLOOD_BURST,
+            flood_excess: FLOOD_EXCESS,
+            resume_window_secs: RESUME_WINDOW_SECS,
+        }
+    }
+}
+
+impl Config {
+    /* the defaults with CONFIG_PATH laid over them, then the MOTD file
+     * it points at. No config file just means the defaults, and no MOTD
+     * file just means no MOTD */
+    pub fn load() -> Config {
+        let mut config = match fs::read_to_string(CONFIG_PATH) {
+            Ok(text) => Config::parse(CONFIG_PATH, &text),
+            Err(err) => {
+                debug!("not reading {}: {}, using the defaults", CONFIG_PATH, err);
+                Config::default()
+            }
+        };
+        if config.cloak_hosts && config.cloak_secret.is_none() {
+            warn!("cloak_hosts is on but there's no cloak_secret, leaving cloaks off");
+            config.cloak_hosts = false;
+        }
+        config.motd = fs::read_to_string(&config.motd_path)
+            .map(|text| text.lines().map(String::from).collect())
+            .unwrap_or_default();
+        config
+    }
+
+    /* one `key value` per line, blank lines and lines starting with # are
+     * skipped. A value can be put in double quotes to keep spaces at either
+     * end of it. Anything that doesn't make sense is logged and left at
+     * its default rather than stopping the server from starting */
+    pub fn parse(file_name: &str, text: &str) -> Config {
+        let mut config = Config::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.find(char::is_whitespace) {
+                Some(i) => (&line[..i], line[i..].trim()),
+                None => (line, ""),
+            };
+            let value = match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+                Some(quoted) => quoted,
+                None => value,
+            };
+            if let Err(err) = config.set(key, value) {
+                warn!("{}:{}: {}", file_name, n + 1, err);
+            }
+        }
+        config
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn number<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+            value.parse().map_err(|_| format!("{} wants a number, not \"{}\"", key, value))
+        }
+        fn boolean(key: &str, value: &str) -> Result<bool, String> {
+            match value {
+                "true" | "yes" | "on" => Ok(true),
+                "false" | "no" | "off" => Ok(false),
+                _ => Err(format!("{} wants true or false, not \"{}\"", key, value)),
+            }
+        }
+        fn text(value: &str) -> Option<String> {
+            if value.is_empty() { None } else { Some(value.to_string()) }
+        }
+        let words: Vec<&str> = value.split_whitespace().collect();
+        match key {
+            "motd_path" => self.motd_path = value.to_string(),
+            "audit_log_path" => self.audit_log_path = value.to_string(),
+            /* it goes out as NETWORK= in 005, so no spaces */
+            "network_name" => match words[..] {
+                [name] => self.network_name = name.to_string(),
+                _ => return Err(String::from("network_name wants one word")),
+            },
+            "server_info" => self.server_info = value.to_string(),
+            "cloak_hosts" => self.cloak_hosts = boolean(key, value)?,
+            "cloak_secret" => self.cloak_secret = text(value),
+            "password" => self.password = text(value),
+            "oper" => match words[..] {
+                [name, hash] => {
+                    self.opers.insert(name.to_string(), hash.to_string());
+                }
+                _ => return Err(String::from("oper wants <name> <password hash>")),
+            },
+            "link" => match words[..] {
+                [name, hash, send] => {
+                    self.links.insert(name.to_ascii_lowercase(), (hash.to_string(), send.to_string()));
+                }
+                _ => return Err(String::from("link wants <server name> <password hash> <password to send>")),
+            },
+            "admin_location" => self.admin_loc1 = text(value),
+            "admin_location2" => self.admin_loc2 = text(value),
+            "admin_email" => self.admin_email = text(value),
+            "invite_expiry_secs" => self.invite_expiry_secs = number(key, value)?,
+            "opers_see_invisible" => self.opers_see_invisible = boolean(key, value)?,
+            "truncate_long_nicks" => self.truncate_long_nicks = boolean(key, value)?,
+            "max_targets" => self.max_targets = number(key, value)?,
+            "max_channels" => self.max_channels = number(key, value)?,
+            "max_silence" => self.max_silence = number(key, value)?,
+            "whowas_history" => self.whowas_history = number(key, value)?,
+            "nick_change_limit" => self.nick_change_limit = number(key, value)?,
+            "nick_change_window_secs" => self.nick_change_window_secs = number(key, value)?,
+            "knock_limit" => self.knock_limit = number(key, value)?,
+            "knock_window_secs" => self.knock_window_secs = number(key, value)?,
+            "knock_channel_delay_secs" => self.knock_channel_delay_secs = number(key, value)?,
+            "quit_prefix" => self.quit_prefix = value.to_string(),
+            "flood_rate" => self.flood_rate = number(key, value)?,
+            "flood_burst" => self.flood_burst = number(key, value)?,
+            "flood_excess" => self.flood_excess = number(key, value)?,
+            "resume_window_secs" => self.resume_window_secs = number(key, value)?,
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+        Ok(())
+    }
 }
 
 impl Core {
     // init hash tables
-    pub fn new(hostname: String, version: String) -> Arc<Self> {
+    pub fn new(hostname: String, version: String, config: Config) -> Arc<Self> {
         let clients = Mutex::new(HashMap::new());
-        //let servers  = Mutex::new(HashMap::new());
-        let namespace = Mutex::new(HashMap::new());
+        let servers = Mutex::new(HashMap::new());
+        let namespace = RwLock::new(HashMap::new());
         let id_counter = Mutex::new(0);
+        let cmd_counts = PARAM_COUNTS
+            .iter()
+            .map(|(name, _min, _max)| (*name, AtomicU64::new(0)))
+            .collect();
         Arc::new(Core {
             clients,
             namespace, // combined nick and channel HashMap
-            id_counter, //servers
+            id_counter,
+            servers,
             hostname,
             version,
             date: Utc::now().to_rfc2822(),
             user_modes: String::from(USER_MODES),
-            chan_modes: String::from(CHAN_MODES)
+            chan_modes: String::from(CHAN_MODES),
+            config: RwLock::new(config),
+            start_time: Instant::now(),
+            whowas: Mutex::new(VecDeque::new()),
+            cmd_counts,
+            observers: Mutex::new(Vec::new()),
+            shutdown_kind: Mutex::new(None),
+            shutdown_notify: Notify::new(),
+            parked: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /* what check_flood() goes by, read afresh for every line so a REHASH
+     * applies to connections that are already up */
+    pub fn flood_limits(&self) -> FloodLimits {
+        let config = self.config.read().unwrap();
+        FloodLimits { rate: config.flood_rate, burst: config.flood_burst, excess: config.flood_excess }
+    }
+
+    /* for REHASH, anything reading the config sees either all of the old
+     * one or all of the new */
+    pub fn apply_config(&self, config: Config) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /* a user's on their way out, keep a note of them for WHOWAS */
+    pub fn remember_user(&self, user: &User) {
+        let cap = self.config.read().unwrap().whowas_history;
+        let mut whowas = self.whowas.lock().unwrap();
+        /* more than one can go at once if REHASH turned the history down */
+        while !whowas.is_empty() && whowas.len() >= cap {
+            whowas.pop_back();
+        }
+        if cap > 0 {
+            whowas.push_front(WhowasEntry {
+                nick: user.get_nick(),
+                username: user.get_username(),
+                host: user.get_host_string(),
+                real_host: user.get_real_host_string(),
+                real_name: user.get_realname(),
+                server: user.get_server(),
+                signoff: Utc::now().to_rfc2822(),
+            });
+        }
+    }
+
+    pub fn get_uptime(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
+    /* only goes up for commands in PARAM_COUNTS, so nobody can grow the
+     * map by sending junk */
+    pub fn count_command(&self, cmd: &str) {
+        if let Some(count) = self.cmd_counts.get(cmd) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /* the commands that have been used at least once, alphabetically */
+    pub fn get_command_counts(&self) -> Vec<(&'static str, u64)> {
+        let mut counts: Vec<(&'static str, u64)> = self.cmd_counts
+            .iter()
+            .map(|(cmd, count)| (*cmd, count.load(Ordering::Relaxed)))
+            .filter(|(_cmd, count)| *count > 0)
+            .collect();
+        counts.sort_unstable();
+        counts
+    }
+
+    /* None when cloaking's off, or there's no secret to cloak with. An
+     * address gets hashed whole, a hostname keeps its last two labels so
+     * you can still tell roughly where someone's from, e.g.
+     * dsl-1-2-3.isp.net -> 1a2b3c4d5e6f7a8b.isp.net */
+    pub fn cloak_host(&self, host: &Host) -> Option<String> {
+        let key = match &*self.config.read().unwrap() {
+            Config { cloak_hosts: true, cloak_secret: Some(secret), .. } => secret.clone(),
+            _ => return None,
+        };
+        let hash = |text: &str| password::to_hex(&Sha256::digest(format!("{}:{}", key, text).as_bytes())[..8]);
+        Some(match host {
+            Host::HostAddr(ip_addr) => format!("user-{}.cloak", hash(&ip_addr.to_string())),
+            Host::Hostname(name) => {
+                let labels: Vec<&str> = name.split('.').collect();
+                if labels.len() > 2 {
+                    format!("{}.{}", hash(name), labels[labels.len() - 2..].join("."))
+                } else {
+                    format!("user-{}.cloak", hash(name))
+                }
+            }
+        })
+    }
+
+    /* does the password hash to what we've got for this oper name. Unknown
+     * names still pay for the hashing */
+    pub fn check_oper(&self, name: &str, password: &str) -> bool {
+        let oper_hash = self.config.read().unwrap().opers.get(name).cloned();
+        match oper_hash {
+            Some(oper_hash) => password::verify(&oper_hash, password),
+            None => {
+                password::waste_time(password);
+                false
+            }
+        }
+    }
+
+    /* if this server's allowed to link and sent the right password, the
+     * password we should send back to it */
+    pub fn check_link(&self, name: &str, password: Option<&str>) -> Option<String> {
+        let link = self.config.read().unwrap().links.get(&name.to_ascii_lowercase()).cloned();
+        match (link, password) {
+            (Some((link_hash, send)), Some(password)) => {
+                if password::verify(&link_hash, password) {
+                    Some(send)
+                } else {
+                    None
+                }
+            },
+            (_, password) => {
+                /* so a name that isn't configured doesn't answer any faster */
+                password::waste_time(password.unwrap_or(""));
+                None
+            }
+        }
+    }
+
+    /* a connection password, if one is needed, has to match the server's */
+    pub fn check_password(&self, password: Option<&str>) -> bool {
+        let server_hash = self.config.read().unwrap().password.clone();
+        match (server_hash, password) {
+            (None, _) => true,
+            (Some(server_hash), Some(password)) => password::verify(&server_hash, password),
+            (Some(_server_hash), None) => false,
+        }
+    }
+
+    /* every operator action has to be recorded here first, and mustn't go
+     * ahead if this fails */
+    pub fn audit(&self, actor: &User, action: &str, target: &str) -> Result<(), ircError> {
+        let path = self.config.read().unwrap().audit_log_path.clone();
+        audit::record(&path, &actor.get_prefix(), action, target).map_err(|err| {
+            error!("couldn't write audit record for {} {} by {}: {}", action, target, actor.get_nick(), err);
+            ircError::FileError(String::from("write"), path)
         })
     }
 
+    /* give the user a fresh resume token, if resumption is switched on -
+     * any old one is used up */
+    pub fn issue_resume_token(&self, user: &User) -> Option<String> {
+        if self.config.read().unwrap().resume_window_secs == 0 {
+            return None;
+        }
+        let mut bytes = [0u8; 16];
+        if let Err(err) = password::random_bytes(&mut bytes) {
+            warn!("couldn't get a resume token for {}: {}", user.get_nick(), err);
+            return None;
+        }
+        let token = password::to_hex(&bytes);
+        user.set_resume_token(Some(token.clone()));
+        Some(token)
+    }
+
+    /* hold on to a user whose connection has gone, if they were given a
+     * token - otherwise they're just dropped as usual */
+    pub fn park_user(&self, user: Arc<User>) {
+        if let Some(token) = user.get_resume_token() {
+            let window = self.config.read().unwrap().resume_window_secs;
+            debug!("parking user {} for {} seconds", user.get_nick(), window);
+            let expires = Instant::now() + Duration::from_secs(window);
+            self.parked.lock().unwrap().insert(token, ParkedUser { user, expires });
+        }
+    }
+
+    pub fn is_parked(&self, id: u64) -> bool {
+        self.parked.lock().unwrap().values().any(|parked| parked.user.get_id() == id)
+    }
+
+    /* claim a parked user with their token, tokens only work once */
+    pub fn take_parked(&self, token: &str) -> Option<Arc<User>> {
+        self.parked
+            .lock()
+            .unwrap()
+            .remove(token)
+            .filter(|parked| parked.expires > Instant::now())
+            .map(|parked| parked.user)
+    }
+
+    /* anyone who didn't make it back in time quits for real */
+    pub async fn expire_parked(&self) {
+        let now = Instant::now();
+        let expired: Vec<ParkedUser> = {
+            let mut parked = self.parked.lock().unwrap();
+            let tokens: Vec<String> = parked
+                .iter()
+                .filter(|(_token, parked)| parked.expires <= now)
+                .map(|(token, _parked)| token.clone())
+                .collect();
+            tokens.iter().filter_map(|token| parked.remove(token)).collect()
+        };
+        for parked in expired.iter() {
+            debug!("resume window for {} expired", parked.user.get_nick());
+            parked.user.announce_quit("Connection closed").await;
+            parked.user.clear_up();
+        }
+    }
+
+    pub fn subscribe(&self, observer: Arc<dyn Observer>) {
+        self.observers.lock().unwrap().push(observer);
+    }
+
+    /* hand out a copy of the list so nobody fires hooks while holding the lock */
+    pub fn get_observers(&self) -> Vec<Arc<dyn Observer>> {
+        self.observers.lock().unwrap().clone()
+    }
+
     pub fn assign_id(&self) -> u64 {
         let mut lock_ptr = self.id_counter.lock().unwrap();
         *lock_ptr += 1;
         *lock_ptr
     }
 
+    /* make sure ids handed out from now on don't clash with id */
+    pub fn reserve_id(&self, id: u64) {
+        let mut lock_ptr = self.id_counter.lock().unwrap();
+        if *lock_ptr < id {
+            *lock_ptr = id;
+        }
+    }
+
     pub fn insert_client(&self, id: u64, client: Weak<Client>) {
         self.clients.lock().unwrap().insert(id, client);
     }
 
+    /* nicks and channel names are both case insensitive, so they're keyed
+     * on their casefolded form, while the User or Channel keeps the name
+     * as it was given */
+    fn name_key(name: &str) -> String {
+        rfc::to_irc_lower(name)
+    }
+
     pub fn insert_name(&self, name: &str, item: NamedEntity) -> Result<(), ircError> {
-        let mut hashmap = self.namespace.lock().unwrap();
-        if !hashmap.contains_key(name) {
-            hashmap.insert(name.to_string(), item);
+        let mut hashmap = self.namespace.write().unwrap();
+        if let Entry::Vacant(entry) = hashmap.entry(Core::name_key(name)) {
+            entry.insert(item);
             debug!("added key {} hashmap, size = {}", name, hashmap.len());
             Ok(())
         } else {
@@ -339,21 +1228,65 @@ impl Core {
         }
     }
 
-    pub fn remove_name(&self, name: &str) -> Result<NamedEntity, ircError> {
-        let mut hashmap = self.namespace.lock().unwrap();
-        let ret = hashmap
-            .remove(name)
-            .ok_or_else(|| ircError::NoSuchNick(name.to_string()));
-        if ret.is_ok() {
-            debug!("removed key {} from hashmap, size = {}", name, hashmap.len());
+    /* nicks and channels share the one map, so only take the entry out if
+     * it's the sort of thing the caller thinks it is */
+    fn remove_name(&self, name: &str, is_wanted: fn(&NamedEntity) -> bool) -> Option<NamedEntity> {
+        let mut hashmap = self.namespace.write().unwrap();
+        let key = Core::name_key(name);
+        if !hashmap.get(&key).is_some_and(is_wanted) {
+            return None;
         }
+        let ret = hashmap.remove(&key);
+        debug!("removed key {} from hashmap, size = {}", name, hashmap.len());
         ret
     }
 
+    pub fn remove_user(&self, nick: &str) -> Result<Weak<User>, ircError> {
+        match self.remove_name(nick, |entity| matches!(entity, NamedEntity::User(_user_weak))) {
+            Some(NamedEntity::User(user_weak)) => Ok(user_weak),
+            _ => Err(ircError::NoSuchNick(nick.to_string())),
+        }
+    }
+
+    pub fn remove_chan(&self, chanmask: &str) -> Result<Arc<Channel>, ircError> {
+        match self.remove_name(chanmask, |entity| matches!(entity, NamedEntity::Chan(_chan))) {
+            Some(NamedEntity::Chan(chan)) => Ok(chan),
+            _ => Err(ircError::NoSuchChannel(chanmask.to_string())),
+        }
+    }
+
+    /* throw away every channel's expired invites */
+    pub fn prune_invites(&self) {
+        for chan in self.list_chans_ptr().iter() {
+            chan.prune_invites();
+        }
+    }
+
+    /* forget about a channel that has emptied, the caller should already
+     * have checked - the channel's key and invites go too, in case anyone
+     * is still holding a pointer to it and tries to join */
+    pub fn remove_empty_chan(&self, chan: &Channel) -> Result<Arc<Channel>, ircError> {
+        chan.set_key(None);
+        chan.clear_invites();
+        self.remove_chan(&chan.get_name())
+    }
+
     pub fn get_host(&self) -> String {
         self.hostname.clone()
     }
 
+    pub fn get_network_name(&self) -> String {
+        self.config.read().unwrap().network_name.clone()
+    }
+
+    pub fn get_server_info(&self) -> String {
+        self.config.read().unwrap().server_info.clone()
+    }
+
+    pub fn truncates_long_nicks(&self) -> bool {
+        self.config.read().unwrap().truncate_long_nicks
+    }
+
     pub fn get_client(&self, id: &u64) -> Option<Weak<Client>> {
         self.clients
             .lock()
@@ -366,15 +1299,166 @@ impl Core {
         self.clients.lock().unwrap().remove(id)
     }
 
-    pub fn get_name(&self, name: &str) -> Option<NamedEntity> {
-        self.namespace.lock().unwrap().get(name).cloned()
+    /* false if the name's taken, by us or by something already linked */
+    pub fn add_server(&self, server: &Arc<Server>) -> bool {
+        let key = server.get_name().to_ascii_lowercase();
+        let mut servers = self.servers.lock().unwrap();
+        if key == self.hostname.to_ascii_lowercase() || servers.contains_key(&key) {
+            return false;
+        }
+        servers.insert(key, Arc::clone(server));
+        true
     }
 
-    pub fn get_nick(&self, nick: &str) -> Option<Weak<User>> {
-        if let Some(NamedEntity::User(u_ptr)) = self.get_name(nick) {
-            Some(u_ptr)
-        } else {
-            None
+    pub fn remove_server(&self, name: &str) -> Option<Arc<Server>> {
+        self.servers.lock().unwrap().remove(&name.to_ascii_lowercase())
+    }
+
+    /* the link's gone, and everyone it brought with it. Their channels
+     * hear the usual "<us> <them>" netsplit quit */
+    pub async fn split_server(&self, server: &Server) {
+        self.remove_server(&server.get_name());
+        let quit_msg = format!("{} {}", self.get_host(), server.get_name());
+        for user in server.take_users().iter() {
+            user.announce_quit(&quit_msg).await;
+            user.clear_up();
+        }
+    }
+
+    /* take a user off the network once everyone's been told (KILL, nick
+     * collisions). Local users lose their connection, remote ones are
+     * just forgotten, their link stays up */
+    pub async fn kill_user(&self, victim: &Arc<User>, quit_msg: &str) {
+        if victim.is_remote() {
+            let server = self.servers.lock().unwrap().get(&victim.get_server().to_ascii_lowercase()).cloned();
+            if let Some(server) = server {
+                server.remove_user(victim.get_id());
+            }
+        } else {
+            match victim.fetch_client() {
+                Ok(Some(client)) => {
+                    /* marked dead first, so its handler doesn't park it on the way out */
+                    client.set_client_type(ClientType::Dead);
+                    self.remove_client(&client.get_id());
+                    client.send_error_and_close(quit_msg).await;
+                },
+                _ => {
+                    if let Some(token) = victim.get_resume_token() {
+                        self.take_parked(&token);
+                    }
+                },
+            }
+        }
+        victim.clear_up();
+    }
+
+    /* what we tell a server that's just linked: a NICK for each of our own
+     * users, then an NJOIN for each channel they're in */
+    pub fn generate_burst(&self) -> Vec<String> {
+        let host = self.get_host();
+        let mut lines = Vec::new();
+        for user in self.all_users().iter().filter(|user| !user.is_remote()) {
+            lines.push(format!(
+                ":{} NICK {} 1 {} {} {} {} {} :{}",
+                host,
+                user.get_nick(),
+                user.get_signon(),
+                user.gen_mode_string(),
+                user.get_username(),
+                user.get_real_host_string(),
+                user.get_server(),
+                user.get_realname()
+            ));
+        }
+        for chan in self.list_chans_ptr().iter() {
+            let members: Vec<String> = chan.gen_user_ptr_vec()
+                .iter()
+                .filter(|member| !member.is_remote())
+                .map(|member| {
+                    let badges = chan.get_flags(&member.get_nick()).map_or("", |flags| flags.all_prefixes());
+                    format!("{}{}", badges, member.get_nick())
+                }).collect();
+            if !members.is_empty() {
+                lines.push(format!(":{} NJOIN {} :{}", host, chan.get_name(), members.join(",")));
+            }
+        }
+        lines
+    }
+
+    pub fn get_servers(&self) -> Vec<Arc<Server>> {
+        self.servers.lock().unwrap().values().cloned().collect()
+    }
+
+    /* say goodbye to every client and have their handlers hang up. Same as
+     * check_idle_clients(), the pointers are copied out first so the lock
+     * isn't held while we upgrade them or wait on the sends */
+    pub async fn shutdown(&self, reason: &str) {
+        let clients: Vec<Weak<Client>> = self.clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(Weak::clone)
+            .collect();
+        let reason = format!("Server shutting down: {}", reason);
+        for client in clients.iter().filter_map(Weak::upgrade) {
+            if let ClientType::Dead = client.get_client_type() {
+                continue;
+            }
+            client.set_client_type(ClientType::Dead);
+            client.send_error_and_close(&reason).await;
+        }
+    }
+
+    /* only the first DIE or RESTART counts, anything after that is too late */
+    fn request_shutdown(&self, kind: ShutdownKind) {
+        let mut shutdown_kind = self.shutdown_kind.lock().unwrap();
+        if shutdown_kind.is_none() {
+            *shutdown_kind = Some(kind);
+            self.shutdown_notify.notify_one();
+        }
+    }
+
+    /* the binary awaits this and exits (Die) or re-execs itself (Restart)
+     * when it returns. It only returns after DIE or RESTART has already
+     * sent everyone their ERROR through shutdown(), so all that's left is
+     * giving the write tasks a moment to flush. One that happens before
+     * anyone's waiting isn't lost, the next call returns straight away */
+    pub async fn wait_for_shutdown(&self) -> ShutdownKind {
+        loop {
+            if let Some(kind) = *self.shutdown_kind.lock().unwrap() {
+                return kind;
+            }
+            self.shutdown_notify.notified().await;
+        }
+    }
+
+    /* ids of all the clients that have been silent for longer than timeout */
+    pub fn check_idle_clients(&self, timeout: Duration) -> Vec<u64> {
+        /* don't upgrade while holding the lock, if we end up with the last
+         * strong pointer Client::drop() will want the lock too */
+        let clients: Vec<(u64, Weak<Client>)> = self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, client_weak)| (*id, Weak::clone(client_weak)))
+            .collect();
+        clients.iter()
+            .filter_map(|(id, client_weak)| {
+                Weak::upgrade(client_weak)
+                    .filter(|client| client.idle_time() > timeout)
+                    .map(|_client| *id)
+            }).collect()
+    }
+
+    pub fn get_name(&self, name: &str) -> Option<NamedEntity> {
+        self.namespace.read().unwrap().get(&Core::name_key(name)).cloned()
+    }
+
+    pub fn get_nick(&self, nick: &str) -> Option<Weak<User>> {
+        if let Some(NamedEntity::User(u_ptr)) = self.get_name(nick) {
+            Some(u_ptr)
+        } else {
+            None
         }
     }
 
@@ -394,10 +1478,106 @@ impl Core {
         self.date.clone()
     }
 
+    /* the 005 tokens describing what we support, for clients to adapt to */
+    pub fn gen_isupport_replies(&self) -> Vec<ircReply> {
+        let config = self.config.read().unwrap();
+        let max_targets = config.max_targets;
+        let tokens = vec![
+            String::from("CASEMAPPING=rfc1459"),
+            String::from("CHANTYPES=&#+!"),
+            String::from("CHANMODES=beI,k,l,imnstP"),
+            String::from("PREFIX=(ov)@+"),
+            String::from("EXCEPTS=e"),
+            String::from("INVEX=I"),
+            format!("NICKLEN={}", rfc::MAX_NICK_LEN),
+            format!("CHANNELLEN={}", rfc::MAX_CHAN_LEN),
+            format!("TARGMAX=JOIN:{},PART:{},PRIVMSG:{},NOTICE:{}", max_targets, max_targets, max_targets, max_targets),
+            format!("CHANLIMIT=&#+!:{}", config.max_channels),
+            format!("SILENCE={}", config.max_silence),
+            format!("NETWORK={}", self.get_network_name()),
+        ];
+        /* no more than 13 tokens to a line */
+        tokens.chunks(13).map(|chunk| ircReply::ISupport(chunk.to_vec())).collect()
+    }
+
+    /* 375, a 372 per line and 376, or 422 when there's no MOTD */
+    pub fn gen_motd_replies(&self) -> ClientReplies {
+        let mut replies = Vec::new();
+        let config = self.config.read().unwrap();
+        if config.motd.is_empty() {
+            replies.push(Err(ircError::NoMotd));
+            return replies;
+        }
+        replies.push(Ok(ircReply::MotdStart(self.get_host())));
+        for line in config.motd.iter() {
+            replies.push(Ok(ircReply::Motd(line.clone())));
+        }
+        replies.push(Ok(ircReply::EndofMotd));
+        replies
+    }
+
+    /* every registered user that's still around */
+    pub fn all_users(&self) -> Vec<Arc<User>> {
+        let mut user_weaks = Vec::new();
+        for value in self.namespace.read().unwrap().values() {
+            if let NamedEntity::User(user_weak) = value {
+                user_weaks.push(Weak::clone(user_weak));
+            }
+        }
+        user_weaks.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /* users for which the field picked out by get_field matches mask,
+     * e.g. find_users_by_mask("*.example.com", User::get_host_string) */
+    pub fn find_users_by_mask(&self, mask: &str, get_field: fn(&User) -> String) -> Vec<Arc<User>> {
+        self.all_users()
+            .into_iter()
+            .filter(|user| rfc::wildcard_match(mask, &get_field(user)))
+            .collect()
+    }
+
+    /* users whose Weak still points somewhere, without upgrading them
+     * while we hold the namespace lock */
+    pub fn user_count(&self) -> usize {
+        self.namespace.read().unwrap()
+            .values()
+            .filter(|entity| matches!(entity, NamedEntity::User(user_weak) if user_weak.strong_count() > 0))
+            .count()
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.namespace.read().unwrap()
+            .values()
+            .filter(|entity| matches!(entity, NamedEntity::Chan(_chan)))
+            .count()
+    }
+
+    /* 256 to 259, with stand-ins for anything that hasn't been filled in */
+    pub fn gen_admin_replies(&self) -> Vec<ircReply> {
+        let or_else = |info: &Option<String>, default: &str| info.clone().unwrap_or_else(|| String::from(default));
+        let config = self.config.read().unwrap();
+        vec![
+            ircReply::AdminMe(self.get_host()),
+            ircReply::AdminLoc1(or_else(&config.admin_loc1, "No location given")),
+            ircReply::AdminLoc2(or_else(&config.admin_loc2, "No further details")),
+            ircReply::AdminEmail(or_else(&config.admin_email, "No contact address given")),
+        ]
+    }
+
+    /* 251, 254 and 255, there's only us so no servers or services */
+    pub fn gen_lusers_replies(&self) -> Vec<ircReply> {
+        let users = self.user_count();
+        vec![
+            ircReply::LuserClient(users),
+            ircReply::LuserChannels(self.channel_count()),
+            ircReply::LuserMe(users),
+        ]
+    }
+
     pub fn list_chans_ptr(&self) -> Vec<Arc<Channel>> {
-        let mutex_lock = self.namespace.lock().unwrap();
+        let read_lock = self.namespace.read().unwrap();
         let mut ret = Vec::new();
-        for ent in mutex_lock.values() {
+        for ent in read_lock.values() {
             if let NamedEntity::Chan(chan) = ent {
                 ret.push(Arc::clone(&chan));
             }
@@ -442,21 +1622,54 @@ impl Core {
         Ok(ircReply::None)
     }
 
-    pub async fn join_chan(self: &Arc<Core>, chanmask: &str, user: &Arc<User>) -> Result<ClientReplies, GenError> {
+    pub async fn join_chan(
+        self: &Arc<Core>,
+        chanmask: &str,
+        key: Option<&str>,
+        user: &Arc<User>
+    ) -> Result<ClientReplies, GenError> {
         let mut replies = Vec::new();
         if !rfc::valid_channel(chanmask) {
             replies.push(Err(ircError::NoSuchChannel(chanmask.to_string())));
             return Ok(replies);
         }
         let nick = user.get_nick();
+        let full_up = user.count_channels() >= self.config.read().unwrap().max_channels;
         match self.get_chan(chanmask) {
             Ok(chan) => {
                 /* need to check if user is already in chan */
                 if chan.is_joined(&nick) {
                     return Ok(replies);
                 }
+                if full_up {
+                    replies.push(Err(ircError::TooManyChannels(chanmask.to_string())));
+                    return Ok(replies);
+                }
+                /* bans only keep people out, anyone already in stays put.
+                 * A +e match gets past a ban and a +I past +i */
+                if chan.is_banned(user) {
+                    replies.push(Err(ircError::BannedFromChan(chanmask.to_string())));
+                    return Ok(replies);
+                }
+                if chan.get_modes().invite_only && !chan.is_invited(user) && !chan.is_invite_exempt(user) {
+                    replies.push(Err(ircError::InviteOnlyChan(chanmask.to_string())));
+                    return Ok(replies);
+                }
+                if !chan.check_key(key) {
+                    replies.push(Err(ircError::BadChannelKey(chanmask.to_string())));
+                    return Ok(replies);
+                }
+                if chan.is_full() {
+                    replies.push(Err(ircError::ChannelIsFull(chanmask.to_string())));
+                    return Ok(replies);
+                }
+                chan.take_invite(user);
                 chan.add_user(user, ChanFlags::None).await
             },
+            Err(_) if full_up => {
+                replies.push(Err(ircError::TooManyChannels(chanmask.to_string())));
+                Ok(replies)
+            },
             Err(_) => {
                 let chan = Arc::new(Channel::new(&self, chanmask));
                 self.insert_name(chanmask, NamedEntity::Chan(Arc::clone(&chan)))?; // what happens if this error does occur?
@@ -465,36 +1678,67 @@ impl Core {
         }
     }
 
-    /* don't want anyone to take our nick while we're in the middle of faffing around... */
+    /* don't want anyone to take our nick while we're in the middle of faffing around...
+     * so the write lock's held from the in-use check right through to the swap.
+     * The rate limit's checked and recorded under it too, so two NICKs racing
+     * each other can't both squeeze into the last slot. Opers don't get limited */
     pub fn try_nick_change(&self, user: &User, new_nick: &str) -> Result<ircReply, GenError> {
-        let mut big_fat_mutex_lock = self.namespace.lock().unwrap();
-        let mut chanlist_mutex_lock = user.channel_list.lock().unwrap();
         let nick = new_nick.to_string();
         let old_nick = user.get_nick();
-        if big_fat_mutex_lock.contains_key(&nick) {
-            gef!(ircError::NicknameInUse(nick))
-        } else {
-            if let Some(val) = big_fat_mutex_lock.remove(&old_nick) {
-                /* move to new key */
-                big_fat_mutex_lock.insert(nick.clone(), val);
-
-                /* update User struct */
-                *user.nick.lock().unwrap() = nick;
-
-                /* update channels list */
-                for (chan_name, chan_wptr) in chanlist_mutex_lock.clone().iter() {
-                    if let Some(chan) = Weak::upgrade(&chan_wptr) {
-                        if let Err(err) = chan.update_nick(&old_nick, &new_nick) {
-                            warn!("try to update nick {} in chan {} despite not being in chan, error: {}", &chan_name, &old_nick, err);
-                        }
-                    } else {
-                        debug!("try_nick_change(): can't upgrade pointer to {}, deleting key", chan_name);
-                        chanlist_mutex_lock.remove(chan_name);
+        let (limit, window) = {
+            let config = self.config.read().unwrap();
+            (config.nick_change_limit, Duration::from_secs(config.nick_change_window_secs))
+        };
+        let swapped = {
+            let mut big_fat_write_lock = self.namespace.write().unwrap();
+            let mut nick_changes = user.nick_changes.lock().unwrap();
+            let (key, old_key) = (Core::name_key(&nick), Core::name_key(&old_nick));
+            /* the one now for the prune and the wait, so the wait can't
+             * come out negative */
+            let now = Instant::now();
+            while nick_changes.front().is_some_and(|when| now.duration_since(*when) >= window) {
+                nick_changes.pop_front();
+            }
+            /* Bob -> bOb is the same key, and is fine */
+            if key != old_key && big_fat_write_lock.contains_key(&key) {
+                return gef!(ircError::NicknameInUse(nick));
+            } else if !user.is_oper() && nick_changes.len() >= limit {
+                let wait = nick_changes.front().map_or(window, |when| window.saturating_sub(now.duration_since(*when)));
+                return gef!(ircError::NickChangeTooFast(nick, wait.as_secs() + 1));
+            }
+            match big_fat_write_lock.remove(&old_key) {
+                Some(val) => {
+                    /* move to new key */
+                    big_fat_write_lock.insert(key, val);
+                    nick_changes.push_back(now);
+
+                    /* update User struct */
+                    *user.nick.lock().unwrap() = nick;
+                    true
+                },
+                None => false,
+            }
+        }; /* de-scope the namespace lock */
+
+        /* update channels list - with nothing else held, since channels
+         * lock their member list before a user's channel list (see
+         * link_user()), and holding ours across update_nick() would be
+         * the other way round. Channels go by our id rather than the old
+         * nick, someone else may have taken that by now */
+        if swapped {
+            let chan_wptrs = user.channel_list.lock().unwrap().clone();
+            for (chan_name, chan_wptr) in chan_wptrs.iter() {
+                if let Some(chan) = Weak::upgrade(&chan_wptr) {
+                    if let Err(err) = chan.update_nick(user.get_id(), &new_nick) {
+                        warn!("try to update nick {} in chan {} despite not being in chan, error: {}", &chan_name, &old_nick, err);
                     }
+                } else {
+                    debug!("try_nick_change(): can't upgrade pointer to {}, deleting key", chan_name);
+                    user.channel_list.lock().unwrap().remove(chan_name);
                 }
             }
-            Ok(ircReply::None)
         }
+        Ok(ircReply::None)
     }
 
     pub fn register(
@@ -509,6 +1753,9 @@ impl Core {
         let id = client.get_id();
         let irc = client.get_irc();
         let server = irc.hostname.clone();
+        if !self.check_password(client.get_password().as_deref()) {
+            return Err(ircError::PasswdMismatch);
+        }
         trace!(
             "register user {}!{}@{}, Real name: {} -- client id {}",
             &nick, &username, &host_str, &real_name, id
@@ -524,6 +1771,9 @@ impl Core {
             client,
         );
         self.insert_name(&nick, NamedEntity::User(Arc::downgrade(&user)))?;
+        for observer in self.get_observers().iter() {
+            observer.on_register(&user);
+        }
         Ok(user)
     }
 
@@ -531,7 +1781,7 @@ impl Core {
     fn _search_user_chans(&self, nick: &str, purge: bool) -> Vec<String> {
         let mut channels = Vec::new();
         let mut chan_strings = Vec::new();
-        for value in self.namespace.lock().unwrap().values() {
+        for value in self.namespace.read().unwrap().values() {
             if let NamedEntity::Chan(chan_ptr) = value {
                 channels.push(Arc::clone(&chan_ptr));
             }
@@ -542,7 +1792,7 @@ impl Core {
                 chan_strings.push(channel.get_name());
                 if purge {
                     channel.rm_key(&nick);
-                    if channel.is_empty() && self.remove_name(&channel.get_name()).is_ok() {
+                    if channel.is_disposable() && self.remove_empty_chan(channel).is_ok() {
                         debug!("_search_user_chans(): remove channel {} from IRC HashMap", &channel.get_name());
                     }
                 }
@@ -559,6 +1809,72 @@ impl Core {
     pub fn search_user_chans_purge(&self, nick: &str) -> Vec<String> {
         self._search_user_chans(nick, true)
     }
+
+    /* the user<->channel relationship is kept on both sides, a User has
+     * its channel_list and a Channel has its member map, and there are
+     * plenty of places where one side can get updated without the other.
+     * Walk the whole namespace, drop the dangling side of any relationship
+     * that isn't mutual and log each repair. Returns the number of repairs,
+     * called periodically from main and safe to trigger at any time */
+    pub fn check_consistency(&self) -> usize {
+        let mut repairs = 0;
+        let mut users = Vec::new();
+        let mut dead_nicks = Vec::new();
+        for (name, ent) in self.namespace.read().unwrap().iter() {
+            if let NamedEntity::User(user_weak) = ent {
+                match Weak::upgrade(user_weak) {
+                    Some(user) => users.push(user),
+                    None => dead_nicks.push(name.clone()),
+                }
+            }
+        }
+
+        /* nicks whose User has already gone */
+        for nick in dead_nicks.iter() {
+            if self.remove_user(nick).is_ok() {
+                warn!("check_consistency(): removed dead user {} from namespace", nick);
+                repairs += 1;
+            }
+        }
+
+        /* user side: channels the user thinks they're in, but which don't
+         * exist any more or don't list the user */
+        for user in users.iter() {
+            let nick = user.get_nick();
+            let chan_list: Vec<(String, Weak<Channel>)> = user.channel_list
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, chan_weak)| (name.clone(), Weak::clone(chan_weak)))
+                .collect();
+            for (chan_name, chan_weak) in chan_list.iter() {
+                let mutual = match (Weak::upgrade(chan_weak), self.get_chan(chan_name)) {
+                    (Some(chan), Ok(live)) => Arc::ptr_eq(&chan, &live) && chan.is_joined(&nick),
+                    _ => false,
+                };
+                if !mutual {
+                    user.channel_list.lock().unwrap().remove(chan_name);
+                    warn!("check_consistency(): user {} listed channel {} one-sidedly, removed", nick, chan_name);
+                    repairs += 1;
+                }
+            }
+        }
+
+        /* channel side: members who are gone or don't list the channel */
+        for chan in self.list_chans_ptr().iter() {
+            let chan_name = chan.get_name();
+            for nick in chan.repair_members().iter() {
+                warn!("check_consistency(): channel {} listed user {} one-sidedly, removed", chan_name, nick);
+                repairs += 1;
+            }
+            if chan.is_disposable() && self.remove_empty_chan(chan).is_ok() {
+                warn!("check_consistency(): removed empty channel {} from namespace", chan_name);
+                repairs += 1;
+            }
+        }
+
+        repairs
+    }
 }
 
 #[derive(Debug)]
@@ -567,11 +1883,91 @@ pub enum MsgType {
     Notice,
 }
 
-pub async fn command(irc: &Arc<Core>, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+/* (command, min params, max params) - anything under the minimum gets a
+ * uniform ERR_NEEDMOREPARAMS before the handler ever sees it, anything past
+ * the maximum is dropped. Commands with their own errors for missing
+ * params (PRIVMSG's 411/412, PING's 409) have a minimum of zero */
+const PARAM_COUNTS: &[(&str, usize, Option<usize>)] = &[
+    ("NICK", 1, Some(1)),
+    ("USER", 4, None),
+    ("PRIVMSG", 0, None),
+    ("NOTICE", 0, None),
+    ("JOIN", 1, Some(2)),
+    ("PART", 1, Some(2)),
+    ("TOPIC", 1, Some(2)),
+    ("LIST", 0, Some(2)),
+    ("MODE", 1, None),
+    ("INVITE", 2, Some(2)),
+    ("KNOCK", 1, Some(2)),
+    ("WHOIS", 1, Some(2)),
+    ("WHOWAS", 1, Some(3)),
+    ("RESUME", 1, Some(1)),
+    ("NAMES", 0, Some(2)),
+    ("WHO", 0, Some(2)),
+    ("AWAY", 0, Some(1)),
+    ("SILENCE", 0, Some(1)),
+    ("ISON", 1, None),
+    ("USERHOST", 1, None),
+    ("OPER", 2, Some(2)),
+    ("KILL", 1, Some(2)),
+    ("WALLOPS", 1, Some(1)),
+    ("MOTD", 0, Some(1)),
+    ("VERSION", 0, Some(1)),
+    ("TIME", 0, Some(1)),
+    ("ADMIN", 0, Some(1)),
+    ("INFO", 0, Some(1)),
+    ("CAP", 1, Some(2)),
+    ("PASS", 1, Some(1)),
+    ("STATS", 0, Some(2)),
+    ("REHASH", 0, Some(0)),
+    ("DIE", 0, Some(0)),
+    ("RESTART", 0, Some(0)),
+    ("LUSERS", 0, Some(2)),
+    ("LINKS", 0, Some(2)),
+    ("SERVER", 3, Some(4)),
+    ("QUIT", 0, None),
+    ("PING", 0, Some(2)),
+    ("PONG", 0, Some(2)),
+];
+
+fn check_param_count(cmd: &str, params: &mut ParsedMsg) -> Result<(), ircError> {
+    if let Some((name, min, max)) = PARAM_COUNTS.iter().find(|(name, _min, _max)| *name == cmd) {
+        if params.opt_params.len() < *min {
+            return Err(ircError::NeedMoreParams(name.to_string()));
+        }
+        if let Some(max) = max {
+            params.opt_params.truncate(*max);
+        }
+    }
+    Ok(())
+}
+
+pub async fn command(irc: &Arc<Core>, client: &Arc<Client>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    if let ClientType::Server(server) = client.get_client_type() {
+        return server_command(irc, client, &server, params).await;
+    }
     let registered = client.is_registered();
     let cmd = params.command.to_ascii_uppercase();
 
-    match &cmd[..] {
+    /* flood control, for registered users that aren't opers. QUIT and PONG
+     * always get through, no sense making someone flood to get out. It's
+     * first, so even lines that only get an error back cost a token */
+    if registered && cmd != "QUIT" && cmd != "PONG" && !client.get_user().is_oper() {
+        match client.check_flood() {
+            FloodCheck::Allowed => (),
+            FloodCheck::Throttled => {
+                client.send_notice(&format!("{} dropped, you're sending too fast", cmd)).await?;
+                return Ok(Vec::new());
+            },
+            FloodCheck::Excess => return close_link(irc, client, "Excess Flood").await,
+        }
+    }
+    if let Err(err) = check_param_count(&cmd, &mut params) {
+        return Ok(vec![Err(err)]);
+    }
+    irc.count_command(&cmd);
+
+    let result = match &cmd[..] {
         "NICK" => nick(irc, client, params).await,
         "USER" => user(irc, client, params).await,
         "PRIVMSG" if registered => msg(irc, &client.get_user(), params, false).await,
@@ -579,164 +1975,1432 @@ pub async fn command(irc: &Arc<Core>, client: &Arc<Client>, params: ParsedMsg) -
         "JOIN" if registered => join(irc, &client.get_user(), params).await,
         "PART" if registered => part(irc, &client.get_user(), params).await,
         "TOPIC" if registered => topic(irc, &client.get_user(), params).await,
-        "LIST" if registered => list(irc).await,
-        "PART" | "JOIN" | "PRIVMSG" | "NOTICE" | "TOPIC" | "LIST" if !registered => gef!(ircError::NotRegistered),
+        "LIST" if registered => list(irc, &client.get_user(), params).await,
+        "MODE" if registered => mode(irc, &client.get_user(), params).await,
+        "KNOCK" if registered => knock(irc, &client.get_user(), params).await,
+        "INVITE" if registered => invite(irc, &client.get_user(), params).await,
+        "WHOIS" if registered => whois(irc, &client.get_user(), params).await,
+        "WHOWAS" if registered => whowas(irc, &client.get_user(), params).await,
+        "NAMES" if registered => names(irc, &client.get_user(), params).await,
+        "WHO" if registered => who(irc, &client.get_user(), params).await,
+        "AWAY" if registered => away(&client.get_user(), params).await,
+        "SILENCE" if registered => silence(&client.get_user(), params).await,
+        "ISON" if registered => ison(irc, params).await,
+        "USERHOST" if registered => userhost(irc, params).await,
+        "OPER" if registered => oper(irc, &client.get_user(), params).await,
+        "KILL" if registered => kill(irc, &client.get_user(), params).await,
+        "WALLOPS" if registered => wallops(irc, &client.get_user(), params).await,
+        "REHASH" if registered => rehash(irc, &client.get_user()).await,
+        "DIE" if registered => die(irc, &client.get_user()).await,
+        "RESTART" if registered => restart(irc, &client.get_user()).await,
+        "MOTD" if registered => motd(irc).await,
+        "STATS" if registered => stats(irc, &client.get_user(), params).await,
+        "LUSERS" if registered => lusers(irc).await,
+        "LINKS" if registered => links(irc).await,
+        "CAP" => cap(irc, client, params).await,
+        "SERVER" => server(irc, client, params).await,
+        "PASS" if !registered => pass(client, params).await,
+        "PASS" => gef!(ircError::AlreadyRegistred),
+        "VERSION" => version(irc).await,
+        "TIME" => time(irc).await,
+        "ADMIN" => admin(irc).await,
+        "INFO" => info(irc).await,
+        "QUIT" => quit(irc, client, params).await,
+        "PING" => ping(irc, client, params).await,
+        "PONG" => pong(irc, client, params).await,
+        "RESUME" if !registered => resume(irc, client, params).await,
+        "RESUME" => gef!(ircError::AlreadyRegistred),
+        "PART" | "JOIN" | "PRIVMSG" | "NOTICE" | "TOPIC" | "LIST" | "MODE" | "INVITE" | "KNOCK" | "WHOIS" | "WHOWAS" | "NAMES" | "WHO" | "AWAY" | "SILENCE" | "ISON" | "USERHOST" | "OPER" | "KILL" | "WALLOPS" | "REHASH" | "DIE" | "RESTART" | "MOTD" | "STATS" | "LUSERS" | "LINKS" if !registered => gef!(ircError::NotRegistered),
         _ => gef!(ircError::UnknownCommand(params.command.to_string())),
+    };
+
+    /* a PASS is only checked once NICK and USER are both in, and a wrong
+     * one loses the connection so passwords can't just be tried one after
+     * another on it */
+    if let Err(GenError::IRC(ircError::PasswdMismatch)) = result {
+        client.send_err(ircError::PasswdMismatch).await?;
+        return reject_link(irc, client, "Bad password").await;
     }
+    result
+}
+
+/* turn away a connection that isn't going to get registered, without the
+ * "Closing Link" treatment close_link() gives one that has */
+async fn reject_link(irc: &Core, client: &Client, error: &str) -> Result<ClientReplies, GenError> {
+    client.set_client_type(ClientType::Dead);
+    irc.remove_client(&client.get_id());
+    client.close_with_error(error).await;
+    Ok(Vec::new())
 }
 
-pub async fn list(irc: &Core) -> Result<ClientReplies, GenError> {
-    let tuple_vector = irc.get_list_reply();
+/* PASS <password> - only before registering, it gets checked once NICK
+ * and USER are both in, and a later PASS replaces an earlier one */
+pub async fn pass(client: &Client, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    client.set_password(Some(params.opt_params.remove(0)));
+    Ok(Vec::new())
+}
+
+/* CAP LS [<version>], LIST, REQ :<caps> or END, any of which are fine
+ * at any point in the connection. CAP LS or REQ before registering puts
+ * it on hold until CAP END, so NICK and USER on their own don't finish it
+ * off. A REQ is all or nothing, if any of the caps (or -caps) isn't one
+ * of ours it's NAKed */
+pub async fn cap(irc: &Core, client: &Arc<Client>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
-    for (chan, topic) in tuple_vector.iter() {
-        replies.push(Ok(ircReply::ListReply(chan.get_name(), chan.get_n_users(), topic.clone())));
+    let subcommand = params.opt_params.remove(0).to_ascii_uppercase();
+    match &subcommand[..] {
+        "LS" => {
+            if !client.is_registered() {
+                client.set_cap_pending(true);
+            }
+            let caps: Vec<String> = CAPABILITIES.iter().map(|cap| cap.to_string()).collect();
+            client.send_cap("LS", &caps).await?;
+        },
+        "LIST" => client.send_cap("LIST", &client.get_caps()).await?,
+        "REQ" => {
+            if !client.is_registered() {
+                client.set_cap_pending(true);
+            }
+            let requested = params.opt_params.pop().unwrap_or_default();
+            let changes: Vec<(bool, &str)> = requested
+                .split_whitespace()
+                .map(|cap| match cap.strip_prefix('-') {
+                    Some(cap) => (false, cap),
+                    None => (true, cap),
+                }).collect();
+            if !changes.is_empty() && changes.iter().all(|(_on, cap)| CAPABILITIES.contains(cap)) {
+                for (on, cap) in changes.iter() {
+                    client.set_cap(cap, *on);
+                }
+                client.send_cap("ACK", &[requested]).await?;
+            } else {
+                client.send_cap("NAK", &[requested]).await?;
+            }
+        },
+        "END" => {
+            if client.is_cap_pending() {
+                client.set_cap_pending(false);
+                finish_cap_negotiation(irc, client, &mut replies).await?;
+            }
+        },
+        _ => replies.push(Err(ircError::InvalidCapCmd(subcommand))),
     }
-    replies.push(Ok(ircReply::EndofList));
     Ok(replies)
 }
 
-pub async fn topic(irc: &Core, user: &User, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+/* if NICK and USER both turned up while CAP was holding things up, now's
+ * the time to register */
+async fn finish_cap_negotiation(irc: &Core, client: &Arc<Client>, replies: &mut ClientReplies) -> Result<(), GenError> {
+    let proto_user_ref = match client.get_client_type() {
+        ClientType::ProtoUser(proto_user_ref) => proto_user_ref,
+        _ => return Ok(()),
+    };
+    let details = {
+        let proto_user = proto_user_ref.lock().unwrap();
+        match (&proto_user.nick, &proto_user.username, &proto_user.real_name) {
+            (Some(nick), Some(username), Some(real_name)) => Some((nick.clone(), username.clone(), real_name.clone())),
+            _ => None,
+        }
+    };
+    if let Some((nick, username, real_name)) = details {
+        let user = irc.register(client, nick, username, real_name)?;
+        client.set_client_type(ClientType::User(user));
+        finish_registration(irc, client, replies).await?;
+    }
+    Ok(())
+}
+
+pub async fn ping(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
-    if params.opt_params.is_empty() {
-        replies.push(Err(ircError::NeedMoreParams("TOPIC".to_string())));
-        return Ok(replies);
+    if let Some(token) = params.opt_params.first() {
+        let line = format!(":{} PONG {} :{}", irc.get_host(), irc.get_host(), token);
+        client.send_line(&line).await?;
+    } else {
+        replies.push(Err(ircError::NoOrigin));
     }
+    Ok(replies)
+}
 
-    /* are ya in the chan? */
-    let chanmask = params.opt_params.remove(0);
-    let chan = irc.get_chan(&chanmask)?;
-    if !chan.is_joined(&user.get_nick()) {
-        replies.push(Err(ircError::NotOnChannel(chanmask)));
-        return Ok(replies);
+/* the client's idle timer has already been reset by the time we get here,
+ * so all that's left is to check they're answering the right question */
+pub async fn pong(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    match params.opt_params.first() {
+        Some(token) if *token != irc.get_host() => {
+            debug!("client {} sent PONG with mismatched token {}", client.get_id(), token);
+        },
+        Some(_token) => (),
+        None => replies.push(Err(ircError::NoOrigin)),
     }
+    Ok(replies)
+}
 
-    /* just want to receive topic? */
-    if params.opt_params.is_empty() {
-        if let Some(topic) = chan.get_topic() {
-            replies.push(Ok(ircReply::Topic(chanmask.clone(), topic.text)));
-            replies.push(Ok(ircReply::TopicSetBy(chanmask, topic.usermask, topic.timestamp)));
-        } else {
-            replies.push(Ok(ircReply::NoTopic(chanmask)));
+pub async fn quit(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let reason = rfc::strip_control(&params.opt_params.join(" "));
+    let quit_msg = if reason.is_empty() {
+        String::from("Client Quit")
+    } else {
+        format!("{}{}", irc.config.read().unwrap().quit_prefix, reason)
+    };
+    close_link(irc, client, &quit_msg).await
+}
+
+/* the client's going, whether they asked to or not (e.g. Excess Flood) */
+async fn close_link(irc: &Core, client: &Arc<Client>, quit_msg: &str) -> Result<ClientReplies, GenError> {
+    /* tell everyone we share a channel with, then unlink from the channels
+     * and the namespace - unregistered clients have nobody to tell */
+    match client.get_client_type() {
+        ClientType::User(user) => {
+            user.announce_quit(quit_msg).await;
+            user.clear_up();
+        },
+        ClientType::Server(server) => {
+            irc.split_server(&server).await;
+        },
+        _ => (),
+    }
+
+    /* process_lines() notices this and hangs up */
+    client.set_client_type(ClientType::Dead);
+    irc.remove_client(&client.get_id());
+    client.send_error_and_close(quit_msg).await;
+    Ok(Vec::new())
+}
+
+/* NAMES [<channel>{,<channel>}] - without a channel list, every channel
+ * the user can see is listed, then everyone not in any of those channels
+ * gets lumped together under `*` */
+pub async fn names(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let nick = user.get_nick();
+    let visible = |chan: &Channel| !chan.get_modes().secret || chan.is_joined(&nick);
+    let mut replies = Vec::new();
+
+    if let Some(targets) = params.opt_params.first() {
+        for chanmask in targets.split(',') {
+            if let Ok(chan) = irc.get_chan(chanmask) {
+                if visible(&chan) {
+                    replies.push(Ok(ircReply::NameReply(chan.get_name(), chan.get_nick_list_for(user))));
+                }
+            }
+            replies.push(Ok(ircReply::EndofNames(chanmask.to_string())));
         }
         return Ok(replies);
-    };
-    
-    /* set topic IF permissions allow */
-    if chan.is_op(user) {
-        chan.set_topic(&params.opt_params.remove(0), &user);
-    } else {
-        replies.push(Err(ircError::ChanOPrivsNeeded(chanmask)));
     }
+
+    for chan in irc.list_chans_ptr().iter().filter(|chan| visible(chan)) {
+        replies.push(Ok(ircReply::NameReply(chan.get_name(), chan.get_nick_list_for(user))));
+        replies.push(Ok(ircReply::EndofNames(chan.get_name())));
+    }
+    let loners: Vec<String> = irc.all_users()
+        .iter()
+        .filter(|other| {
+            !other.get_channel_list()
+                .iter()
+                .filter_map(Weak::upgrade)
+                .any(|chan| visible(&chan))
+        }).filter(|other| other.is_visible_to(user))
+        .map(|other| other.get_nick())
+        .collect();
+    if !loners.is_empty() {
+        replies.push(Ok(ircReply::NameReply(String::from("*"), loners)));
+    }
+    replies.push(Ok(ircReply::EndofNames(String::from("*"))));
     Ok(replies)
 }
 
-pub async fn join(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
-    let mut replies = Vec::new();
-    if params.opt_params.is_empty() {
-        replies.push(Err(ircError::NeedMoreParams("JOIN".to_string())));
-        return Ok(replies);
+/* RESUME <token> in place of NICK/USER picks up a session whose connection
+ * dropped, the new connection gets the welcome burst and then the channel
+ * state of the old one */
+pub async fn resume(irc: &Core, client: &Arc<Client>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let token = params.opt_params.remove(0);
+    let user = match irc.take_parked(&token) {
+        Some(user) => user,
+        None => {
+            let line = format!(":{} FAIL RESUME INVALID_TOKEN :Cannot resume connection, token is invalid", irc.get_host());
+            client.send_line(&line).await?;
+            return Ok(Vec::new());
+        }
+    };
+
+    debug!("client {} resumes session of {}", client.get_id(), user.get_nick());
+    user.set_client(client);
+    client.set_client_type(ClientType::User(Arc::clone(&user)));
+    client.send_line(&format!(":{} RESUME SUCCESS {}", irc.get_host(), user.get_nick())).await?;
+    for reply in gen_welcome_burst(irc, &user).into_iter() {
+        client.send_rpl(reply).await?;
     }
+    user.resync_state(client).await?;
+    send_resume_token(irc, client, &user).await?;
+    Ok(Vec::new())
+}
 
-    /* JOIN can take a second argument. The format is:
-     * JOIN comma,sep.,chan,list comma,sep.,key,list
-     * but I'll leave key implementation til later */
-    let targets = params.opt_params.remove(0);
-    for target in targets.split(',') {
-        replies.append(&mut irc.join_chan(&target, user).await?);
+/* a resumed session gets a fresh token straight away, the one it came
+ * back with has been used up. Nothing's sent with resumption switched off */
+async fn send_resume_token(irc: &Core, client: &Client, user: &User) -> Result<(), GenError> {
+    if let Some(token) = irc.issue_resume_token(user) {
+        client.send_line(&format!(":{} RESUME TOKEN {}", irc.get_host(), token)).await?;
     }
-    Ok(replies)
+    Ok(())
 }
 
-pub async fn part(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
-    let mut replies: ClientReplies = Vec::new();
-    if params.opt_params.is_empty() {
-        replies.push(Err(ircError::NeedMoreParams("PART".to_string())));
-        return Ok(replies);
+/* 001 to 005 */
+fn gen_welcome_burst(irc: &Core, user: &User) -> Vec<ircReply> {
+    let mut burst = vec![
+        ircReply::Welcome(irc.get_network_name(), user.get_nick(), user.get_username(), user.get_host_string()),
+        ircReply::YourHost(irc.get_host(), irc.get_version()),
+        ircReply::Created(irc.get_date()),
+        ircReply::MyInfo(irc.get_host(), irc.get_version(), irc.get_umodes(), irc.get_chanmodes()),
+    ];
+    burst.append(&mut irc.gen_isupport_replies());
+    burst
+}
+
+/* NICK and USER both end up here whenever the client type moves on, which
+ * only means registration is done if they're now a User. Whichever of the
+ * two came second is the one that gets here with a User, so the burst
+ * only ever goes out the once */
+async fn finish_registration(irc: &Core, client: &Client, replies: &mut ClientReplies) -> Result<(), GenError> {
+    let user = match client.get_client_type() {
+        ClientType::User(user) => user,
+        _ => return Ok(()),
+    };
+    replies.extend(gen_welcome_burst(irc, &user).into_iter().map(Ok));
+    replies.extend(irc.gen_lusers_replies().into_iter().map(Ok));
+    replies.append(&mut irc.gen_motd_replies());
+    if let Some(token) = irc.issue_resume_token(&user) {
+        for result_t in replies.drain(..) {
+            match result_t {
+                Ok(reply) => client.send_rpl(reply).await?,
+                Err(err) => client.send_err(err).await?
+            }
+        }
+        client.send_line(&format!(":{} RESUME TOKEN {}", irc.get_host(), token)).await?;
     }
+    Ok(())
+}
 
-    let targets = params.opt_params.remove(0);
-    let part_msg = if params.opt_params.is_empty() {
-        String::from("")
-    } else {
-        params.opt_params.remove(0)
+/* LIST [<channel>{,<channel>} [<server>]] - secret channels only show up
+ * for their members, whether asked for by name or not */
+pub async fn list(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let tuple_vector = match params.opt_params.first() {
+        Some(targets) => targets
+            .split(',')
+            .filter_map(|chanmask| irc.get_chan(chanmask).ok())
+            .map(|chan| {
+                let topic = chan.get_topic();
+                (chan, topic)
+            }).collect(),
+        None => irc.get_list_reply(),
     };
-    for target in targets.split(',') {
-        replies.push(irc.part_chan(&target, user, &part_msg).await);
+    let nick = user.get_nick();
+    let mut replies = Vec::new();
+    for (chan, topic) in tuple_vector.iter() {
+        if chan.get_modes().secret && !chan.is_joined(&nick) {
+            continue;
+        }
+        replies.push(Ok(ircReply::ListReply(chan.get_name(), chan.get_n_users(), topic.clone())));
     }
+    replies.push(Ok(ircReply::EndofList));
     Ok(replies)
 }
 
-pub async fn msg(
-    irc: &Core,
-    send_u: &Arc<User>,
-    mut params: ParsedMsg,
-    notice: bool,
-) -> Result<ClientReplies, GenError> {
+pub async fn mode(irc: &Core, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
+    let chanmask = params.opt_params.remove(0);
+    if !rfc::valid_channel(&chanmask) {
+        return user_mode(irc, user, &chanmask, params).await;
+    }
+    let chan = irc.get_chan(&chanmask)?;
+
+    /* no mode string, they just want to know what the modes are */
     if params.opt_params.is_empty() {
-        if !notice {
-                replies.push(Err(ircError::NoRecipient("PRIVMSG".to_string())));
-        }
+        let member = chan.is_joined(&user.get_nick());
+        replies.push(Ok(ircReply::ChannelModeIs(chanmask.clone(), chan.gen_mode_string(member))));
+        replies.push(Ok(ircReply::CreationTime(chanmask, chan.get_created())));
         return Ok(replies);
     }
-    /* this appears to be what's crashing, despite the check for params.opt_params.is_empty() beforehand
-     * ah, I'd forgotten to remove one of the notice bools from the above if statements,
-     * if params.opt_params.is_empty() && notice won't work */
-    let targets = params.opt_params.remove(0); 
-    let cmd = if notice { "NOTICE" } else { "PRIVMSG" };
 
-    // if there were no more args, message should be an empty String
-    if params.opt_params.is_empty() {
-        if !notice {
-            replies.push(Err(ircError::NoTextToSend));
+    /* MODE #chan b (or +e, bI...) with no masks is only asking to see the
+     * lists, which any member can do, it's putting masks on that's for ops */
+    let list_query = params.opt_params.len() == 1
+        && params.opt_params[0].chars().all(|letter| "+-beI".contains(letter));
+    if list_query && chan.is_joined(&user.get_nick()) {
+        for letter in params.opt_params[0].chars().filter(|letter| "beI".contains(*letter)) {
+            replies.append(&mut chan.gen_mask_list_replies(letter));
         }
         return Ok(replies);
     }
-    // if there are more than two arguments,
-    // concatenate the remainder to one string
-    let message = params.opt_params.join(" ");
-    trace!("{} from user {} to {}, content: {}", cmd, send_u.get_nick(), targets, message);
 
-    // loop over targets
-    for target in targets.split(',') {
-        match irc.get_name(target) {
-            Some(NamedEntity::User(user_weak)) => {
-                match User::upgrade(&user_weak, target) {
-                    Ok(recv_u) => {
-                        replies.push(recv_u.send_msg(&send_u, &cmd, &target, &message).await?);
-                    },
-                    Err(GenError::DeadUser(nick)) => {
-                        let _res = irc.search_user_chans_purge(&nick);
-                        if let Err(err) = irc.remove_name(&nick) {
-                            warn!("error {} removing nick {} from hash, but it doesn't exist", err, &nick)
-                        }
-                    },
-                    /* this may be a more serious error & will abort processing the join command */
-                    Err(e) => return Err(e),
-                }
+    /* opers get to act as chanops anywhere, but it goes on the record */
+    if !chan.is_op(user) {
+        if !user.is_oper() {
+            replies.push(Err(ircError::ChanOPrivsNeeded(chanmask)));
+            return Ok(replies);
+        }
+        if let Err(err) = irc.audit(user, "MODE", &chanmask) {
+            replies.push(Err(err));
+            return Ok(replies);
+        }
+    }
+
+    /* e.g. MODE #chan +ov-t nick1 nick2, arguments are consumed in
+     * order by the mode letters that take them */
+    let mode_string = params.opt_params.remove(0);
+    let mut args = params.opt_params.into_iter().peekable();
+    let mut changes = Vec::new();
+    let mut adding = true;
+    for letter in mode_string.chars() {
+        match letter {
+            '+' => adding = true,
+            '-' => adding = false,
+            /* a list mode with no mask left to take means show the list */
+            'b' | 'e' | 'I' if args.peek().is_none() => {
+                replies.append(&mut chan.gen_mask_list_replies(letter));
+            },
+            _ => match chan.apply_mode(user, adding, letter, &mut args) {
+                Ok(Some(change)) => changes.push(change),
+                Ok(None) => (),
+                Err(err) => replies.push(Err(err)),
             },
-            Some(NamedEntity::Chan(chan))
-                => replies.push(chan.send_msg(&send_u, &cmd, &target, &message).await?),
-            None => replies.push(Err(ircError::NoSuchNick(target.to_string())))
         }
     }
+
+    if !changes.is_empty() {
+        let line = format!(
+            ":{} MODE {} {}",
+            user.get_prefix(),
+            chan.get_name(),
+            chan::format_mode_changes(&changes)
+        );
+        chan.broadcast(&line).await;
+    }
+    /* an oper taking -P off a channel nobody's in means it can go now */
+    if chan.is_disposable() {
+        let _res = irc.remove_empty_chan(&chan);
+    }
     Ok(replies)
 }
 
-pub async fn user(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
-    // a USER command should have exactly four parameters
-    // <username> <hostname> <servername> <realname>,
-    // though we ignore the middle two unless a server is
-    // forwarding the message
+/* MODE <nick> [<modes>] - only ever our own nick. +i and +w can go
+ * either way, but +o only comes from OPER so can just be dropped here */
+async fn user_mode(irc: &Core, user: &Arc<User>, target: &str, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
-    let args = params.opt_params;
-    if args.len() != 4 {
-        return gef!(ircError::NeedMoreParams("USER".to_string()));
+    if rfc::to_irc_lower(target) != rfc::to_irc_lower(&user.get_nick()) {
+        if irc.get_nick(target).is_some() {
+            replies.push(Err(ircError::UsersDontMatch));
+        } else {
+            replies.push(Err(ircError::NoSuchNick(target.to_string())));
+        }
+        return Ok(replies);
     }
-    let username = args[0].clone();
-    let real_name = args[3].clone();
 
-    let result = match client.get_client_type() {
+    if params.opt_params.is_empty() {
+        replies.push(Ok(ircReply::UserModeIs(user.gen_mode_string())));
+        return Ok(replies);
+    }
+
+    let mut changes = Vec::new();
+    let mut adding = true;
+    let mut unknown = false;
+    for letter in params.opt_params.remove(0).chars() {
+        let (was, set): (bool, fn(&User, bool)) = match letter {
+            '+' => { adding = true; continue },
+            '-' => { adding = false; continue },
+            'i' => (user.is_invisible(), User::set_invisible),
+            'w' => (user.is_wallops(), User::set_wallops),
+            'o' if !adding => (user.is_oper(), User::set_oper),
+            'o' => continue,
+            _ => { unknown = true; continue },
+        };
+        if was != adding {
+            set(user, adding);
+            changes.push(chan::ModeChange { adding, letter, arg: None });
+        }
+    }
+    if unknown {
+        replies.push(Err(ircError::UModeUnknownFlag));
+    }
+    if !changes.is_empty() {
+        let line = format!(":{} MODE {} :{}", user.get_prefix(), user.get_nick(), chan::format_mode_changes(&changes));
+        user.send_line(&line).await?;
+    }
+    Ok(replies)
+}
+
+/* VERSION [<target>] - 351 and then the same 005s as at registration,
+ * which anyone can ask for, registered or not */
+pub async fn version(irc: &Core) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let version = format!("{}-{}.", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    replies.push(Ok(ircReply::Version(version, irc.get_host(), irc.get_server_info())));
+    replies.extend(irc.gen_isupport_replies().into_iter().map(Ok));
+    Ok(replies)
+}
+
+/* TIME [<target>] - our own local time, whoever's asking */
+pub async fn time(irc: &Core) -> Result<ClientReplies, GenError> {
+    Ok(vec![Ok(ircReply::Time(irc.get_host(), Local::now().to_rfc2822()))])
+}
+
+/* ADMIN [<target>] - who runs the place and how to get hold of them */
+pub async fn admin(irc: &Core) -> Result<ClientReplies, GenError> {
+    Ok(irc.gen_admin_replies().into_iter().map(Ok).collect())
+}
+
+/* INFO [<target>] - what we are, who wrote us, and since when we've been up */
+pub async fn info(irc: &Core) -> Result<ClientReplies, GenError> {
+    let mut lines = vec![
+        format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        format!("Written by {}", env!("CARGO_PKG_AUTHORS").replace(':', ", ")),
+        format!("Licensed under {}", env!("CARGO_PKG_LICENSE")),
+    ];
+    let repository = env!("CARGO_PKG_REPOSITORY");
+    if !repository.is_empty() {
+        lines.push(format!("Source: {}", repository));
+    }
+    lines.push(format!("On-line since {}", irc.get_date()));
+    let mut replies: ClientReplies = lines.into_iter().map(|line| Ok(ircReply::Info(line))).collect();
+    replies.push(Ok(ircReply::EndofInfo));
+    Ok(replies)
+}
+
+/* MOTD [<target>] - there's only us, so the target doesn't matter */
+pub async fn motd(irc: &Core) -> Result<ClientReplies, GenError> {
+    Ok(irc.gen_motd_replies())
+}
+
+/* LUSERS [<mask> [<target>]] - neither makes a difference with one server */
+pub async fn lusers(irc: &Core) -> Result<ClientReplies, GenError> {
+    Ok(irc.gen_lusers_replies().into_iter().map(Ok).collect())
+}
+
+/* LINKS [[<remote server>] <server mask>] - us and whoever's linked
+ * directly to us, whatever mask they give gets ignored */
+pub async fn links(irc: &Core) -> Result<ClientReplies, GenError> {
+    let host = irc.get_host();
+    let mut replies = vec![Ok(ircReply::Links(host.clone(), host.clone(), 0, irc.get_server_info()))];
+    for server in irc.get_servers().iter() {
+        replies.push(Ok(ircReply::Links(server.get_name(), host.clone(), server.get_hopcount(), server.get_info())));
+    }
+    replies.push(Ok(ircReply::EndofLinks(String::from("*"))));
+    Ok(replies)
+}
+
+/* SERVER <servername> <hopcount> [<token>] :<info> - a peer server
+ * introducing itself, having sent PASS first. If it's one we link with
+ * we answer with our own PASS and SERVER */
+pub async fn server(irc: &Core, client: &Arc<Client>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    match client.get_client_type() {
+        ClientType::Unregistered | ClientType::ProtoUser(_) => (),
+        ClientType::Dead => return Ok(replies),
+        _ => {
+            replies.push(Err(ircError::AlreadyRegistred));
+            return Ok(replies);
+        }
+    }
+    let info = params.opt_params.pop().unwrap_or_default();
+    let name = params.opt_params.remove(0);
+    let hopcount = match params.opt_params[0].parse::<u32>() {
+        Ok(hopcount) => hopcount,
+        Err(_) => {
+            replies.push(Err(ircError::NeedMoreParams(String::from("SERVER"))));
+            return Ok(replies);
+        }
+    };
+    let send_password = match irc.check_link(&name, client.get_password().as_deref()) {
+        Some(password) => password,
+        None => {
+            warn!("client {} tried to link as {} without the right password", client.get_id(), name);
+            client.send_err(ircError::PasswdMismatch).await?;
+            return reject_link(irc, client, "Bad password").await;
+        }
+    };
+
+    /* like a wrong password, this is the end of the connection rather than
+     * something to try again on it */
+    let server = Arc::new(Server { name, hopcount, info, client_id: client.get_id(), users: Mutex::new(HashMap::new()) });
+    if !irc.add_server(&server) {
+        warn!("client {} tried to link as {}, which is already here", client.get_id(), server.get_name());
+        return reject_link(irc, client, &format!("ID \"{}\" already registered", server.get_name())).await;
+    }
+    client.set_client_type(ClientType::Server(Arc::clone(&server)));
+    info!("server {} linked on client {}", server.get_name(), client.get_id());
+    client.send_line(&format!("PASS {}", send_password)).await?;
+    client.send_line(&format!(":{} SERVER {} 1 :{}", irc.get_host(), irc.get_host(), irc.get_server_info())).await?;
+    for line in irc.generate_burst().iter() {
+        client.send_line(line).await?;
+    }
+    Ok(replies)
+}
+
+/* everything a linked server sends us comes through here rather than the
+ * user command table. For now that's its burst (NICK and NJOIN), KILLs
+ * for its own users, keeping the link alive, and it going away */
+async fn server_command(irc: &Arc<Core>, client: &Arc<Client>, server: &Arc<Server>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    match &params.command.to_ascii_uppercase()[..] {
+        "NICK" => ingest_nick(irc, client, server, params).await,
+        "NJOIN" => ingest_njoin(irc, server, params).await,
+        "KILL" => ingest_kill(irc, server, params).await,
+        "PING" => ping(irc, client, params).await,
+        "PONG" => pong(irc, client, params).await,
+        "SQUIT" | "ERROR" | "QUIT" => close_link(irc, client, "Link closed by peer").await,
+        cmd => {
+            debug!("ignoring {} from server {}", cmd, server.get_name());
+            Ok(Vec::new())
+        }
+    }
+}
+
+/* a user from the other side of the link. If the nick's already in use,
+ * whichever of the two signed on first keeps it and the other is killed,
+ * both if they signed on at the same time. Either way the peer gets a
+ * KILL for the nick, it may have taken our user in from our own burst */
+async fn ingest_nick(irc: &Arc<Core>, client: &Arc<Client>, server: &Arc<Server>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let intro = match RemoteIntro::parse(&params.opt_params) {
+        Some(intro) => intro,
+        None => {
+            warn!("bad NICK introduction from server {}: {:?}", server.get_name(), params.opt_params);
+            return Ok(Vec::new());
+        }
+    };
+    if let Some(existing) = irc.get_nick(&intro.nick).and_then(|user_weak| Weak::upgrade(&user_weak)) {
+        let existing_signon = existing.get_signon();
+        if intro.signon <= existing_signon {
+            if let Err(err) = existing.send_err(ircError::NickCollision(existing.get_nick())).await {
+                debug!("failed to send nick collision to {}: {}", existing.get_nick(), err);
+            }
+            existing.announce_quit("Nick collision").await;
+            irc.kill_user(&existing, "Nick collision").await;
+        }
+        let host = irc.get_host();
+        client.send_line(&format!(":{} KILL {} :{} (Nick collision)", host, intro.nick, host)).await?;
+        if intro.signon >= existing_signon {
+            return Ok(Vec::new());
+        }
+    }
+    let user = User::remote(irc, client, server, intro);
+    irc.insert_name(&user.get_nick(), NamedEntity::User(Arc::downgrade(&user)))?;
+    server.add_user(&user);
+    Ok(Vec::new())
+}
+
+/* KILL <nick> [:<reason>] from a linked server. With the server as
+ * prefix it only gets to remove users it introduced itself (e.g. the
+ * loser of a nick collision it's sorted out). With one of its users as
+ * prefix it's an oper over there, checked on their end, and then it can
+ * take out one of ours too. Never another server's */
+async fn ingest_kill(irc: &Arc<Core>, server: &Server, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let killer = match &params.opt_prefix {
+        Some(MsgPrefix::Name(nick)) | Some(MsgPrefix::Nick(nick)) | Some(MsgPrefix::NickHost(nick, _)) | Some(MsgPrefix::NickUserHost(nick, _, _)) => {
+            irc.get_nick(nick).and_then(|user_weak| Weak::upgrade(&user_weak)).filter(|killer| killer.get_server() == server.get_name())
+        },
+        _ => None,
+    };
+    let victim = match params.opt_params.first().and_then(|nick| irc.get_nick(nick)).and_then(|user_weak| Weak::upgrade(&user_weak)) {
+        Some(victim) if victim.get_server() == server.get_name() => victim,
+        Some(victim) if killer.is_some() && !victim.is_remote() => victim,
+        _ => {
+            debug!("ignoring KILL from server {}: {:?}", server.get_name(), params.opt_params);
+            return Ok(Vec::new());
+        }
+    };
+    let reason = params.opt_params.get(1).cloned().unwrap_or_else(|| server.get_name());
+    let quit_msg = match &killer {
+        Some(killer) => format!("Killed ({} ({}))", killer.get_nick(), reason),
+        None => format!("Killed ({})", reason),
+    };
+    victim.announce_quit(&quit_msg).await;
+    if let Some(killer) = killer.filter(|_| !victim.is_remote()) {
+        let line = format!(":{} KILL {} :{}", killer.get_prefix(), victim.get_nick(), reason);
+        if let Err(err) = victim.send_line(&line).await {
+            debug!("failed to send KILL to {}: {}", victim.get_nick(), err);
+        }
+    }
+    irc.kill_user(&victim, &quit_msg).await;
+    Ok(Vec::new())
+}
+
+/* NJOIN <channel> :[@|+]<nick>{,[@|+]<nick>} - some of the peer's users
+ * in a channel. Only users that server introduced get joined */
+async fn ingest_njoin(irc: &Arc<Core>, server: &Server, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let (chanmask, members) = match (params.opt_params.first(), params.opt_params.get(1)) {
+        (Some(chanmask), Some(members)) if rfc::valid_channel(chanmask) => (chanmask, members),
+        _ => {
+            warn!("bad NJOIN from server {}: {:?}", server.get_name(), params.opt_params);
+            return Ok(Vec::new());
+        }
+    };
+    let chan = match irc.get_chan(chanmask) {
+        Ok(chan) => chan,
+        Err(_) => {
+            let chan = Arc::new(Channel::new(irc, chanmask));
+            irc.insert_name(chanmask, NamedEntity::Chan(Arc::clone(&chan)))?;
+            chan
+        }
+    };
+    for member in members.split(',').filter(|member| !member.is_empty()) {
+        let nick = member.trim_start_matches(&['@', '+'][..]);
+        let badges = &member[..member.len() - nick.len()];
+        let flags = match (badges.contains('@'), badges.contains('+')) {
+            (true, true) => ChanFlags::OpVoice,
+            (true, false) => ChanFlags::Op,
+            (false, true) => ChanFlags::Voice,
+            (false, false) => ChanFlags::None,
+        };
+        let user = match irc.get_nick(nick).and_then(|user_weak| Weak::upgrade(&user_weak)) {
+            Some(user) if user.get_server() == server.get_name() => user,
+            _ => continue,
+        };
+        if chan.link_user(&user, flags).is_none() {
+            continue;
+        }
+        /* only our own users need telling, the peer knows already */
+        let line = format!(":{} JOIN {}", user.get_prefix(), chan.get_name());
+        for local in chan.gen_user_ptr_vec().iter().filter(|member| !member.is_remote()) {
+            if let Err(err) = local.send_line(&line).await {
+                debug!("failed to send JOIN to {}: {}", local.get_nick(), err);
+            }
+        }
+    }
+    Ok(Vec::new())
+}
+
+/* STATS [<letter> [<target>]] - u for uptime, m for how many times each
+ * command's been used, c (opers only) to run the consistency check, and
+ * anything else just gets the end of the report */
+pub async fn stats(irc: &Core, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let letter = params.opt_params.first()
+        .and_then(|query| query.chars().next())
+        .unwrap_or('*');
+    match letter {
+        'u' => replies.push(Ok(ircReply::StatsUptime(irc.get_uptime().as_secs()))),
+        'm' => replies.extend(
+            irc.get_command_counts()
+                .into_iter()
+                .map(|(cmd, count)| Ok(ircReply::StatsCommands(cmd.to_string(), count)))
+        ),
+        /* opers can run the consistency check now rather than wait for
+         * the next periodic one */
+        'c' if !user.is_oper() => replies.push(Err(ircError::NoPrivileges)),
+        'c' => {
+            if let Err(err) = irc.audit(user, "STATS", "c") {
+                replies.push(Err(err));
+                return Ok(replies);
+            }
+            let repairs = irc.check_consistency();
+            user.send_notice(&format!("Consistency check made {} repairs", repairs)).await?;
+        },
+        _ => (),
+    }
+    replies.push(Ok(ircReply::EndofStats(letter.to_string())));
+    Ok(replies)
+}
+
+/* OPER <name> <password> - which, like any other oper action, doesn't
+ * happen if it can't be written to the audit log */
+pub async fn oper(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let (name, password) = (&params.opt_params[0], &params.opt_params[1]);
+    if !irc.check_oper(name, password) {
+        /* failures get recorded too, but they're refused either way */
+        let _res = irc.audit(user, "OPER-FAILED", name);
+        replies.push(Err(ircError::PasswdMismatch));
+        return Ok(replies);
+    }
+    if let Err(err) = irc.audit(user, "OPER", name) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    user.set_oper(true);
+    replies.push(Ok(ircReply::YoureOper));
+    Ok(replies)
+}
+
+/* KILL <nick> [:<reason>] - opers only. Everyone sharing a channel with
+ * the victim sees them QUIT, the victim gets the KILL itself, and then
+ * the connection goes (or the parked session, if they're waiting to RESUME) */
+pub async fn kill(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if !user.is_oper() {
+        replies.push(Err(ircError::NoPrivileges));
+        return Ok(replies);
+    }
+    let target = &params.opt_params[0];
+    let victim = match irc.get_nick(target).and_then(|user_ptr| Weak::upgrade(&user_ptr)) {
+        Some(victim) => victim,
+        None => {
+            replies.push(Err(ircError::NoSuchNick(target.to_string())));
+            return Ok(replies);
+        }
+    };
+    if let Err(err) = irc.audit(user, "KILL", &victim.get_nick()) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+
+    let reason = params.opt_params.get(1).cloned().unwrap_or_else(|| user.get_nick());
+    let quit_msg = format!("Killed ({} ({}))", user.get_nick(), reason);
+    victim.announce_quit(&quit_msg).await;
+    /* for a remote user this goes down the link, and the server it's on
+     * takes it as coming from an oper of ours */
+    let line = format!(":{} KILL {} :{}", user.get_prefix(), victim.get_nick(), reason);
+    if let Err(err) = victim.send_line(&line).await {
+        debug!("failed to send KILL to {}: {}", victim.get_nick(), err);
+    }
+    irc.kill_user(&victim, &quit_msg).await;
+    Ok(replies)
+}
+
+/* REHASH - opers only, re-reads CONFIG_PATH and the MOTD file and swaps
+ * in everything Config holds without anyone having to reconnect */
+pub async fn rehash(irc: &Core, user: &User) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if !user.is_oper() {
+        replies.push(Err(ircError::NoPrivileges));
+        return Ok(replies);
+    }
+    if let Err(err) = irc.audit(user, "REHASH", CONFIG_PATH) {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    irc.apply_config(Config::load());
+    replies.push(Ok(ircReply::Rehashing(CONFIG_PATH.to_string())));
+    Ok(replies)
+}
+
+/* DIE - opers only, everyone (the oper included) gets an ERROR and then
+ * the binary is told to exit through wait_for_shutdown() */
+pub async fn die(irc: &Core, user: &User) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if !user.is_oper() {
+        replies.push(Err(ircError::NoPrivileges));
+        return Ok(replies);
+    }
+    if let Err(err) = irc.audit(user, "DIE", "*") {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    warn!("DIE from {}, shutting down", user.get_nick());
+    irc.shutdown(&format!("DIE from {}", user.get_nick())).await;
+    irc.request_shutdown(ShutdownKind::Die);
+    Ok(replies)
+}
+
+/* RESTART - DIE, except the binary re-execs itself afterwards */
+pub async fn restart(irc: &Core, user: &User) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if !user.is_oper() {
+        replies.push(Err(ircError::NoPrivileges));
+        return Ok(replies);
+    }
+    if let Err(err) = irc.audit(user, "RESTART", "*") {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    warn!("RESTART from {}, restarting", user.get_nick());
+    irc.shutdown("Restarting").await;
+    irc.request_shutdown(ShutdownKind::Restart);
+    Ok(replies)
+}
+
+/* WALLOPS :<text> - opers only, goes to everyone who's +w. Anyone whose
+ * client turns out to be dead is tidied up by send_line() on the way */
+pub async fn wallops(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if !user.is_oper() {
+        replies.push(Err(ircError::NoPrivileges));
+        return Ok(replies);
+    }
+    if let Err(err) = irc.audit(user, "WALLOPS", "*") {
+        replies.push(Err(err));
+        return Ok(replies);
+    }
+    let line = format!(":{} WALLOPS :{}", user.get_prefix(), params.opt_params[0]);
+    for recv_u in irc.all_users().iter().filter(|other| other.is_wallops()) {
+        if let Err(err) = recv_u.send_line(&line).await {
+            debug!("failed to send WALLOPS to {}: {}", recv_u.get_nick(), err);
+        }
+    }
+    Ok(replies)
+}
+
+/* AWAY [:<message>] - no message (or an empty one) means we're back */
+pub async fn away(user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    match params.opt_params.into_iter().next().filter(|away_msg| !away_msg.is_empty()) {
+        Some(away_msg) => {
+            user.set_away(Some(away_msg));
+            replies.push(Ok(ircReply::NowAway));
+        },
+        None => {
+            user.set_away(None);
+            replies.push(Ok(ircReply::UnAway));
+        }
+    }
+    Ok(replies)
+}
+
+/* SILENCE [{+|-}<mask>{,{+|-}<mask>}] - without a param, list our own
+ * masks. Masks get filled out to nick!user@host, and a change is echoed
+ * back as a SILENCE line from ourselves, the way other servers do it */
+pub async fn silence(user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let changes = match params.opt_params.first() {
+        Some(changes) => changes,
+        None => {
+            let nick = user.get_nick();
+            for mask in user.get_silence_list().into_iter() {
+                replies.push(Ok(ircReply::SilenceList(nick.clone(), mask)));
+            }
+            replies.push(Ok(ircReply::EndofSilenceList));
+            return Ok(replies);
+        }
+    };
+    for change in changes.split(',').filter(|change| !change.is_empty()) {
+        let (adding, mask) = match change.strip_prefix('-') {
+            Some(mask) => (false, mask),
+            None => (true, change.strip_prefix('+').unwrap_or(change)),
+        };
+        if mask.is_empty() {
+            continue;
+        }
+        let mask = rfc::expand_mask(mask);
+        let changed = if adding {
+            match user.add_silence(&mask) {
+                Ok(changed) => changed,
+                Err(err) => {
+                    replies.push(Err(err));
+                    continue;
+                }
+            }
+        } else {
+            user.remove_silence(&mask)
+        };
+        if changed {
+            let sign = if adding { '+' } else { '-' };
+            user.send_line(&format!(":{} SILENCE {}{}", user.get_prefix(), sign, mask)).await?;
+        }
+    }
+    Ok(replies)
+}
+
+/* ISON <nick> *( SPACE <nick> ) - which of these nicks are online right
+ * now, in a single 303 (send_rpl() splits it up if it gets too long).
+ * Clients tend to send the nicks as one trailing param, so split those too */
+pub async fn ison(irc: &Core, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let online = params.opt_params
+        .iter()
+        .flat_map(|param| param.split_whitespace())
+        .filter_map(|nick| irc.get_nick(nick))
+        .filter_map(|user_ptr| Weak::upgrade(&user_ptr))
+        .map(|user| user.get_nick())
+        .collect();
+    replies.push(Ok(ircReply::IsOn(online)));
+    Ok(replies)
+}
+
+/* USERHOST <nick> *( SPACE <nick> ) - up to five of them, each one online
+ * comes back as nick[*]=[+|-]user@host, * for opers and - for away */
+pub async fn userhost(irc: &Core, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let tokens = params.opt_params
+        .iter()
+        .flat_map(|param| param.split_whitespace())
+        .take(5)
+        .filter_map(|nick| irc.get_nick(nick))
+        .filter_map(|user_ptr| Weak::upgrade(&user_ptr))
+        .map(|user| {
+            format!(
+                "{}{}={}{}@{}",
+                user.get_nick(),
+                if user.is_oper() { "*" } else { "" },
+                if user.get_away().is_some() { "-" } else { "+" },
+                user.get_username(),
+                user.get_host_string()
+            )
+        }).collect();
+    replies.push(Ok(ircReply::UserHost(tokens)));
+    Ok(replies)
+}
+
+/* WHO [<mask> ["o"]] - a channel name lists its members, anything else is
+ * matched against nick, host, server and real name, no mask at all means
+ * everyone. With "o" only opers are listed */
+pub async fn who(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let mask = params.opt_params.first().cloned().unwrap_or_else(|| String::from("*"));
+    let opers_only = params.opt_params.get(1).is_some_and(|flag| flag == "o");
+    let multi_prefix = user.has_cap("multi-prefix");
+
+    let (chan, users) = match irc.get_chan(&mask) {
+        Ok(chan) => {
+            if chan.get_modes().secret && !chan.is_joined(&user.get_nick()) {
+                replies.push(Ok(ircReply::EndofWho(mask)));
+                return Ok(replies);
+            }
+            /* members see everyone, otherwise +i members are left out */
+            let member = chan.is_joined(&user.get_nick());
+            let users = chan.gen_user_ptr_vec()
+                .into_iter()
+                .filter(|other| member || other.is_visible_to(user))
+                .collect();
+            (Some(chan), users)
+        },
+        Err(_) if rfc::valid_channel(&mask) => (None, Vec::new()),
+        Err(_) => {
+            let users = irc.all_users()
+                .into_iter()
+                .filter(|other| {
+                    [other.get_nick(), other.get_host_string(), other.get_server(), other.get_realname()]
+                        .iter()
+                        .any(|field| rfc::wildcard_match(&mask, field))
+                }).filter(|other| other.is_visible_to(user))
+                .collect();
+            (None, users)
+        }
+    };
+
+    for other in users.iter().filter(|other| !opers_only || other.is_oper()) {
+        let nick = other.get_nick();
+        /* H(ere) or G(one), * for opers, then @/+ for the channel if there is one */
+        let mut flags = if other.get_away().is_some() { String::from("G") } else { String::from("H") };
+        if other.is_oper() {
+            flags.push('*');
+        }
+        let chan_name = match &chan {
+            Some(chan) => {
+                if let Some(chan_flags) = chan.get_flags(&nick) {
+                    flags.push_str(chan_flags.prefix_for(multi_prefix));
+                }
+                chan.get_name()
+            },
+            None => String::from("*"),
+        };
+        replies.push(Ok(ircReply::WhoReply(
+            chan_name,
+            other.get_username(),
+            other.get_host_string(),
+            other.get_server(),
+            nick,
+            flags,
+            other.get_realname()
+        )));
+    }
+    replies.push(Ok(ircReply::EndofWho(mask)));
+    Ok(replies)
+}
+
+/* WHOIS [<server>] <nick>{,<nick>} - we're the only server, so if one
+ * is given it's ignored */
+pub async fn whois(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let targets = params.opt_params.last().unwrap().clone();
+    let my_nick = user.get_nick();
+    for nick in targets.split(',') {
+        let target = match irc.get_nick(nick).and_then(|user_weak| Weak::upgrade(&user_weak)) {
+            Some(target) => target,
+            None => {
+                replies.push(Err(ircError::NoSuchNick(nick.to_string())));
+                replies.push(Ok(ircReply::EndofWhois(nick.to_string())));
+                continue;
+            }
+        };
+        let target_nick = target.get_nick();
+        /* the real host behind a cloak is for opers' eyes only */
+        let host = if user.is_oper() { target.get_real_host_string() } else { target.get_host_string() };
+        replies.push(Ok(ircReply::WhoisUser(
+            target_nick.clone(),
+            target.get_username(),
+            host,
+            target.get_realname()
+        )));
+
+        /* secret channels are only shown to people who are in them too */
+        let mut chans = Vec::new();
+        for chan in target.get_channel_list().iter().filter_map(Weak::upgrade) {
+            if chan.get_modes().secret && !chan.is_joined(&my_nick) {
+                continue;
+            }
+            if let Some(chan_flags) = chan.get_flags(&target_nick) {
+                chans.push(format!("{}{}", chan_flags.prefix(), chan.get_name()));
+            }
+        }
+        if !chans.is_empty() {
+            replies.push(Ok(ircReply::WhoisChannels(target_nick.clone(), chans)));
+        }
+        if let Some(away_msg) = target.get_away() {
+            replies.push(Ok(ircReply::Away(target_nick.clone(), away_msg)));
+        }
+
+        replies.push(Ok(ircReply::WhoisServer(target_nick.clone(), target.get_server(), irc.get_server_info())));
+        replies.push(Ok(ircReply::EndofWhois(target_nick)));
+    }
+    Ok(replies)
+}
+
+/* WHOWAS <nick>{,<nick>} [<count> [<target>]] - newest first, a count
+ * that's missing or not above zero means everything we've got. Like
+ * WHOIS, only opers get to see behind a cloak */
+pub async fn whowas(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let count = params.opt_params.get(1)
+        .and_then(|count| count.parse::<usize>().ok())
+        .filter(|count| *count > 0)
+        .unwrap_or(usize::MAX);
+    for nick in params.opt_params[0].split(',').filter(|nick| !nick.is_empty()) {
+        let key = rfc::to_irc_lower(nick);
+        let entries: Vec<WhowasEntry> = irc.whowas.lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| rfc::to_irc_lower(&entry.nick) == key)
+            .take(count)
+            .cloned()
+            .collect();
+        if entries.is_empty() {
+            replies.push(Err(ircError::WasNoSuchNick(nick.to_string())));
+        }
+        for entry in entries.into_iter() {
+            let host = if user.is_oper() { entry.real_host } else { entry.host };
+            replies.push(Ok(ircReply::WhoWasUser(entry.nick.clone(), entry.username, host, entry.real_name)));
+            replies.push(Ok(ircReply::WhoisServer(entry.nick, entry.server, entry.signoff)));
+        }
+        replies.push(Ok(ircReply::EndofWhoWas(nick.to_string())));
+    }
+    Ok(replies)
+}
+
+/* INVITE <nick> <channel> */
+pub async fn invite(irc: &Core, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let nick = params.opt_params.remove(0);
+    let chanmask = params.opt_params.remove(0);
+
+    let target = match irc.get_nick(&nick) {
+        Some(user_weak) => User::upgrade(&user_weak, &nick)?,
+        None => {
+            replies.push(Err(ircError::NoSuchNick(nick)));
+            return Ok(replies);
+        }
+    };
+    let chan = irc.get_chan(&chanmask)?;
+
+    /* only members get to invite, and only ops if it's +i */
+    if !chan.is_joined(&user.get_nick()) {
+        replies.push(Err(ircError::NotOnChannel(chanmask)));
+        return Ok(replies);
+    }
+    if chan.get_modes().invite_only && !chan.is_op(user) {
+        replies.push(Err(ircError::ChanOPrivsNeeded(chanmask)));
+        return Ok(replies);
+    }
+    if chan.is_joined(&target.get_nick()) {
+        replies.push(Err(ircError::UserOnChannel(target.get_nick(), chanmask)));
+        return Ok(replies);
+    }
+
+    chan.add_invite(&target);
+    let line = format!(":{} INVITE {} :{}", user.get_prefix(), target.get_nick(), chan.get_name());
+    target.send_line(&line).await?;
+    replies.push(Ok(ircReply::Inviting(chan.get_name(), target.get_nick())));
+    Ok(replies)
+}
+
+/* KNOCK <channel> [:<reason>] - ask the ops of a +i channel for an INVITE */
+pub async fn knock(irc: &Core, user: &User, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    let chanmask = params.opt_params.remove(0);
+    let chan = irc.get_chan(&chanmask)?;
+
+    if chan.is_joined(&user.get_nick()) {
+        replies.push(Err(ircError::CannotKnock(chanmask, String::from("you're already on it"))));
+        return Ok(replies);
+    }
+    if !chan.get_modes().invite_only {
+        replies.push(Err(ircError::CannotKnock(chanmask, String::from("channel is open"))));
+        return Ok(replies);
+    }
+    if chan.is_banned(user) {
+        replies.push(Err(ircError::BannedFromChan(chanmask)));
+        return Ok(replies);
+    }
+    /* so nobody can flood a channel's ops with them, each user only gets
+     * a few and each channel only passes one on every so often */
+    if !chan.knock_due() && !user.is_oper() {
+        replies.push(Err(ircError::TooManyKnocks(chanmask, String::from("channel"))));
+        return Ok(replies);
+    }
+    if !user.try_knock() {
+        replies.push(Err(ircError::TooManyKnocks(chanmask, String::from("user"))));
+        return Ok(replies);
+    }
+    chan.note_knock();
+
+    let reason = params.opt_params.pop().filter(|reason| !reason.is_empty());
+    for op in chan.get_ops().iter() {
+        let knock = ircReply::Knock(chan.get_name(), user.get_prefix(), reason.clone());
+        if let Err(err) = op.send_rpl(knock).await {
+            debug!("failed to pass KNOCK on to {}: {}", op.get_nick(), err);
+        }
+    }
+    replies.push(Ok(ircReply::KnockDelivered(chan.get_name())));
+    Ok(replies)
+}
+
+pub async fn topic(irc: &Core, user: &User, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+
+    /* are ya in the chan? */
+    let chanmask = params.opt_params.remove(0);
+    let chan = irc.get_chan(&chanmask)?;
+    if !chan.is_joined(&user.get_nick()) {
+        replies.push(Err(ircError::NotOnChannel(chanmask)));
+        return Ok(replies);
+    }
+
+    /* just want to receive topic? */
+    if params.opt_params.is_empty() {
+        if let Some(topic) = chan.get_topic() {
+            replies.push(Ok(ircReply::Topic(chanmask.clone(), topic.text)));
+            replies.push(Ok(ircReply::TopicSetBy(chanmask, topic.usermask, topic.timestamp)));
+        } else {
+            replies.push(Ok(ircReply::NoTopic(chanmask)));
+        }
+        return Ok(replies);
+    };
+    
+    /* set topic IF permissions allow - anyone in the channel can, unless
+     * it's +t and then it's just the chanops */
+    if chan.get_modes().topic_lock && !chan.is_op(user) {
+        replies.push(Err(ircError::ChanOPrivsNeeded(chanmask)));
+        return Ok(replies);
+    }
+    let topic_text = params.opt_params.remove(0);
+    chan.set_topic(&topic_text, &user);
+    let line = format!(":{} TOPIC {} :{}", user.get_prefix(), chan.get_name(), topic_text);
+    chan.broadcast(&line).await;
+    Ok(replies)
+}
+
+pub async fn join(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+
+    /* JOIN can take a second argument. The format is:
+     * JOIN comma,sep.,chan,list comma,sep.,key,list
+     * keys apply positionally, so `JOIN #a,#b,#c k1,,k3` tries #a with k1,
+     * #b with no key and #c with k3 - missing trailing keys mean no key
+     * and any surplus keys are ignored */
+    let targets = params.opt_params.remove(0);
+    let key_list = if params.opt_params.is_empty() {
+        String::new()
+    } else {
+        params.opt_params.remove(0)
+    };
+    let mut keys = key_list.split(',');
+    let max_targets = irc.config.read().unwrap().max_targets;
+    for (n_target, target) in targets.split(',').enumerate() {
+        /* split() is lazy, so a huge list costs nothing past the cap */
+        if n_target == max_targets {
+            replies.push(Err(ircError::TooManyTargets(target.to_string())));
+            break;
+        }
+        let key = keys.next().filter(|key| !key.is_empty());
+        /* JOIN 0 leaves everything, taken in turn with any other targets,
+         * so `JOIN #a,0,#b` ends up in just #b */
+        if target == "0" {
+            part_all(irc, user).await;
+            continue;
+        }
+        replies.append(&mut irc.join_chan(target, key, user).await?);
+    }
+    Ok(replies)
+}
+
+/* PART every channel, with the usual PART going out for each. Entries for
+ * channels that have already gone get pruned on the way */
+async fn part_all(irc: &Core, user: &Arc<User>) {
+    for chan in user.get_channel_list().iter().filter_map(Weak::upgrade) {
+        if let Err(err) = irc.part_chan(&chan.get_name(), user, "").await {
+            debug!("JOIN 0: {} couldn't part {}: {}", user.get_nick(), chan.get_name(), err);
+        }
+    }
+    user.count_channels();
+}
+
+pub async fn part(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies: ClientReplies = Vec::new();
+    let targets = params.opt_params.remove(0);
+    let part_msg = if params.opt_params.is_empty() {
+        String::from("")
+    } else {
+        params.opt_params.remove(0)
+    };
+    let max_targets = irc.config.read().unwrap().max_targets;
+    for (n_target, target) in targets.split(',').enumerate() {
+        if n_target == max_targets {
+            replies.push(Err(ircError::TooManyTargets(target.to_string())));
+            break;
+        }
+        /* the PART echoed back is confirmation enough, only errors to add */
+        if let Err(err) = irc.part_chan(&target, user, &part_msg).await {
+            replies.push(Err(err));
+        }
+    }
+    Ok(replies)
+}
+
+pub async fn msg(
+    irc: &Core,
+    send_u: &Arc<User>,
+    mut params: ParsedMsg,
+    notice: bool,
+) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if params.opt_params.is_empty() {
+        if !notice {
+                replies.push(Err(ircError::NoRecipient("PRIVMSG".to_string())));
+        }
+        return Ok(replies);
+    }
+    /* this appears to be what's crashing, despite the check for params.opt_params.is_empty() beforehand
+     * ah, I'd forgotten to remove one of the notice bools from the above if statements,
+     * if params.opt_params.is_empty() && notice won't work */
+    let targets = params.opt_params.remove(0); 
+    let cmd = if notice { "NOTICE" } else { "PRIVMSG" };
+
+    // if there are more than two arguments,
+    // concatenate the remainder to one string - no args or an empty
+    // trailing one (maybe all CR/LF/NUL till the parser stripped them)
+    // means there's nothing to send
+    let message = params.opt_params.join(" ");
+    if message.is_empty() {
+        if !notice {
+            replies.push(Err(ircError::NoTextToSend));
+        }
+        return Ok(replies);
+    }
+    trace!("{} from user {} to {}, content: {}", cmd, send_u.get_nick(), targets, message);
+
+    // loop over targets - everyone only gets the message once, through
+    // whichever target reached them first, however many of the targets
+    // they're covered by (`PRIVMSG bob,#chan,bob`, with bob on #chan)
+    let mut sent_to = BTreeSet::new();
+    let max_targets = irc.config.read().unwrap().max_targets;
+    for (n_target, target) in targets.split(',').enumerate() {
+        if n_target == max_targets {
+            replies.push(Err(ircError::TooManyTargets(target.to_string())));
+            break;
+        }
+        if target.starts_with('$') {
+            replies.append(&mut msg_mask(irc, send_u, cmd, target, &message).await);
+            continue;
+        }
+        match irc.get_name(target) {
+            Some(NamedEntity::User(user_weak)) => {
+                match User::upgrade(&user_weak, target) {
+                    Ok(recv_u) => {
+                        /* anyone who's silenced the sender just doesn't get it,
+                         * and the sender isn't told */
+                        if !sent_to.insert(recv_u.get_id()) || recv_u.is_silencing(send_u) {
+                            continue;
+                        }
+                        if let Err(err) = recv_u.send_msg(send_u, cmd, target, &message).await? {
+                            replies.push(Err(err));
+                        }
+                        /* never auto-reply to a NOTICE */
+                        if let (Some(away_msg), false) = (recv_u.get_away(), notice) {
+                            replies.push(Ok(ircReply::Away(recv_u.get_nick(), away_msg)));
+                        }
+                    },
+                    Err(GenError::DeadUser(nick)) => {
+                        let _res = irc.search_user_chans_purge(&nick);
+                        if let Err(err) = irc.remove_user(&nick) {
+                            warn!("error {} removing nick {} from hash, but it doesn't exist", err, &nick)
+                        }
+                    },
+                    /* this may be a more serious error & will abort processing the join command */
+                    Err(e) => return Err(e),
+                }
+            },
+            Some(NamedEntity::Chan(chan)) => {
+                let members = match chan.get_recipients(send_u, cmd, target) {
+                    Ok(members) => members,
+                    Err(err) => {
+                        replies.push(Err(err));
+                        continue;
+                    }
+                };
+                for recv_u in members.iter()
+                    .filter(|member| sent_to.insert(member.get_id()))
+                    .filter(|member| !member.is_silencing(send_u)) {
+                    if let Err(err) = recv_u.send_msg(send_u, cmd, target, &message).await {
+                        debug!("failed to send {} to {} via {}: {}", cmd, recv_u.get_nick(), target, err);
+                    }
+                }
+            },
+            /* a channel-shaped target gets the channel flavoured error */
+            None if rfc::valid_channel(target)
+                => replies.push(Err(ircError::NoSuchChannel(target.to_string()))),
+            None => replies.push(Err(ircError::NoSuchNick(target.to_string())))
+        }
+    }
+    Ok(replies)
+}
+
+/* opers get to broadcast with $$<servermask> or $#<hostmask> targets,
+ * the mask has to end in a proper toplevel domain */
+async fn msg_mask(irc: &Core, send_u: &Arc<User>, cmd: &str, target: &str, message: &str) -> ClientReplies {
+    let mut replies = Vec::new();
+    if !send_u.is_oper() {
+        replies.push(Err(ircError::NoPrivileges));
+        return replies;
+    }
+
+    let (mask, get_field): (&str, fn(&User) -> String) = if let Some(mask) = target.strip_prefix("$$") {
+        (mask, User::get_server)
+    } else if let Some(mask) = target.strip_prefix("$#") {
+        (mask, User::get_real_host_string)
+    } else {
+        replies.push(Err(ircError::NoSuchNick(target.to_string())));
+        return replies;
+    };
+    if !rfc::has_toplevel(mask) {
+        replies.push(Err(ircError::NoTopLevel(mask.to_string())));
+        return replies;
+    }
+    if rfc::wild_toplevel(mask) {
+        replies.push(Err(ircError::WildTopLevel(mask.to_string())));
+        return replies;
+    }
+    if let Err(err) = irc.audit(send_u, cmd, target) {
+        replies.push(Err(err));
+        return replies;
+    }
+
+    for recv_u in irc.find_users_by_mask(mask, get_field).iter() {
+        if let Err(err) = recv_u.send_msg(send_u, cmd, target, message).await {
+            debug!("failed to send {} to {} via mask {}: {}", cmd, recv_u.get_nick(), target, err);
+        }
+    }
+    replies
+}
+
+pub async fn user(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    // a USER command should have four parameters
+    // <username> <hostname> <servername> <realname>,
+    // though we ignore the middle two unless a server is
+    // forwarding the message. Some clients forget the ':' on a
+    // realname with spaces in, so everything from the fourth on is
+    // the realname (and a ':' that made it this far isn't part of it)
+    let mut replies = Vec::new();
+    let args = params.opt_params;
+    let username = args[0].clone();
+    let real_name = args[3..].join(" ");
+    let real_name = real_name.strip_prefix(':').unwrap_or(&real_name).to_string();
+
+    let result = match client.get_client_type() {
         ClientType::Dead => None,
         ClientType::Unregistered => {
             // initiate handshake
@@ -752,43 +3416,53 @@ pub async fn user(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result
             return Ok(replies);
         }
         ClientType::ProtoUser(proto_user_ref) => {
-            // got nick already? if so, complete registration
-            let proto_user = proto_user_ref.lock().unwrap();
-            if let Some(nick) = &proto_user.nick {
+            // got nick already? if so, complete registration. Either way
+            // hang on to the USER details, so if registering fails (e.g. a
+            // bad PASS) another NICK can have another go
+            // NOTICE_BLOCKY - we're already holding the lock, don't take it again
+            let mut proto_user = proto_user_ref.lock().unwrap();
+            proto_user.username = Some(username.clone());
+            proto_user.real_name = Some(real_name.clone());
+            if client.is_cap_pending() {
+                // CAP END will finish off registration
+                None
+            } else if let Some(nick) = &proto_user.nick {
                 // had nick already, complete registration
-                let ret = Some(ClientType::User(
-                    irc.register(client, nick.clone(), username.clone(), real_name)?, // propagate the error if it goes wrong
-                ));
-                replies.push(Ok(ircReply::Welcome(nick.clone(), username.clone(), client.get_host_string())));
-                replies.push(Ok(ircReply::YourHost(irc.get_host(), irc.get_version())));
-                replies.push(Ok(ircReply::Created(irc.get_date())));
-                replies.push(Ok(ircReply::MyInfo(irc.get_host(), irc.get_version(), irc.get_umodes(), irc.get_chanmodes())));
-                ret
+                Some(ClientType::User(
+                    irc.register(client, nick.clone(), username, real_name)?, // propagate the error if it goes wrong
+                ))
             } else {
-                // don't see an error in the irc file,
-                // except the one if you're already reg'd
-                // NOTICE_BLOCKY
-                proto_user_ref.lock().unwrap().username = Some(username);
-                proto_user_ref.lock().unwrap().real_name = Some(real_name);
                 None
             }
-        } //ClientType::Server(_server_ref) => (None, None, false)
+        }
+        ClientType::Server(_server_ref) => {
+            // linked servers introduce users their own way
+            replies.push(Err(ircError::AlreadyRegistred));
+            return Ok(replies);
+        }
     };
 
     if let Some(new_client_type) = result {
         client.set_client_type(new_client_type);
+        finish_registration(irc, client, &mut replies).await?;
     }
     Ok(replies)
 }
 
-pub async fn nick(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+pub async fn nick(irc: &Core, client: &Arc<Client>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
-    let nick;
-    if let Some(n) = params.opt_params.iter().next() {
-        nick = n.to_string();
-    } else {
-        replies.push(Err(ircError::NeedMoreParams("NICK".to_string())));
-        return Ok(replies);
+    let mut nick = params.opt_params.remove(0);
+
+    // too long? either that's an error like any other bad nick, or it
+    // gets cut down to size - but only if the shortened one's free,
+    // otherwise it's still refused for being too long
+    if nick.chars().count() > rfc::MAX_NICK_LEN && irc.truncates_long_nicks() {
+        let short: String = nick.chars().take(rfc::MAX_NICK_LEN).collect();
+        if irc.get_name(&short).is_some() {
+            replies.push(Err(ircError::ErroneusNickname(nick)));
+            return Ok(replies);
+        }
+        nick = short;
     }
 
     // is the nick a valid nick string?
@@ -797,11 +3471,21 @@ pub async fn nick(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result
         return Ok(replies);
     }
 
-    // is this nick already taken?
-    if let Some(_hit) = irc.get_name(&nick) {
-        replies.push(Err(ircError::NicknameInUse(nick)));
+    // is this nick already taken? by someone other than ourselves, that
+    // is - changing the case of your own nick is allowed
+    let own_nick = match client.get_client_type() {
+        ClientType::User(user_ref) => Some(user_ref.get_nick()),
+        _ => None,
+    };
+    if own_nick.as_deref() == Some(nick.as_str()) {
         return Ok(replies);
     }
+    if let Some(_hit) = irc.get_name(&nick) {
+        if own_nick.map(|own| rfc::to_irc_lower(&own)) != Some(rfc::to_irc_lower(&nick)) {
+            replies.push(Err(ircError::NicknameInUse(nick)));
+            return Ok(replies);
+        }
+    }
 
     // we can return a tuple and send messages after the match
     // to avoid borrowing mutably inside the immutable borrow
@@ -818,40 +3502,45 @@ pub async fn nick(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result
         }
         ClientType::User(user_ref) => {
             // just a nick change
+            let old_prefix = format!(":{}", user_ref.get_prefix());
             user_ref.change_nick(&nick)?;
+            user_ref.announce_nick(&old_prefix).await;
             None
         }
+        ClientType::Server(_server_ref) => {
+            // linked servers introduce users their own way
+            replies.push(Err(ircError::AlreadyRegistred));
+            return Ok(replies);
+        }
         ClientType::ProtoUser(proto_user_ref) => {
             // in this case we already got USER
             let mut proto_user = proto_user_ref.lock().unwrap();
             // need to account for the case where NICK is sent
-            // twice without any user command
-            if proto_user.nick.is_some() {
+            // twice without any user command - and if we had USER but
+            // registering failed (e.g. a bad PASS), this is another go
+            // still in the middle of CAP negotiation, CAP END finishes off
+            if proto_user.username.is_none() || client.is_cap_pending() {
                 proto_user.nick = Some(nick);
                 None
             } else {
                 // full registration! wooo
                 let username = proto_user.username.as_ref();
                 let real_name = proto_user.real_name.as_ref();
-                let ret = Some(ClientType::User(
+                Some(ClientType::User(
                     irc.register(
                         client,
                         nick.clone(),
                         username.unwrap().to_string(),
                         real_name.unwrap().to_string(),
                     )?, // error propagation if registration fails
-                ));
-                replies.push(Ok(ircReply::Welcome(nick.clone(), username.unwrap().clone(), client.get_host_string())));
-                replies.push(Ok(ircReply::YourHost(irc.get_host(), irc.get_version())));
-                replies.push(Ok(ircReply::Created(irc.get_date())));
-                replies.push(Ok(ircReply::MyInfo(irc.get_host(), irc.get_version(), irc.get_umodes(), irc.get_chanmodes())));
-                ret
+                ))
             }
         }
     };
 
     if let Some(new_client_type) = result {
         client.set_client_type(new_client_type);
+        finish_registration(irc, client, &mut replies).await?;
     }
     Ok(replies)
 }
@@ -0,0 +1,39 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use chrono::Utc;
+use std::fs::OpenOptions;
+use std::io::Error as ioError;
+use std::io::Write;
+
+/* privileged (operator) actions get a line each in their own file, apart
+ * from the debug log: when, who, what and to whom. Every operator handler
+ * has to go through Core::audit() before doing anything, and if the record
+ * can't be written the action doesn't happen. The file is opened per record
+ * in append mode, so it can be rotated or moved out from under us */
+pub fn record(path: &str, actor: &str, action: &str, target: &str) -> Result<(), ioError> {
+    let line = format!(
+        "{} actor={} action={} target={}\n",
+        Utc::now().to_rfc3339(),
+        actor,
+        action,
+        target
+    );
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    /* one write per record so concurrent records don't interleave */
+    file.write_all(line.as_bytes())?;
+    file.flush()
+}
@@ -3,6 +3,7 @@ extern crate chrono;
 use crate::client::{ClientReply, ClientReplies, GenError};
 use crate::irc::error::Error as ircError;
 use crate::irc::reply::Reply as ircReply;
+use crate::irc::rfc_defs as rfc;
 use crate::irc::{Core, User};
 
 use chrono::Utc;
@@ -10,6 +11,7 @@ use std::clone::Clone;
 use std::collections::BTreeMap;
 use std::{error, fmt};
 use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
 use log::{debug,warn};
 
@@ -34,10 +36,117 @@ pub enum ChanFlags {
     None,
     Voice,
     Op,
+    OpVoice,
+}
+
+impl ChanFlags {
+    /* what goes in front of the nick in NAMES and WHO, or in front of the
+     * channel in WHOIS, so they can't ever disagree */
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            ChanFlags::None => "",
+            ChanFlags::Voice => "+",
+            ChanFlags::Op | ChanFlags::OpVoice => "@",
+        }
+    }
+
+    /* every prefix that applies, highest first, for multi-prefix clients */
+    pub fn all_prefixes(&self) -> &'static str {
+        match self {
+            ChanFlags::OpVoice => "@+",
+            _ => self.prefix(),
+        }
+    }
+
+    /* the one prefix, or all of them if the viewer's asked for multi-prefix */
+    pub fn prefix_for(&self, multi_prefix: bool) -> &'static str {
+        if multi_prefix {
+            self.all_prefixes()
+        } else {
+            self.prefix()
+        }
+    }
+
+    pub fn is_op(&self) -> bool {
+        matches!(self, ChanFlags::Op | ChanFlags::OpVoice)
+    }
+
+    /* ops first, then voices, then everyone else */
+    fn rank(&self) -> u8 {
+        match self {
+            ChanFlags::Op | ChanFlags::OpVoice => 0,
+            ChanFlags::Voice => 1,
+            ChanFlags::None => 2,
+        }
+    }
+}
+
+/* boolean channel modes, the ones that take an argument (like the key)
+ * live in their own fields on Channel */
+#[derive(Debug, Clone)]
+pub struct ChanModes {
+    pub topic_lock: bool,  /* +t */
+    pub moderated: bool,   /* +m */
+    pub invite_only: bool, /* +i */
+    pub no_external: bool, /* +n */
+    pub secret: bool,      /* +s */
+    pub permanent: bool,   /* +P, opers only */
+}
+
+impl ChanModes {
+    /* channels start out +nt, like on most networks */
+    pub fn new() -> ChanModes {
+        ChanModes {
+            topic_lock: true,
+            moderated: false,
+            invite_only: false,
+            no_external: true,
+            secret: false,
+            permanent: false,
+        }
+    }
+}
+
+impl Default for ChanModes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/* a single successfully applied mode change, e.g. +o nick or -t */
+#[derive(Debug, Clone)]
+pub struct ModeChange {
+    pub adding: bool,
+    pub letter: char,
+    pub arg: Option<String>,
+}
+
+/* squash a list of changes into a MODE line's `+ov-t nick1 nick2` part */
+pub fn format_mode_changes(changes: &[ModeChange]) -> String {
+    let mut letters = String::new();
+    let mut args = Vec::new();
+    let mut sign = None;
+    for change in changes.iter() {
+        if sign != Some(change.adding) {
+            letters.push(if change.adding { '+' } else { '-' });
+            sign = Some(change.adding);
+        }
+        letters.push(change.letter);
+        if let Some(arg) = &change.arg {
+            args.push(arg.clone());
+        }
+    }
+    if !args.is_empty() {
+        letters.push(' ');
+        letters.push_str(&args.join(" "));
+    }
+    letters
 }
 
 #[derive(Debug, Clone)]
 pub struct ChanUser {
+    id: u64,
+    nick: String,
     user_ptr: Weak<User>,
     chan_flags: ChanFlags,
 }
@@ -45,12 +154,90 @@ pub struct ChanUser {
 impl ChanUser {
     pub fn new(user: &Arc<User>, flags: ChanFlags) -> ChanUser {
         ChanUser {
+            id: user.get_id(),
+            nick: user.get_nick(),
             user_ptr: Arc::downgrade(&user),
             chan_flags: flags,
         }
     }
 }
 
+/* channel members are held by their User id, which never changes, with a
+ * nick -> id index alongside so we can still look people up by nick. A nick
+ * change only has to move the index entry, and since the index is ordered
+ * the member list comes out sorted by nick for NAMES. The index is keyed on
+ * the casefolded nick, the ChanUser keeps it as displayed. Both halves live
+ * behind the one Mutex so they can't disagree */
+#[derive(Debug, Default)]
+struct Members {
+    by_id: BTreeMap<u64, ChanUser>,
+    by_nick: BTreeMap<String, u64>,
+}
+
+impl Members {
+    fn get(&self, nick: &str) -> Option<&ChanUser> {
+        self.by_nick.get(&rfc::to_irc_lower(nick)).and_then(|id| self.by_id.get(id))
+    }
+
+    fn get_mut(&mut self, nick: &str) -> Option<&mut ChanUser> {
+        let id = self.by_nick.get(&rfc::to_irc_lower(nick))?;
+        self.by_id.get_mut(id)
+    }
+
+    fn contains_key(&self, nick: &str) -> bool {
+        self.by_nick.contains_key(&rfc::to_irc_lower(nick))
+    }
+
+    fn insert(&mut self, nick: String, mut chan_user: ChanUser) {
+        /* shouldn't happen, but don't leave an old nick pointing at them */
+        if self.by_id.contains_key(&chan_user.id) {
+            self.by_nick.retain(|_nick, id| *id != chan_user.id);
+        }
+        self.by_nick.insert(rfc::to_irc_lower(&nick), chan_user.id);
+        chan_user.nick = nick;
+        self.by_id.insert(chan_user.id, chan_user);
+    }
+
+    fn remove(&mut self, nick: &str) -> Option<ChanUser> {
+        let id = self.by_nick.remove(&rfc::to_irc_lower(nick))?;
+        self.by_id.remove(&id)
+    }
+
+    /* by id, since the old nick may already have been taken by someone
+     * else whose own rename got here first - in which case the index entry
+     * is theirs now and stays put */
+    fn rename_id(&mut self, id: u64, new_nick: &str) -> bool {
+        let chan_user = match self.by_id.get_mut(&id) {
+            Some(chan_user) => chan_user,
+            None => return false,
+        };
+        let old_key = rfc::to_irc_lower(&chan_user.nick);
+        if self.by_nick.get(&old_key) == Some(&id) {
+            self.by_nick.remove(&old_key);
+        }
+        self.by_nick.insert(rfc::to_irc_lower(new_nick), id);
+        chan_user.nick = new_nick.to_string();
+        true
+    }
+
+    /* (nick, member) pairs, in nick order */
+    fn list(&self) -> Vec<(String, ChanUser)> {
+        self.by_nick
+            .values()
+            .filter_map(|id| {
+                self.by_id.get(id).map(|chan_user| (chan_user.nick.clone(), chan_user.clone()))
+            }).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+}
+
 #[derive(Debug)]
 pub struct ChanTopic {
     pub text: String,
@@ -68,12 +255,30 @@ impl Clone for ChanTopic {
     }
 }
 
+/* an entry on one of the +b/+e/+I lists, we hang on to who set it and
+ * when so 367/348/346 can tell the ops about it later */
+#[derive(Debug, Clone)]
+pub struct MaskEntry {
+    pub mask: String,
+    pub set_by: String,
+    pub timestamp: i64,
+}
+
 #[derive(Debug)]
 pub struct Channel {
     name: String,
     topic: Mutex<Option<ChanTopic>>,
-    users: Mutex<BTreeMap<String, ChanUser>>,
-    banmasks: Mutex<Vec<String>>,
+    users: Mutex<Members>,
+    bans: Mutex<Vec<MaskEntry>>,
+    excepts: Mutex<Vec<MaskEntry>>,
+    invexes: Mutex<Vec<MaskEntry>>,
+    key: Mutex<Option<String>>,
+    limit: Mutex<Option<usize>>,
+    modes: Mutex<ChanModes>,
+    invites: Mutex<BTreeMap<u64, Instant>>,
+    /* when the last KNOCK was passed on to the ops */
+    last_knock: Mutex<Option<Instant>>,
+    created: i64,
     irc: Arc<Core>,
 }
 
@@ -81,25 +286,34 @@ impl Channel {
     pub fn new(irc: &Arc<Core>, chanmask: &str) -> Channel {
         let name = chanmask.to_string();
         let topic = Mutex::new(None);
-        let users = Mutex::new(BTreeMap::new());
-        let banmasks = Mutex::new(Vec::new());
+        let users = Mutex::new(Members::default());
+        let bans = Mutex::new(Vec::new());
+        let excepts = Mutex::new(Vec::new());
+        let invexes = Mutex::new(Vec::new());
+        let key = Mutex::new(None);
+        let limit = Mutex::new(None);
+        let modes = Mutex::new(ChanModes::new());
+        let invites = Mutex::new(BTreeMap::new());
         Channel {
             name,
             topic,
             users,
-            banmasks,
+            bans,
+            excepts,
+            invexes,
+            key,
+            limit,
+            modes,
+            invites,
+            last_knock: Mutex::new(None),
+            created: Utc::now().timestamp(),
             irc: Arc::clone(&irc)
         }
     }
 
     /* spit out a vector of (key, value) tuples */
     fn _get_user_list(&self) -> Vec<(String, ChanUser)> {
-        self.users
-            .lock()
-            .unwrap()
-            .clone()
-            .into_iter()
-            .collect::<Vec<_>>()
+        self.users.lock().unwrap().list()
     }
 
     /* generate a vector of Arc pointers to users on this channel,
@@ -121,6 +335,36 @@ impl Channel {
         ret
     }
 
+    /* drop any member whose user has vanished, or whose user doesn't
+     * list this channel in return, handing back the nicks removed so
+     * the caller can log the repairs */
+    pub fn repair_members(&self) -> Vec<String> {
+        let mut removed = Vec::new();
+        for (nick, val) in self._get_user_list().iter() {
+            let mutual = match Weak::upgrade(&val.user_ptr) {
+                Some(user) => {
+                    user.get_nick() == *nick
+                        && user.channel_list.lock().unwrap().contains_key(&self.name)
+                },
+                None => false,
+            };
+            if mutual {
+                continue;
+            }
+
+            /* only remove the entry we actually inspected, the user may
+             * have parted and rejoined in the meantime */
+            let mut users = self.users.lock().unwrap();
+            if let Some(cur) = users.get(nick) {
+                if Weak::ptr_eq(&cur.user_ptr, &val.user_ptr) {
+                    users.remove(nick);
+                    removed.push(nick.clone());
+                }
+            }
+        }
+        removed
+    }
+
     /* this one just gives the actual nicks themselves,
      * without chan privilege signifiers */
     fn _get_nick_list_wo_badges(&self) -> Vec<String> {
@@ -134,15 +378,33 @@ impl Channel {
     /* this time give the nicks processed with added '+'
      * tag for voice or '@' for chanop */
     pub fn get_nick_list(&self) -> Vec<String> {
-        self._get_user_list()
+        Channel::badge_nicks(&self._get_user_list(), false)
+    }
+
+    /* NAMES as seen by someone outside the channel leaves out the +i
+     * members they've got no other way of seeing */
+    pub fn get_nick_list_for(&self, viewer: &User) -> Vec<String> {
+        let multi_prefix = viewer.has_cap("multi-prefix");
+        if self.is_joined(&viewer.get_nick()) {
+            return Channel::badge_nicks(&self._get_user_list(), multi_prefix);
+        }
+        let members: Vec<(String, ChanUser)> = self._get_user_list()
+            .into_iter()
+            .filter(|(_nick, chan_user)| {
+                Weak::upgrade(&chan_user.user_ptr).is_some_and(|member| member.is_visible_to(viewer))
+            }).collect();
+        Channel::badge_nicks(&members, multi_prefix)
+    }
+
+    /* @nick, +nick or just nick, for NAMES. Members come in nick order,
+     * the sort being stable keeps that within the ops, voices and the rest */
+    fn badge_nicks(members: &[(String, ChanUser)], multi_prefix: bool) -> Vec<String> {
+        let mut members: Vec<&(String, ChanUser)> = members.iter().collect();
+        members.sort_by_key(|(_key, val)| val.chan_flags.rank());
+        members
             .iter()
-            .map(|(key, val)| {
-                match val.chan_flags {
-                    ChanFlags::None => key.to_string(),
-                    ChanFlags::Voice => format!("+{}", key).to_string(),
-                    ChanFlags::Op => format!("@{}", key).to_string(),
-                }
-            }).collect::<Vec<_>>()
+            .map(|(key, val)| format!("{}{}", val.chan_flags.prefix_for(multi_prefix), key))
+            .collect()
     }
 
     pub fn get_n_users(&self) -> usize {
@@ -156,7 +418,17 @@ impl Channel {
         }
     }
 
+    /* put back a topic exactly as it was, setter and time included */
+    pub fn restore_topic(&self, topic: ChanTopic) {
+        *self.topic.lock().unwrap() = Some(topic);
+    }
+
+    /* an empty topic isn't a topic, `TOPIC #chan :` unsets it */
     pub fn set_topic(&self, topic_text: &str, user: &User) {
+        if topic_text.is_empty() {
+            self.clear_topic();
+            return;
+        }
         let topic = ChanTopic {
             text: topic_text.to_string(),
             usermask: user.get_prefix(),
@@ -165,10 +437,168 @@ impl Channel {
         *self.topic.lock().unwrap() = Some(topic);
     }
 
+    pub fn clear_topic(&self) {
+        *self.topic.lock().unwrap() = None;
+    }
+
     pub fn get_name(&self) -> String {
         self.name.clone()
     }
 
+    /* unix time the channel came into being, for RPL_CREATIONTIME */
+    pub fn get_created(&self) -> i64 {
+        self.created
+    }
+
+    pub fn get_key(&self) -> Option<String> {
+        self.key.lock().unwrap().clone()
+    }
+
+    pub fn set_key(&self, key: Option<String>) {
+        *self.key.lock().unwrap() = key;
+    }
+
+    pub fn get_limit(&self) -> Option<usize> {
+        *self.limit.lock().unwrap()
+    }
+
+    pub fn set_limit(&self, limit: Option<usize>) {
+        *self.limit.lock().unwrap() = limit;
+    }
+
+    /* at (or somehow past, if +l was set low) the user limit */
+    pub fn is_full(&self) -> bool {
+        match self.get_limit() {
+            Some(limit) => self.get_n_users() >= limit,
+            None => false,
+        }
+    }
+
+    /* a channel with no key set lets anyone in, otherwise the key given
+     * has to match exactly */
+    pub fn check_key(&self, key: Option<&str>) -> bool {
+        match &*self.key.lock().unwrap() {
+            Some(chan_key) => key == Some(chan_key.as_str()),
+            None => true,
+        }
+    }
+
+    /* invites are kept by user id rather than nick, so they die with the
+     * user and can't be picked up by whoever takes the nick next. Each one
+     * is good until invite_expiry_secs after it was sent, inviting again
+     * starts the clock over */
+    pub fn add_invite(&self, user: &User) {
+        let expires = Instant::now() + Duration::from_secs(self.irc.config.read().unwrap().invite_expiry_secs);
+        self.invites.lock().unwrap().insert(user.id, expires);
+    }
+
+    pub fn is_invited(&self, user: &User) -> bool {
+        self.invites
+            .lock()
+            .unwrap()
+            .get(&user.id)
+            .is_some_and(|expires| *expires > Instant::now())
+    }
+
+    /* use up an invite, if there was a live one */
+    pub fn take_invite(&self, user: &User) -> bool {
+        self.invites
+            .lock()
+            .unwrap()
+            .remove(&user.id)
+            .is_some_and(|expires| expires > Instant::now())
+    }
+
+    pub fn prune_invites(&self) {
+        let now = Instant::now();
+        self.invites.lock().unwrap().retain(|_id, expires| *expires > now);
+    }
+
+    pub fn clear_invites(&self) {
+        self.invites.lock().unwrap().clear();
+    }
+
+    /* b, e and I all work the same way, they're just different lists */
+    fn mask_list(&self, letter: char) -> &Mutex<Vec<MaskEntry>> {
+        match letter {
+            'e' => &self.excepts,
+            'I' => &self.invexes,
+            _ => &self.bans,
+        }
+    }
+
+    pub fn get_mask_list(&self, letter: char) -> Vec<MaskEntry> {
+        self.mask_list(letter).lock().unwrap().clone()
+    }
+
+    /* false if the mask was already on there */
+    pub fn add_mask(&self, letter: char, mask: &str, setter: &User) -> bool {
+        let mut list = self.mask_list(letter).lock().unwrap();
+        let lower = rfc::to_irc_lower(mask);
+        if list.iter().any(|entry| rfc::to_irc_lower(&entry.mask) == lower) {
+            return false;
+        }
+        list.push(MaskEntry {
+            mask: mask.to_string(),
+            set_by: setter.get_prefix(),
+            timestamp: Utc::now().timestamp(),
+        });
+        true
+    }
+
+    pub fn restore_mask(&self, letter: char, entry: MaskEntry) {
+        self.mask_list(letter).lock().unwrap().push(entry);
+    }
+
+    /* false if there was nothing to take off */
+    pub fn rm_mask(&self, letter: char, mask: &str) -> bool {
+        let mut list = self.mask_list(letter).lock().unwrap();
+        let lower = rfc::to_irc_lower(mask);
+        let len = list.len();
+        list.retain(|entry| rfc::to_irc_lower(&entry.mask) != lower);
+        list.len() != len
+    }
+
+    /* does the user's nick!user@host match anything on one of the lists */
+    fn matches_mask_list(&self, letter: char, user: &User) -> bool {
+        let prefix = user.get_prefix();
+        self.mask_list(letter)
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|entry| rfc::wildcard_match(&entry.mask, &prefix))
+    }
+
+    /* on the ban list, and not let off by a +e */
+    pub fn is_banned(&self, user: &User) -> bool {
+        self.matches_mask_list('b', user) && !self.matches_mask_list('e', user)
+    }
+
+    /* a +I match gets in past +i without needing an INVITE */
+    pub fn is_invite_exempt(&self, user: &User) -> bool {
+        self.matches_mask_list('I', user)
+    }
+
+    /* the 367/368, 348/349 or 346/347 run for one of the lists */
+    pub fn gen_mask_list_replies(&self, letter: char) -> ClientReplies {
+        let chan = self.get_name();
+        let mut replies = Vec::new();
+        for entry in self.get_mask_list(letter).into_iter() {
+            let (mask, set_by, stamp) = (entry.mask, entry.set_by, entry.timestamp);
+            replies.push(Ok(match letter {
+                'e' => ircReply::ExceptList(chan.clone(), mask, set_by, stamp),
+                'I' => ircReply::InviteList(chan.clone(), mask, set_by, stamp),
+                _ => ircReply::BanList(chan.clone(), mask, set_by, stamp),
+            }));
+        }
+        replies.push(Ok(match letter {
+            'e' => ircReply::EndofExceptList(chan),
+            'I' => ircReply::EndofInviteList(chan),
+            _ => ircReply::EndofBanList(chan),
+        }));
+        replies
+    }
+
     pub fn get_names_list(&self) -> Vec<String> {
         self.get_nick_list()
     }
@@ -177,9 +607,201 @@ impl Channel {
         self.users.lock().unwrap().is_empty()
     }
 
+    /* empty and not +P, so nothing's lost if it goes */
+    pub fn is_disposable(&self) -> bool {
+        self.is_empty() && !self.get_modes().permanent
+    }
+
     pub fn is_op(&self, user: &User) -> bool {
-        let op = format!("@{}", &user.nick.lock().unwrap());
-        self.users.lock().unwrap().contains_key(&op)
+        let nick = user.get_nick();
+        match self.users.lock().unwrap().get(&nick) {
+            Some(chan_user) => chan_user.chan_flags.is_op(),
+            None => false,
+        }
+    }
+
+    pub fn get_modes(&self) -> ChanModes {
+        self.modes.lock().unwrap().clone()
+    }
+
+    /* the RPL_CHANNELMODEIS form, e.g. `+klnt sekrit 10`, where the key
+     * and limit themselves are only shown to members */
+    pub fn gen_mode_string(&self, show_args: bool) -> String {
+        let modes = self.get_modes();
+        let mut letters = String::from("+");
+        let mut args = Vec::new();
+        for (set, letter) in [
+            (modes.invite_only, 'i'),
+            (modes.moderated, 'm'),
+            (modes.no_external, 'n'),
+            (modes.secret, 's'),
+            (modes.topic_lock, 't'),
+            (modes.permanent, 'P'),
+        ].iter() {
+            if *set {
+                letters.push(*letter);
+            }
+        }
+        if let Some(key) = self.get_key() {
+            letters.push('k');
+            if show_args {
+                args.push(key);
+            }
+        }
+        if let Some(limit) = self.get_limit() {
+            letters.push('l');
+            if show_args {
+                args.push(limit.to_string());
+            }
+        }
+        if !args.is_empty() {
+            letters.push(' ');
+            letters.push_str(&args.join(" "));
+        }
+        letters
+    }
+
+    /* flip one of the boolean modes, false if it was already that way
+     * (or isn't one of them) */
+    pub fn set_mode_flag(&self, letter: char, on: bool) -> bool {
+        let mut modes = self.modes.lock().unwrap();
+        let flag = match letter {
+            't' => &mut modes.topic_lock,
+            'm' => &mut modes.moderated,
+            'i' => &mut modes.invite_only,
+            's' => &mut modes.secret,
+            'n' => &mut modes.no_external,
+            'P' => &mut modes.permanent,
+            _ => return false,
+        };
+        if *flag == on {
+            return false;
+        }
+        *flag = on;
+        true
+    }
+
+    /* grant or take away chanop status */
+    pub fn set_op(&self, nick: &str, op: bool) -> Result<(), ircError> {
+        let mut users = self.users.lock().unwrap();
+        let chan_user = users
+            .get_mut(nick)
+            .ok_or_else(|| ircError::UserNotInChannel(nick.to_string(), self.name.clone()))?;
+        chan_user.chan_flags = match (&chan_user.chan_flags, op) {
+            (ChanFlags::Voice, true) | (ChanFlags::OpVoice, true) => ChanFlags::OpVoice,
+            (_, true) => ChanFlags::Op,
+            (ChanFlags::Op, false) => ChanFlags::None,
+            (ChanFlags::OpVoice, false) => ChanFlags::Voice,
+            (flags, false) => flags.clone(),
+        };
+        Ok(())
+    }
+
+    /* grant or take away voice, an op keeps their voice underneath for
+     * multi-prefix clients to see */
+    pub fn set_voice(&self, nick: &str, voice: bool) -> Result<(), ircError> {
+        let mut users = self.users.lock().unwrap();
+        let chan_user = users
+            .get_mut(nick)
+            .ok_or_else(|| ircError::UserNotInChannel(nick.to_string(), self.name.clone()))?;
+        chan_user.chan_flags = match (&chan_user.chan_flags, voice) {
+            (ChanFlags::None, true) => ChanFlags::Voice,
+            (ChanFlags::Op, true) => ChanFlags::OpVoice,
+            (ChanFlags::Voice, false) => ChanFlags::None,
+            (ChanFlags::OpVoice, false) => ChanFlags::Op,
+            (flags, _) => flags.clone(),
+        };
+        Ok(())
+    }
+
+    /* apply a single mode letter, taking its argument from args if it needs
+     * one. Gives back the change to be echoed to the channel, or None if it
+     * didn't actually change anything */
+    pub fn apply_mode(
+        &self,
+        setter: &User,
+        adding: bool,
+        letter: char,
+        args: &mut dyn Iterator<Item = String>
+    ) -> Result<Option<ModeChange>, ircError> {
+        let mut arg = None;
+        match letter {
+            'b' | 'e' | 'I' => {
+                let mask = args.next().ok_or_else(|| ircError::NeedMoreParams("MODE".to_string()))?;
+                let changed = if adding {
+                    self.add_mask(letter, &mask, setter)
+                } else {
+                    self.rm_mask(letter, &mask)
+                };
+                if !changed {
+                    return Ok(None);
+                }
+                arg = Some(mask);
+            },
+            'o' | 'v' => {
+                let nick = args.next().ok_or_else(|| ircError::NeedMoreParams("MODE".to_string()))?;
+                if letter == 'o' {
+                    self.set_op(&nick, adding)?;
+                } else {
+                    self.set_voice(&nick, adding)?;
+                }
+                arg = Some(nick);
+            },
+            'k' if adding => {
+                let key = args.next().ok_or_else(|| ircError::NeedMoreParams("MODE".to_string()))?;
+                if self.get_key().is_some() {
+                    return Err(ircError::KeySet(self.name.clone()));
+                }
+                self.set_key(Some(key.clone()));
+                arg = Some(key);
+            },
+            'k' => {
+                /* -k traditionally carries the key, but we don't need it */
+                let _key = args.next();
+                if self.get_key().is_none() {
+                    return Ok(None);
+                }
+                self.set_key(None);
+                arg = Some(String::from("*"));
+            },
+            'l' if adding => {
+                let limit = args.next().ok_or_else(|| ircError::NeedMoreParams("MODE".to_string()))?;
+                /* junk or a zero limit gets quietly ignored, same as most servers */
+                let limit = match limit.parse::<usize>() {
+                    Ok(limit) if limit > 0 => limit,
+                    _ => return Ok(None),
+                };
+                if self.get_limit() == Some(limit) {
+                    return Ok(None);
+                }
+                self.set_limit(Some(limit));
+                arg = Some(limit.to_string());
+            },
+            'l' => {
+                if self.get_limit().is_none() {
+                    return Ok(None);
+                }
+                self.set_limit(None);
+            },
+            /* +P keeps an empty channel (and its topic, modes and lists)
+             * around, which is for opers to decide */
+            'P' if !setter.is_oper() => return Err(ircError::NoPrivileges),
+            't' | 'm' | 'i' | 'n' | 's' | 'P' => {
+                if !self.set_mode_flag(letter, adding) {
+                    return Ok(None);
+                }
+            },
+            _ => return Err(ircError::UnknownMode(letter)),
+        }
+        Ok(Some(ModeChange { adding, letter, arg }))
+    }
+
+    pub fn get_flags(&self, nick: &str) -> Option<ChanFlags> {
+        self.users
+            .lock()
+            .unwrap()
+            .get(nick)
+            .map(|chan_user| chan_user.chan_flags.clone())
     }
 
     pub fn is_joined(&self, nick: &str) -> bool {
@@ -192,32 +814,90 @@ impl Channel {
     pub async fn add_user(self: &Arc<Self>, new_user: &Arc<User>, flags: ChanFlags) -> Result<ClientReplies, GenError> {
         let chan = self.get_name();
         let mut replies = Vec::new();
-        {
-            let mut chan_mutex_lock = self.users.lock().unwrap();
-            let mut user_mutex_lock = new_user.channel_list.lock().unwrap();
-            let nick = new_user.get_nick();
-            let chan = self.get_name();
-            let chan_ptr = Arc::downgrade(&self);
+        let members = match self.link_user(new_user, flags) {
+            Some(members) => members,
+            None => return Ok(replies), /* already on chan */
+        };
 
-            if !chan_mutex_lock.contains_key(&nick) {
-                chan_mutex_lock.insert(nick, ChanUser::new(new_user, flags));
-                user_mutex_lock.insert(chan, chan_ptr);
+        for observer in self.irc.get_observers().iter() {
+            observer.on_join(new_user, self);
+        }
 
-                
-            } else {
-                return Ok(replies) /* already on chan */
+        /* the JOIN (which the new user gets too, it's their confirmation)
+         * and the NAMES both come from the snapshot taken as they were
+         * added, so they agree on who was there even if someone else
+         * joins or leaves in the meantime */
+        let line = format!(":{} JOIN {}", new_user.get_prefix(), chan);
+        for (_nick, chan_user) in members.iter() {
+            if let Some(user) = Weak::upgrade(&chan_user.user_ptr) {
+                if let Err(err) = user.send_line(&line).await {
+                    debug!("failed to send JOIN to {}: {}", user.get_nick(), err);
+                }
             }
-        } /* de-scope mutex locks */
+        }
+        replies.append(&mut self.join_burst(&members, new_user.has_cap("multi-prefix")));
+        Ok(replies)
+    }
+
+    /* just the bookkeeping half of add_user(), both sides linked under
+     * both locks, no notifications. Gives back the member list as it was
+     * straight after, or None if they were already on */
+    pub fn link_user(self: &Arc<Self>, new_user: &Arc<User>, flags: ChanFlags) -> Option<Vec<(String, ChanUser)>> {
+        let mut chan_mutex_lock = self.users.lock().unwrap();
+        let mut user_mutex_lock = new_user.channel_list.lock().unwrap();
+        let nick = new_user.get_nick();
+        if chan_mutex_lock.contains_key(&nick) {
+            return None;
+        }
+        chan_mutex_lock.insert(nick, ChanUser::new(new_user, flags));
+        user_mutex_lock.insert(self.get_name(), Arc::downgrade(&self));
+        Some(chan_mutex_lock.list())
+    }
+
+    /* whether it's been long enough since the last KNOCK here */
+    pub fn knock_due(&self) -> bool {
+        let delay = Duration::from_secs(self.irc.config.read().unwrap().knock_channel_delay_secs);
+        self.last_knock.lock().unwrap().is_none_or(|when| when.elapsed() >= delay)
+    }
+
+    pub fn note_knock(&self) {
+        *self.last_knock.lock().unwrap() = Some(Instant::now());
+    }
+
+    /* everyone with +o, e.g. to pass on a KNOCK */
+    pub fn get_ops(&self) -> Vec<Arc<User>> {
+        self._get_user_list()
+            .into_iter()
+            .filter(|(_nick, chan_user)| chan_user.chan_flags.is_op())
+            .filter_map(|(_nick, chan_user)| Weak::upgrade(&chan_user.user_ptr))
+            .collect()
+    }
+
+    /* (user id, flags) of every member */
+    pub fn get_member_ids(&self) -> Vec<(u64, ChanFlags)> {
+        self._get_user_list()
+            .into_iter()
+            .map(|(_nick, chan_user)| (chan_user.id, chan_user.chan_flags))
+            .collect()
+    }
+
+    /* the topic and names replies that follow a JOIN */
+    pub fn gen_join_burst(&self, multi_prefix: bool) -> ClientReplies {
+        self.join_burst(&self._get_user_list(), multi_prefix)
+    }
 
-        /* also self.notify_join() */
-        replies.push(self.notify_join(new_user, &chan).await?);
+    fn join_burst(&self, members: &[(String, ChanUser)], multi_prefix: bool) -> ClientReplies {
+        let chan = self.get_name();
+        let mut replies = Vec::new();
         if let Some(topic) = self.get_topic() {
             replies.push(Ok(ircReply::Topic(chan.to_string(), topic.text)));
             replies.push(Ok(ircReply::TopicSetBy(chan.to_string(), topic.usermask, topic.timestamp)))
+        } else {
+            replies.push(Ok(ircReply::NoTopic(chan.to_string())));
         }
-        replies.push(Ok(ircReply::NameReply(chan.to_string(), self.get_nick_list())));
-        replies.push(Ok(ircReply::EndofNames(chan.to_string())));
-        Ok(replies)
+        replies.push(Ok(ircReply::NameReply(chan.to_string(), Channel::badge_nicks(members, multi_prefix))));
+        replies.push(Ok(ircReply::EndofNames(chan)));
+        replies
     }
 
     /* still need this for User::drop() */
@@ -229,7 +909,9 @@ impl Channel {
      * that in one place, both for User and Chan side - plus, mutex lock
      * everything for the entire fn call */
     pub async fn rm_user(&self, user: &User, msg: &str) -> Result<(), ChanError> {
-        /* Notify part msg */
+        /* Notify part msg - this goes out while they're still a member, so
+         * the parting user gets their own PART echoed back too, even when
+         * they're the last one here */
         if !self.is_empty() {
             let _res = self.notify_part(user, &self.get_name(), msg).await;
         }
@@ -242,8 +924,8 @@ impl Channel {
             let chan = self.get_name();
             if let Some(_val) = chan_mutex_lock.remove(&key) {
                 user_mutex_lock.remove(&chan);
-                if chan_mutex_lock.is_empty() {
-                    if let Err(err) = self.irc.remove_name(&chan) {
+                if chan_mutex_lock.is_empty() && !self.get_modes().permanent {
+                    if let Err(err) = self.irc.remove_empty_chan(self) {
                         warn!("error {} removing chan {} from hash - it doesn't exist", err, &chan);
                     }
                 }
@@ -257,11 +939,8 @@ impl Channel {
     }
 
     /* similar rationale to the above about linking and unlinking users to chans */
-    pub fn update_nick(&self, old_nick: &str, new_nick: &str) -> Result<(), ircError> {
-        let mut mutex_lock = self.users.lock().unwrap();
-        let key = old_nick.to_string();
-        if let Some(val) = mutex_lock.remove(&key) {
-            mutex_lock.insert(new_nick.to_string(), val);
+    pub fn update_nick(&self, id: u64, new_nick: &str) -> Result<(), ircError> {
+        if self.users.lock().unwrap().rename_id(id, new_nick) {
             Ok(())
         } else {
             Err(ircError::NotOnChannel(self.name.clone()))
@@ -276,7 +955,9 @@ impl Channel {
         msg: &str
     ) -> Result<ClientReply, GenError> {
         // checks for banmasks should be done-
-        // also whether the sending user is in the channel or not
+        if let Err(err) = self.check_can_send(source, command_str, target) {
+            return Ok(Err(err));
+        }
         let prefix = source.get_prefix();
         let line = if msg.is_empty() {
             format!(":{} {} {}", prefix, command_str, target)
@@ -284,31 +965,56 @@ impl Channel {
             format!(":{} {} {} :{}", prefix, command_str, target, msg)
         };
 
-        if self.is_joined(&source.get_nick()) {
-            // if we clone the list, the true list could change while
-            // we're forwarding messages, but this keeps us thread safe
-            let users = self.gen_user_ptr_vec();
-            for user in users.iter() {
-                // if you're parting or joining, your own echoed message confirms success
-                if user.id != source.id || command_str == "JOIN" || command_str == "PART" {
-                    if let Err(err) = user.send_line(&line).await {
-                        debug!("another tasks's client died: {}, note dead key {}", err, &user.get_nick());
-                        //user.clear_chans_and_exit();
-                    }
+        // if we clone the list, the true list could change while
+        // we're forwarding messages, but this keeps us thread safe
+        let users = self.gen_user_ptr_vec();
+        for user in users.iter() {
+            // if you're parting or joining, your own echoed message confirms success
+            if user.id != source.id || command_str == "JOIN" || command_str == "PART" {
+                if let Err(err) = user.send_line(&line).await {
+                    debug!("another tasks's client died: {}, note dead key {}", err, &user.get_nick());
+                    //user.clear_chans_and_exit();
                 }
             }
-            Ok(Ok(ircReply::None))
-        } else {
-            Ok(Err(ircError::CannotSendToChan(target.to_string())))
         }
+        Ok(Ok(ircReply::None))
     }
 
-    pub async fn send_msg(&self, source: &User, cmd: &str, target: &str, msg: &str) -> Result<ClientReply, GenError> {
-        self._send_msg(source, cmd, target, msg).await
+    /* who a PRIVMSG or NOTICE to the channel ends up with - all the members
+     * bar the sender, if they're allowed to send to it at all */
+    pub fn get_recipients(&self, source: &User, command_str: &str, target: &str) -> Result<Vec<Arc<User>>, ircError> {
+        self.check_can_send(source, command_str, target)?;
+        Ok(self.gen_user_ptr_vec()
+            .into_iter()
+            .filter(|user| user.get_id() != source.get_id())
+            .collect())
+    }
+
+    /* JOIN, PART and QUIT only ever come from members. PRIVMSG and NOTICE
+     * can come from outside unless the channel is +n, and on a +m channel
+     * only ops and voiced users get to talk */
+    fn check_can_send(&self, source: &User, command_str: &str, target: &str) -> Result<(), ircError> {
+        let flags = self.get_flags(&source.get_nick());
+        let cannot_send = || ircError::CannotSendToChan(target.to_string());
+        if command_str != "PRIVMSG" && command_str != "NOTICE" {
+            return flags.map(|_flags| ()).ok_or_else(cannot_send);
+        }
+        let modes = self.get_modes();
+        match flags {
+            None if modes.no_external => Err(cannot_send()),
+            Some(ChanFlags::Op) | Some(ChanFlags::Voice) | Some(ChanFlags::OpVoice) => Ok(()),
+            _ if modes.moderated => Err(cannot_send()),
+            _ => Ok(()),
+        }
     }
 
-    pub async fn notify_join(&self, source: &User, chan: &str) -> Result<ClientReply, GenError> {
-        self._send_msg(source, "JOIN", chan, "").await
+    /* send a line to every member, e.g. a MODE change */
+    pub async fn broadcast(&self, line: &str) {
+        for user in self.gen_user_ptr_vec().iter() {
+            if let Err(err) = user.send_line(line).await {
+                debug!("failed to send to {} on {}: {}", user.get_nick(), self.name, err);
+            }
+        }
     }
 
     pub async fn notify_part(&self, source: &User, chan: &str, msg: &str) -> Result<ClientReply, GenError> {
@@ -319,3 +1025,123 @@ impl Channel {
         self._send_msg(source, "QUIT", chan, msg).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(id: u64, nick: &str) -> ChanUser {
+        ChanUser { id, nick: nick.to_string(), user_ptr: Weak::new(), chan_flags: ChanFlags::None }
+    }
+
+    fn members(nicks: &[&str]) -> Members {
+        let mut members = Members::default();
+        for (id, nick) in nicks.iter().enumerate() {
+            members.insert(nick.to_string(), member(id as u64, nick));
+        }
+        members
+    }
+
+    fn nicks(members: &Members) -> Vec<String> {
+        members.list().into_iter().map(|(nick, _chan_user)| nick).collect()
+    }
+
+    #[test]
+    fn members_are_found_by_nick_whatever_the_case() {
+        let members = members(&["alice", "Bob[x]"]);
+        assert!(members.contains_key("ALICE"));
+        assert_eq!(members.get("bob{X}").map(|chan_user| chan_user.id), Some(1));
+        assert!(!members.contains_key("carol"));
+        assert_eq!(nicks(&members), vec!["alice", "Bob[x]"]);
+    }
+
+    /* the member stays where it is under its id, flags and all, only the
+     * nick index moves */
+    #[test]
+    fn rename_only_moves_the_index() {
+        let mut members = members(&["alice", "bob", "carol"]);
+        members.get_mut("bob").unwrap().chan_flags = ChanFlags::Op;
+        let ids: Vec<u64> = members.by_id.keys().cloned().collect();
+
+        assert!(members.rename_id(1, "Zed"));
+        assert_eq!(members.by_id.keys().cloned().collect::<Vec<u64>>(), ids);
+        assert!(!members.contains_key("bob"));
+        let zed = members.get("zed").unwrap();
+        assert_eq!((zed.id, zed.nick.as_str()), (1, "Zed"));
+        assert!(zed.chan_flags.is_op());
+        assert_eq!(nicks(&members), vec!["alice", "carol", "Zed"]);
+        assert_eq!(members.len(), 3);
+        assert!(!members.rename_id(7, "nobody"));
+    }
+
+    /* bob took alice's old nick before alice's own rename got here, the
+     * index entry for it is bob's and has to stay his */
+    #[test]
+    fn rename_leaves_a_reused_nick_alone() {
+        let mut members = members(&["alice", "bob"]);
+        assert!(members.rename_id(1, "alice"));
+        assert!(members.rename_id(0, "carol"));
+        assert_eq!(members.get("alice").map(|chan_user| chan_user.id), Some(1));
+        assert_eq!(members.get("carol").map(|chan_user| chan_user.id), Some(0));
+        assert!(!members.contains_key("bob"));
+        assert_eq!(members.by_nick.len(), members.len());
+    }
+
+    #[test]
+    fn remove_takes_out_both_halves() {
+        let mut members = members(&["alice", "bob"]);
+        assert_eq!(members.remove("BOB").map(|chan_user| chan_user.id), Some(1));
+        assert!(members.remove("bob").is_none());
+        assert_eq!((members.by_id.len(), members.by_nick.len()), (1, 1));
+        members.remove("alice");
+        assert!(members.is_empty());
+    }
+
+    /* pruning only drops the invites that have run out */
+    #[test]
+    fn prune_keeps_live_invites() {
+        let chan = Channel::new(&crate::irc::testing::core(), "#a");
+        let now = Instant::now();
+        chan.invites.lock().unwrap().insert(1, now);
+        chan.invites.lock().unwrap().insert(2, now + Duration::from_secs(60));
+        chan.prune_invites();
+        assert_eq!(chan.invites.lock().unwrap().keys().cloned().collect::<Vec<u64>>(), vec![2]);
+    }
+
+    /* benchmarks, there's no #[bench] on stable so these are timed by
+     * hand. Run them with `cargo test --release -- --ignored --nocapture bench_` */
+    const BENCH_MEMBERS: usize = 10_000;
+
+    fn bench_members() -> Members {
+        let names: Vec<String> = (0..BENCH_MEMBERS).map(|n| format!("user{}", n)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        members(&names)
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_broadcast_large_channel() {
+        let members = bench_members();
+        let rounds = 100;
+        let start = Instant::now();
+        for _i in 0..rounds {
+            /* what gen_user_ptr_vec() walks for every broadcast */
+            assert_eq!(members.list().len(), BENCH_MEMBERS);
+        }
+        let per_member = start.elapsed() / (rounds * BENCH_MEMBERS) as u32;
+        println!("broadcast to {} members: {:?} a member", BENCH_MEMBERS, per_member);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_nick_change_large_channel() {
+        let mut members = bench_members();
+        let start = Instant::now();
+        for id in 0..BENCH_MEMBERS as u64 {
+            members.rename_id(id, &format!("renamed{}", id));
+        }
+        let per_rename = start.elapsed() / BENCH_MEMBERS as u32;
+        assert_eq!(members.len(), BENCH_MEMBERS);
+        println!("nick change with {} members: {:?} a change", BENCH_MEMBERS, per_rename);
+    }
+}
@@ -30,6 +30,7 @@ impl From<ParseError> for Error {
             ParseError::EmptyNick => Error::EmptyNick,
             ParseError::EmptyUser => Error::EmptyUser,
             ParseError::EmptyHost => Error::EmptyHost,
+            ParseError::InvalidTags(tags) => Error::InvalidTags(tags),
         }
     }
 }
@@ -39,18 +40,45 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::NoSuchNick(nick) => write!(f, "401 {} :No such nick/channel", nick),
+            Error::NoTopLevel(mask) => write!(f, "413 {} :No toplevel domain specified", mask),
+            Error::WildTopLevel(mask) => write!(f, "414 {} :Wildcard in toplevel domain", mask),
             Error::NoSuchChannel(chan) => write!(f, "403 {} :No such channel", chan),
             Error::CannotSendToChan(chan) => write!(f, "404 {} :Cannot send to channel", chan),
+            Error::TooManyChannels(chan) => write!(f, "405 {} :You have joined too many channels", chan),
+            Error::WasNoSuchNick(nick) => write!(f, "406 {} :There was no such nickname", nick),
+            Error::TooManyTargets(target) => write!(f, "407 {} :Too many recipients. The rest were ignored", target),
+            Error::NoOrigin => write!(f, "409 :No origin specified"),
+            Error::InvalidCapCmd(cmd) => write!(f, "410 {} :Invalid CAP command", cmd),
             Error::NoRecipient(cmd) => write!(f, "411 :No recipient given ({})", cmd),
             Error::NoTextToSend => write!(f, "412 :No text to send"),
+            Error::InputTooLong => write!(f, "417 :Input line was too long"),
             Error::UnknownCommand(cmd) => write!(f, "421 {} :Unknown command", cmd),
+            Error::NoMotd => write!(f, "422 :MOTD File is missing"),
+            Error::FileError(op, file) => write!(f, "424 :File error doing {} on {}", op, file),
             Error::ErroneusNickname(nick) => write!(f, "432 {} :Erroneous nickname", nick),
             Error::NicknameInUse(nick) => write!(f, "433 {} :Nickname is already in use", nick),
+            Error::NickCollision(nick) => write!(f, "436 {} :Nickname collision KILL", nick),
+            Error::NickChangeTooFast(nick, secs) => write!(f, "438 {} :Nick change too fast. Please wait {} seconds", nick, secs),
+            Error::UserNotInChannel(nick, chan) => write!(f, "441 {} {} :They aren't on that channel", nick, chan),
+            Error::UserOnChannel(nick, chan) => write!(f, "443 {} {} :is already on channel", nick, chan),
             Error::NotOnChannel(chan) => write!(f, "442 {} :You're not on that channel", chan),
             Error::NotRegistered => write!(f, "451 :You have not registered"),
             Error::NeedMoreParams(cmd) => write!(f, "461 {} :Not enough parameters", cmd),
             Error::AlreadyRegistred => write!(f, "462 :You may not reregister"),
+            Error::PasswdMismatch => write!(f, "464 :Password incorrect"),
+            Error::KeySet(chan) => write!(f, "467 {} :Channel key already set", chan),
+            Error::ChannelIsFull(chan) => write!(f, "471 {} :Cannot join channel (+l)", chan),
+            Error::UnknownMode(mode) => write!(f, "472 {} :is unknown mode char to me", mode),
+            Error::InviteOnlyChan(chan) => write!(f, "473 {} :Cannot join channel (+i)", chan),
+            Error::BannedFromChan(chan) => write!(f, "474 {} :Cannot join channel (+b)", chan),
+            Error::BadChannelKey(chan) => write!(f, "475 {} :Cannot join channel (+k)", chan),
+            Error::CannotKnock(chan, reason) => write!(f, "480 :Cannot knock on {} ({})", chan, reason),
+            Error::NoPrivileges => write!(f, "481 :Permission Denied- You're not an IRC operator"),
             Error::ChanOPrivsNeeded(chan) => write!(f, "482 {} :You're not channel operator", chan),
+            Error::UModeUnknownFlag => write!(f, "501 :Unknown MODE flag"),
+            Error::UsersDontMatch => write!(f, "502 :Cant change mode for other users"),
+            Error::SilenceListFull(mask) => write!(f, "511 {} :Your silence list is full", mask),
+            Error::TooManyKnocks(chan, who) => write!(f, "713 {} :Too many KNOCKs ({})", chan, who),
             Error::InvalidCommand(cmd) => write!(f, "600 {} :Parser: invalid command", cmd),
             Error::InvalidHost(host) => write!(f, "601 {} :Parser: invalid host", host),
             Error::InvalidUser(user) => write!(f, "602 {} :Parser: invalid user", user),
@@ -60,6 +88,7 @@ impl fmt::Display for Error {
             Error::EmptyNick => write!(f, "606 :Parser: empty message"),
             Error::EmptyUser => write!(f, "607 :Parser: empty message"),
             Error::EmptyHost => write!(f, "608 :Parser: empty message"),
+            Error::InvalidTags(tags) => write!(f, "609 {} :Parser: invalid tags", tags),
         }
     }
 }
@@ -70,25 +99,28 @@ pub enum Error {
     //    NoSuchServer(        NumReply, &'static str),
     NoSuchChannel(String),
     CannotSendToChan(String),
-    //    TooManyChannels(     NumReply, &'static str),
-    //    WasNoSuchNick(       NumReply, &'static str),
-    //    TooManyTargets(      NumReply, &'static str),
-    //    NoOrigin(            NumReply, &'static str),
+    TooManyChannels(String),
+    WasNoSuchNick(String),
+    TooManyTargets(String),
+    NoOrigin,
+    InvalidCapCmd(String),
     NoRecipient(String),
     NoTextToSend,
-    //    NoTopLevel(          NumReply, &'static str),
-    //    WildTopLevel(        NumReply, &'static str),
+    NoTopLevel(String),
+    WildTopLevel(String),
+    InputTooLong,
     UnknownCommand(String),
-    //    NoMotd(              NumReply, &'static str),
+    NoMotd,
     //    NoAdminInfo(         NumReply, &'static str),
-    //    FileError(           NumReply, &'static str),
+    FileError(String, String),
     //    NoNickNameGiven(     NumReply, &'static str),
     ErroneusNickname(String),
     NicknameInUse(String),
-    //    NickCollision(       NumReply, &'static str),
-    //    UserNotInChannel(    NumReply, &'static str),
+    NickCollision(String),
+    NickChangeTooFast(String, u64),
+    UserNotInChannel(String, String),
     NotOnChannel(String),
-    //    UserOnChannel(       NumReply, &'static str),
+    UserOnChannel(String, String),
     //    NoLogin(             NumReply, &'static str),
     //    SummonDisabled(      NumReply, &'static str),
     //    UsersDisabled(       NumReply, &'static str),
@@ -96,20 +128,23 @@ pub enum Error {
     NeedMoreParams(String),
     AlreadyRegistred,
     //    NoPermForHost(       NumReply, &'static str),
-    //    PasswdmisMatch(      NumReply, &'static str),
+    PasswdMismatch,
     //    YoureBannedCreep(    NumReply, &'static str),
-    //    KeySet(              NumReply, &'static str),
-    //    ChannelIsFull(       NumReply, &'static str),
-    //    UnknownMode(         NumReply, &'static str),
-    //    InviteOnlyChan(      NumReply, &'static str),
-    //    BannedFromChan(      NumReply, &'static str),
-    //    BadChannelKey(       NumReply, &'static str),
-    //    NoPrivileges(        NumReply, &'static str),
+    KeySet(String),
+    ChannelIsFull(String),
+    UnknownMode(char),
+    InviteOnlyChan(String),
+    BannedFromChan(String),
+    BadChannelKey(String),
+    CannotKnock(String, String),
+    NoPrivileges,
     ChanOPrivsNeeded(String),
     //    CantKillServer(      NumReply, &'static str),
     //    NoOperHost(          NumReply, &'static str),
-    //    UModeUnknownFlag(    NumReply, &'static str),
-    //    UsersDontMatch(      NumReply, &'static str),
+    UModeUnknownFlag,
+    UsersDontMatch,
+    SilenceListFull(String),
+    TooManyKnocks(String, String),
     //BadChanMask(String)
     InvalidCommand(String),
     InvalidHost(String),
@@ -120,6 +155,7 @@ pub enum Error {
     EmptyNick,
     EmptyUser,
     EmptyHost,
+    InvalidTags(String),
 }
 
 //pub const ERR_NOSUCHNICK: Error = Error::NoSuchNick(401, "<nickname> :No such nick/channel");
@@ -143,6 +179,7 @@ pub enum Error {
 //pub const ERR_NICKNAMEINUSE: Error =
 //    Error::NicknameInUse(433, "<nick> :Nickname is already in use");
 //pub const ERR_: Error = NickCollision(       436, "<nick> :Nickname collision KILL"),
+//pub const ERR_: Error = NickChangeTooFast(   438, "<nick> :Nick change too fast. Please wait <secs> seconds"),
 //pub const ERR_: Error = UserNotInChannel(    441, "<nick> <channel> :They aren't on that channel"),
 //pub const ERR_: Error = NotOnChannel(        442, "<channel> :You're not on that channel"),
 //pub const ERR_: Error = UserOnChannel(       443, "<user> <channel> :is already on channel"),
@@ -162,9 +199,12 @@ pub enum Error {
 //pub const ERR_: Error = InviteOnlyChan(      473, "<channel> :Cannot join channel (+i)"),
 //pub const ERR_: Error = BannedFromChan(      474, "<channel> :Cannot join channel (+b)"),
 //pub const ERR_: Error = BadChannelKey(       475, "<channel> :Cannot join channel (+k)"),
+//pub const ERR_: Error = CannotKnock(         480, ":Cannot knock on <channel> (<reason>)"),
 //pub const ERR_: Error = NoPrivileges(        481, ":Permission Denied- You're not an IRC operator"),
 //pub const ERR_: Error = ChanOPrivsNeeded(    482, "<channel> :You're not channel operator"),
 //pub const ERR_: Error = CantKillServer(      483, ":You cant kill a server!"),
 //pub const ERR_: Error = NoOperHost(          491, ":No O-lines for your host"),
 //pub const ERR_: Error = UModeUnknownFlag(    501, ":Unknown MODE flag"),
-//pub const ERR_: Error = UsersDontMatch(      502, ":Cant change mode for other users")
+//pub const ERR_: Error = UsersDontMatch(      502, ":Cant change mode for other users"),
+//pub const ERR_: Error = SilenceListFull(     511, "<mask> :Your silence list is full")
+//pub const ERR_: Error = TooManyKnocks(       713, "<channel> :Too many KNOCKs (<user|channel>)")
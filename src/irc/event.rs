@@ -0,0 +1,40 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::client::Client;
+use crate::irc::chan::Channel;
+use crate::irc::User;
+use std::fmt;
+
+/* anything that wants to know what's going on without the command handlers
+ * having to know about it (logging, metrics, server notices, DNSBL checks...)
+ * implements Observer and gets handed to Core::subscribe(), every hook has a
+ * default no-op body so observers only implement the ones they care about.
+ * Hooks are called synchronously from the handlers, with no Core locks held,
+ * so anything slow should be handed off to a task of its own */
+pub trait Observer: fmt::Debug + Send + Sync {
+    /* a new connection has been accepted, nothing is known about it yet */
+    fn on_connect(&self, _client: &Client) {}
+
+    /* NICK/USER handshake completed, the user is now in the namespace */
+    fn on_register(&self, _user: &User) {}
+
+    /* user has been added to a channel */
+    fn on_join(&self, _user: &User, _chan: &Channel) {}
+
+    /* user has left the server via QUIT */
+    fn on_quit(&self, _user: &User, _msg: &str) {}
+}
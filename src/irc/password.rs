@@ -0,0 +1,88 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+use std::fs::File;
+use std::io::Error as ioError;
+use std::io::Read;
+use subtle::ConstantTimeEq;
+
+/* passwords (OPER, PASS and server links) are never kept in the clear,
+ * just as `pbkdf2-sha256$<rounds>$<salt>$<hash>` with the salt and hash in
+ * hex, which is what `rusty-ircd --hash-password <password>` prints. The
+ * rounds go in with each one so they can be turned up later without
+ * breaking any that are already out there */
+const SCHEME: &str = "pbkdf2-sha256";
+pub const ROUNDS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+fn derive(password: &str, salt: &[u8], rounds: u32) -> [u8; HASH_LEN] {
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+/* straight from the kernel, for salts and anything else that mustn't be guessed */
+pub fn random_bytes(buf: &mut [u8]) -> Result<(), ioError> {
+    File::open("/dev/urandom")?.read_exact(buf)
+}
+
+/* a fresh salt every time, so the same password never hashes the same */
+pub fn hash_password(password: &str) -> Result<String, ioError> {
+    let mut salt = [0u8; SALT_LEN];
+    random_bytes(&mut salt)?;
+    Ok(hash_with_salt(password, &salt, ROUNDS))
+}
+
+pub fn hash_with_salt(password: &str, salt: &[u8], rounds: u32) -> String {
+    format!("{}${}${}${}", SCHEME, rounds, to_hex(salt), to_hex(&derive(password, salt, rounds)))
+}
+
+/* compared in constant time so nobody can work a hash out a byte at a
+ * time. Anything that isn't one of ours never matches */
+pub fn verify(stored: &str, password: &str) -> bool {
+    let fields: Vec<&str> = stored.split('$').collect();
+    let (rounds, salt, hash) = match fields[..] {
+        [SCHEME, rounds, salt, hash] => match (rounds.parse::<u32>(), from_hex(salt), from_hex(hash)) {
+            (Ok(rounds), Some(salt), Some(hash)) if rounds > 0 && hash.len() == HASH_LEN => (rounds, salt, hash),
+            _ => return false,
+        },
+        _ => return false,
+    };
+    derive(password, &salt, rounds).ct_eq(&hash[..]).into()
+}
+
+/* for when there's nothing to check against (e.g. an unknown oper name),
+ * so the answer takes as long as a real check would */
+pub fn waste_time(password: &str) {
+    derive(password, &[0u8; SALT_LEN], ROUNDS);
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
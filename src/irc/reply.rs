@@ -15,49 +15,130 @@
 *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 /*
+    005 RPL_ISUPPORT "<token> {<space><token>} :are supported by this server"
+    212 RPL_STATSCOMMANDS "<command> <count>"
+    219 RPL_ENDOFSTATS "<stats letter> :End of STATS report"
+    221 RPL_UMODEIS "<user mode string>"
+    242 RPL_STATSUPTIME ":Server Up %d days %d:%02d:%02d"
+    251 RPL_LUSERCLIENT ":There are <integer> users and <integer> services on <integer> servers"
+    254 RPL_LUSERCHANNELS "<integer> :channels formed"
+    255 RPL_LUSERME ":I have <integer> clients and <integer> servers"
+    256 RPL_ADMINME "<server> :Administrative info"
+    257 RPL_ADMINLOC1 ":<admin info>"
+    258 RPL_ADMINLOC2 ":<admin info>"
+    259 RPL_ADMINEMAIL ":<admin info>"
+    271 RPL_SILELIST "<nick> <mask>"
+    272 RPL_ENDOFSILELIST ":End of Silence List"
     300 RPL_NONE
+    301 RPL_AWAY "<nick> :<away message>"
     302 RPL_USERHOST ":[<reply>{<space><reply>}]"
     303 RPL_ISON ":[<nick> {<space><nick>}]"
-    301 RPL_AWAY "<nick> :<away message>"
     305 RPL_UNAWAY ":You are no longer marked as being away"
     306 RPL_NOWAWAY ":You have been marked as being away"
     311 RPL_WHOISUSER "<nick> <user> <host> * :<real name>"
     312 RPL_WHOISSERVER "<nick> <server> :<server info>"
     313 RPL_WHOISOPERATOR "<nick> :is an IRC operator"
+    314 RPL_WHOWASUSER "<nick> <user> <host> * :<real name>"
     317 RPL_WHOISIDLE "<nick> <integer> :seconds idle"
     318 RPL_ENDOFWHOIS "<nick> :End of /WHOIS list"
     319 RPL_WHOISCHANNELS "<nick> :{[@|+]<channel><space>}"
-    314 RPL_WHOWASUSER "<nick> <user> <host> * :<real name>"
-    369 RPL_ENDOFWHOWAS "<nick> :End of WHOWAS"
     321 RPL_LISTSTART "Channel :Users  Name"
     322 RPL_LIST "<channel> <# visible> :<topic>"
     323 RPL_LISTEND ":End of /LIST"
     324 RPL_CHANNELMODEIS "<channel> <mode> <mode params>"
+    329 RPL_CREATIONTIME "<channel> <creation time>"
     331 RPL_NOTOPIC "<channel> :No topic is set"
     332 RPL_TOPIC "<channel> :<topic>"
     341 RPL_INVITING "<channel> <nick>"
     342 RPL_SUMMONING "<user> :Summoning user to IRC"
+    346 RPL_INVITELIST "<channel> <invitemask>"
+    347 RPL_ENDOFINVITELIST "<channel> :End of channel invite list"
+    348 RPL_EXCEPTLIST "<channel> <exceptionmask>"
+    349 RPL_ENDOFEXCEPTLIST "<channel> :End of channel exception list"
     351 RPL_VERSION "<version>.<debuglevel> <server> :<comments>"
     352 RPL_WHOREPLY "<channel> <user> <host> <server> <nick> <H|G>[*][@|+] :<hopcount> <real name>"
+    364 RPL_LINKS "<mask> <server> :<hopcount> <server info>"
+    365 RPL_ENDOFLINKS "<mask> :End of LINKS list"
+    367 RPL_BANLIST "<channel> <banmask>"
+    368 RPL_ENDOFBANLIST "<channel> :End of channel ban list"
+    369 RPL_ENDOFWHOWAS "<nick> :End of WHOWAS"
+    371 RPL_INFO ":<string>"
+    372 RPL_MOTD ":- <text>"
+    374 RPL_ENDOFINFO ":End of INFO list"
+    375 RPL_MOTDSTART ":- <server> Message of the day - "
+    376 RPL_ENDOFMOTD ":End of /MOTD command"
+    381 RPL_YOUREOPER ":You are now an IRC operator"
+    382 RPL_REHASHING "<config file> :Rehashing"
+    391 RPL_TIME "<server> :<string showing server's local time>"
+    710 RPL_KNOCK "<channel> <nick>!<user>@<host> :has asked for an invite"
+    711 RPL_KNOCKDLVR "<channel> :Your KNOCK has been delivered"
     seems to be some missing...
 */
 
 use std::fmt;
-use std::str;
 use crate::irc::rfc_defs as rfc;
 use crate::irc::chan::ChanTopic;
 
 pub enum Reply {
     None,
-    Welcome(String, String, String),
+    Welcome(String, String, String, String),
     YourHost(String, String),
     Created(String),
     MyInfo(String, String, String, String),
+    ISupport(Vec<String>),
+    StatsCommands(String, u64),
+    EndofStats(String),
+    UserModeIs(String),
+    StatsUptime(u64),
+    LuserClient(usize),
+    LuserChannels(usize),
+    LuserMe(usize),
+    AdminMe(String),
+    AdminLoc1(String),
+    AdminLoc2(String),
+    AdminEmail(String),
+    SilenceList(String, String),
+    EndofSilenceList,
+    Away(String, String),
+    UserHost(Vec<String>),
+    IsOn(Vec<String>),
+    UnAway,
+    NowAway,
+    EndofWho(String),
+    WhoisUser(String, String, String, String),
+    WhoisServer(String, String, String),
+    EndofWhois(String),
+    WhoisChannels(String, Vec<String>),
+    WhoWasUser(String, String, String, String),
+    EndofWhoWas(String),
+    ChannelModeIs(String, String),
+    CreationTime(String, i64),
     NoTopic(String),
     Topic(String, String),
     TopicSetBy(String, String, i64),
+    Inviting(String, String),
+    InviteList(String, String, String, i64),
+    EndofInviteList(String),
+    ExceptList(String, String, String, i64),
+    EndofExceptList(String),
+    Version(String, String, String),
+    Links(String, String, u32, String),
+    EndofLinks(String),
+    WhoReply(String, String, String, String, String, String, String),
     NameReply(String, Vec<String>),
     EndofNames(String),
+    BanList(String, String, String, i64),
+    EndofBanList(String),
+    Info(String),
+    Motd(String),
+    EndofInfo,
+    MotdStart(String),
+    EndofMotd,
+    YoureOper,
+    Rehashing(String),
+    Time(String, String),
+    Knock(String, String, Option<String>),
+    KnockDelivered(String),
     ListStart,
     ListReply(String, usize, Option<ChanTopic>),
     EndofList,
@@ -70,19 +151,68 @@ impl Reply {
     /* map enums to numberic reply codes */
     fn numeric(&self) -> Code {
         match self {
-            Reply::Welcome(_n, _u, _h) => 001,
+            Reply::Welcome(_w, _n, _u, _h) => 001,
             Reply::YourHost(_s,_v) => 002,
             Reply::Created(_t) => 003,
             Reply::MyInfo(_s, _v, _um, _cm) => 004,
+            Reply::ISupport(_toks) => 005,
+            Reply::StatsCommands(_cmd, _count) => 212,
+            Reply::EndofStats(_letter) => 219,
+            Reply::UserModeIs(_modes) => 221,
+            Reply::StatsUptime(_secs) => 242,
+            Reply::LuserClient(_users) => 251,
+            Reply::LuserChannels(_chans) => 254,
+            Reply::LuserMe(_clients) => 255,
+            Reply::AdminMe(_serv) => 256,
+            Reply::AdminLoc1(_info) => 257,
+            Reply::AdminLoc2(_info) => 258,
+            Reply::AdminEmail(_info) => 259,
+            Reply::SilenceList(_nick, _mask) => 271,
+            Reply::EndofSilenceList => 272,
             Reply::None => 300,
+            Reply::Away(_n, _msg) => 301,
+            Reply::UserHost(_toks) => 302,
+            Reply::IsOn(_ns) => 303,
+            Reply::UnAway => 305,
+            Reply::NowAway => 306,
+            Reply::EndofWho(_name) => 315,
+            Reply::WhoisUser(_n, _u, _h, _r) => 311,
+            Reply::WhoisServer(_n, _s, _i) => 312,
+            Reply::EndofWhois(_n) => 318,
+            Reply::WhoisChannels(_n, _chs) => 319,
+            Reply::WhoWasUser(_n, _u, _h, _r) => 314,
+            Reply::EndofWhoWas(_n) => 369,
             Reply::ListStart => 321,
             Reply::ListReply(_ch, _nu, _top) => 322,
             Reply::EndofList => 323,
+            Reply::ChannelModeIs(_ch, _modes) => 324,
+            Reply::CreationTime(_ch, _stamp) => 329,
             Reply::NoTopic(_ch) => 331,
             Reply::Topic(_ch, _top) => 332,
             Reply::TopicSetBy(_ch, _umask, _stamp) => 333,
+            Reply::Inviting(_ch, _nick) => 341,
+            Reply::InviteList(_ch, _mask, _by, _stamp) => 346,
+            Reply::EndofInviteList(_ch) => 347,
+            Reply::ExceptList(_ch, _mask, _by, _stamp) => 348,
+            Reply::EndofExceptList(_ch) => 349,
+            Reply::Version(_v, _s, _c) => 351,
+            Reply::Links(_m, _s, _hops, _info) => 364,
+            Reply::EndofLinks(_m) => 365,
+            Reply::WhoReply(_ch, _u, _h, _s, _n, _fl, _r) => 352,
             Reply::NameReply(_ch, _ns) => 353,
-            Reply::EndofNames(_ch) => 366
+            Reply::EndofNames(_ch) => 366,
+            Reply::BanList(_ch, _mask, _by, _stamp) => 367,
+            Reply::EndofBanList(_ch) => 368,
+            Reply::Info(_line) => 371,
+            Reply::Motd(_line) => 372,
+            Reply::EndofInfo => 374,
+            Reply::MotdStart(_serv) => 375,
+            Reply::EndofMotd => 376,
+            Reply::YoureOper => 381,
+            Reply::Rehashing(_file) => 382,
+            Reply::Time(_serv, _time) => 391,
+            Reply::Knock(_ch, _prefix, _reason) => 710,
+            Reply::KnockDelivered(_ch) => 711
         }
     }
 
@@ -95,10 +225,39 @@ impl Reply {
     fn body(&self) -> Option<String> {
         match self {
             Reply::None => None,
-            Reply::Welcome(nick, user, host) => Some(format!(":Welcome to Rusty IRC Network {}!{}@{}", nick, user, host)),
+            Reply::Welcome(network, nick, user, host) => Some(format!(":Welcome to the {} IRC Network {}!{}@{}", network, nick, user, host)),
             Reply::YourHost(serv, ver) => Some(format!(":Your host is {}, running version {}", serv, ver)),
             Reply::Created(time) => Some(format!(":This server was created {}", time)),
             Reply::MyInfo(serv, ver, umodes, chanmodes) => Some(format!(":{} {} {} {}", serv, ver, umodes, chanmodes)),
+            Reply::ISupport(tokens) => Some(format!("{} :are supported by this server", tokens.join(" "))),
+            Reply::StatsCommands(cmd, count) => Some(format!("{} {}", cmd, count)),
+            Reply::EndofStats(letter) => Some(format!("{} :End of STATS report", letter)),
+            Reply::UserModeIs(modes) => Some(modes.to_string()),
+            Reply::StatsUptime(secs) => Some(format!(
+                ":Server Up {} days {}:{:02}:{:02}",
+                secs / 86400, secs % 86400 / 3600, secs % 3600 / 60, secs % 60
+            )),
+            Reply::LuserClient(users) => Some(format!(":There are {} users and 0 services on 1 servers", users)),
+            Reply::LuserChannels(chans) => Some(format!("{} :channels formed", chans)),
+            Reply::LuserMe(clients) => Some(format!(":I have {} clients and 0 servers", clients)),
+            Reply::AdminMe(serv) => Some(format!("{} :Administrative info", serv)),
+            Reply::AdminLoc1(info) => Some(format!(":{}", info)),
+            Reply::AdminLoc2(info) => Some(format!(":{}", info)),
+            Reply::AdminEmail(info) => Some(format!(":{}", info)),
+            Reply::SilenceList(nick, mask) => Some(format!("{} {}", nick, mask)),
+            Reply::EndofSilenceList => Some(String::from(":End of Silence List")),
+            Reply::Away(nick, away_msg) => Some(format!("{} :{}", nick, away_msg)),
+            Reply::UserHost(tokens) => Some(format!(":{}", tokens.join(" "))),
+            Reply::IsOn(nicks) => Some(format!(":{}", nicks.join(" "))),
+            Reply::UnAway => Some(String::from(":You are no longer marked as being away")),
+            Reply::NowAway => Some(String::from(":You have been marked as being away")),
+            Reply::EndofWho(name) => Some(format!("{} :End of WHO list", name)),
+            Reply::WhoisUser(nick, user, host, real_name) => Some(format!("{} {} {} * :{}", nick, user, host, real_name)),
+            Reply::WhoisServer(nick, server, info) => Some(format!("{} {} :{}", nick, server, info)),
+            Reply::EndofWhois(nick) => Some(format!("{} :End of /WHOIS list", nick)),
+            Reply::WhoisChannels(nick, chans) => Some(format!("{} :{}", nick, chans.join(" "))),
+            Reply::WhoWasUser(nick, user, host, real_name) => Some(format!("{} {} {} * :{}", nick, user, host, real_name)),
+            Reply::EndofWhoWas(nick) => Some(format!("{} :End of WHOWAS", nick)),
             Reply::ListStart => Some(format!("Channel Users :Topic")),
             Reply::ListReply(chan, n_users, topic_opt) => {
                 if let Some(topic) = topic_opt {
@@ -108,11 +267,36 @@ impl Reply {
                 }
             },
             Reply::EndofList => Some(format!(":End of /LIST")),
+            Reply::ChannelModeIs(chan, modes) => Some(format!("{} {}", chan, modes)),
+            Reply::CreationTime(chan, timestamp) => Some(format!("{} {}", chan, timestamp)),
             Reply::NoTopic(chan) => Some(format!("{} :No topic is set.", chan)),
             Reply::Topic(chan, topic_msg) => Some(format!("{} :{}", chan, topic_msg)),
             Reply::TopicSetBy(chan, usermask, timestamp) => Some(format!("{} {} {}", chan, usermask, timestamp)),
+            Reply::Inviting(chan, nick) => Some(format!("{} {}", chan, nick)),
+            Reply::InviteList(chan, mask, set_by, timestamp) => Some(format!("{} {} {} {}", chan, mask, set_by, timestamp)),
+            Reply::EndofInviteList(chan) => Some(format!("{} :End of channel invite list", chan)),
+            Reply::ExceptList(chan, mask, set_by, timestamp) => Some(format!("{} {} {} {}", chan, mask, set_by, timestamp)),
+            Reply::EndofExceptList(chan) => Some(format!("{} :End of channel exception list", chan)),
+            Reply::Version(ver, serv, comments) => Some(format!("{} {} :{}", ver, serv, comments)),
+            Reply::Links(mask, serv, hops, info) => Some(format!("{} {} :{} {}", mask, serv, hops, info)),
+            Reply::EndofLinks(mask) => Some(format!("{} :End of LINKS list", mask)),
+            Reply::WhoReply(chan, user, host, server, nick, flags, real_name)
+                => Some(format!("{} {} {} {} {} {} :0 {}", chan, user, host, server, nick, flags, real_name)),
             Reply::NameReply(chan, nicks) => Some(format!("{} :{}", chan, nicks.join(" "))),
             Reply::EndofNames(chan) => Some(format!("{} :End of /NAMES list", chan)),
+            Reply::BanList(chan, mask, set_by, timestamp) => Some(format!("{} {} {} {}", chan, mask, set_by, timestamp)),
+            Reply::EndofBanList(chan) => Some(format!("{} :End of channel ban list", chan)),
+            Reply::Info(line) => Some(format!(":{}", line)),
+            Reply::Motd(line) => Some(format!(":- {}", line)),
+            Reply::EndofInfo => Some(String::from(":End of INFO list")),
+            Reply::MotdStart(serv) => Some(format!(":- {} Message of the day - ", serv)),
+            Reply::EndofMotd => Some(String::from(":End of /MOTD command")),
+            Reply::YoureOper => Some(String::from(":You are now an IRC operator")),
+            Reply::Rehashing(file) => Some(format!("{} :Rehashing", file)),
+            Reply::Time(serv, time) => Some(format!("{} :{}", serv, time)),
+            Reply::Knock(chan, prefix, None) => Some(format!("{} {} :has asked for an invite", chan, prefix)),
+            Reply::Knock(chan, prefix, Some(reason)) => Some(format!("{} {} :has asked for an invite ({})", chan, prefix, reason)),
+            Reply::KnockDelivered(chan) => Some(format!("{} :Your KNOCK has been delivered", chan)),
         }
     }
 
@@ -127,64 +311,95 @@ impl Reply {
     }
 }
 
-/* `:asdf.cool.net 001 luser :Welcome my lovely!` */
+/* `:asdf.cool.net 001 luser :Welcome my lovely!` - break a reply that's
+ * too long into the first line and whatever's left over (which goes back
+ * through here until it fits), both keeping everything before the ` :` so
+ * the trailing part is what gets spread out. Anything we can't split
+ * sensibly is handed back whole, and fit_line() will cut it down */
 pub fn split(message: &str) -> (String, Option<String>) {
-    let msg_bytes = message.as_bytes();
-    if msg_bytes.len() <= rfc::MAX_MSG_SIZE - 2
-        || msg_bytes[0] != b':' {
+    if message.len() <= rfc::MAX_MSG_SIZE - 2
+        || !message.starts_with(':') {
         return (message.to_string(), None);
     }
-    
+
     let message_trimmed = &message[1..];
-    let substrings: Vec<&str> = message_trimmed.splitn(2, " :").collect();
-    if substrings.len() != 2 {
-        panic!("message {} contains no ` :` token!", message);
+    let (prefix, reply_bulk) = match message_trimmed.find(" :") {
+        Some(idx) => (&message_trimmed[..idx], &message_trimmed[idx + 2..]),
+        None => return (message.to_string(), None),
+    };
+    /* the leading ':', the ' :' and the CRLF */
+    let overhead = prefix.len() + 5;
+    if overhead >= rfc::MAX_MSG_SIZE {
+        return (message.to_string(), None);
     }
-    let prefix = substrings[0];
-    let prefix_bytes = substrings[0].as_bytes();
-    let reply_bulk = substrings[1].as_bytes();
-    let overhead = prefix_bytes.len() + 5;
     let room = rfc::MAX_MSG_SIZE - overhead;
-    if reply_bulk.len() <= room {
-        panic!("body {} is already short enough, algorithm is broken", str::from_utf8(reply_bulk).unwrap());
-    }
 
-    if let Some(space_index) = rfind_space_index(reply_bulk, room) {
-        let chunk = str::from_utf8(&reply_bulk[..space_index]).unwrap();
-        let remainder = str::from_utf8(&reply_bulk[space_index+1..]).unwrap();
-        (
-            format!(":{} :{}", prefix, chunk),
-            Some(format!(":{} :{}", prefix, remainder))
-        )
-    } else {
-        /* if there was no space we could use to split at, just cut arbitrarily at the max */
-        let chunk = str::from_utf8(&reply_bulk[..room]).unwrap();
-        let remainder = str::from_utf8(&reply_bulk[room..]).unwrap();
-        (
-            format!(":{} :{}", prefix, chunk),
-            Some(format!(":{} :{}", prefix, remainder))
-        )
-    }
+    /* split at the last space that fits, or if there isn't one, cut at the
+     * max - backing off so we don't land in the middle of a char */
+    let (chunk, remainder) = match reply_bulk[..floor_char_boundary(reply_bulk, room)].rfind(' ') {
+        Some(space_index) if space_index > 0 => (&reply_bulk[..space_index], &reply_bulk[space_index + 1..]),
+        _ => {
+            let cut = floor_char_boundary(reply_bulk, room);
+            if cut == 0 {
+                return (message.to_string(), None);
+            }
+            reply_bulk.split_at(cut)
+        }
+    };
+    (
+        format!(":{} :{}", prefix, chunk),
+        Some(format!(":{} :{}", prefix, remainder))
+    )
 }
 
-fn rfind_space_index (bytes: &[u8], mut index: usize) -> Option<usize> {
-    while index > 0 {
-        if bytes[index] == b' ' {
-            return Some(index);
-        }
+/* the nearest char boundary at or before index */
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    if index >= text.len() {
+        return text.len();
+    }
+    while !text.is_char_boundary(index) {
         index -= 1;
     }
-    None
+    index
 }
 
 impl fmt::Display for Reply {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Reply::None => write!(f, "300"),
-            Reply::Welcome(nick, user, host) => write!(f, "001 :Welcome to Rusty IRC Network {}!{}@{}", nick, user, host),
+            Reply::Welcome(network, nick, user, host) => write!(f, "001 :Welcome to the {} IRC Network {}!{}@{}", network, nick, user, host),
             Reply::YourHost(serv, ver) => write!(f, "002 :Your host is {}, running version {}", serv, ver),
             Reply::Created(time) => write!(f, "003 :This server was created {}", time),
             Reply::MyInfo(serv, ver, umodes, chanmodes) => write!(f, "004 :{} {} {} {}", serv, ver, umodes, chanmodes),
+            Reply::ISupport(tokens) => write!(f, "005 {} :are supported by this server", tokens.join(" ")),
+            Reply::StatsCommands(cmd, count) => write!(f, "212 {} {}", cmd, count),
+            Reply::EndofStats(letter) => write!(f, "219 {} :End of STATS report", letter),
+            Reply::UserModeIs(modes) => write!(f, "221 {}", modes),
+            Reply::StatsUptime(secs) => write!(
+                f, "242 :Server Up {} days {}:{:02}:{:02}",
+                secs / 86400, secs % 86400 / 3600, secs % 3600 / 60, secs % 60
+            ),
+            Reply::LuserClient(users) => write!(f, "251 :There are {} users and 0 services on 1 servers", users),
+            Reply::LuserChannels(chans) => write!(f, "254 {} :channels formed", chans),
+            Reply::LuserMe(clients) => write!(f, "255 :I have {} clients and 0 servers", clients),
+            Reply::AdminMe(serv) => write!(f, "256 {} :Administrative info", serv),
+            Reply::AdminLoc1(info) => write!(f, "257 :{}", info),
+            Reply::AdminLoc2(info) => write!(f, "258 :{}", info),
+            Reply::AdminEmail(info) => write!(f, "259 :{}", info),
+            Reply::SilenceList(nick, mask) => write!(f, "271 {} {}", nick, mask),
+            Reply::EndofSilenceList => write!(f, "272 :End of Silence List"),
+            Reply::Away(nick, away_msg) => write!(f, "301 {} :{}", nick, away_msg),
+            Reply::UserHost(tokens) => write!(f, "302 :{}", tokens.join(" ")),
+            Reply::IsOn(nicks) => write!(f, "303 :{}", nicks.join(" ")),
+            Reply::UnAway => write!(f, "305 :You are no longer marked as being away"),
+            Reply::NowAway => write!(f, "306 :You have been marked as being away"),
+            Reply::EndofWho(name) => write!(f, "315 {} :End of WHO list", name),
+            Reply::WhoisUser(nick, user, host, real_name) => write!(f, "311 {} {} {} * :{}", nick, user, host, real_name),
+            Reply::WhoisServer(nick, server, info) => write!(f, "312 {} {} :{}", nick, server, info),
+            Reply::EndofWhois(nick) => write!(f, "318 {} :End of /WHOIS list", nick),
+            Reply::WhoisChannels(nick, chans) => write!(f, "319 {} :{}", nick, chans.join(" ")),
+            Reply::WhoWasUser(nick, user, host, real_name) => write!(f, "314 {} {} {} * :{}", nick, user, host, real_name),
+            Reply::EndofWhoWas(nick) => write!(f, "369 {} :End of WHOWAS", nick),
             Reply::ListStart => write!(f, "321 Chan Users :Topic"),
             Reply::ListReply(chan, n_users, topic_opt) => {
                 if let Some(topic) = topic_opt {
@@ -194,11 +409,36 @@ impl fmt::Display for Reply {
                 }
             },
             Reply::EndofList => write!(f, "323 :End of /LIST"),
+            Reply::ChannelModeIs(chan, modes) => write!(f, "324 {} {}", chan, modes),
+            Reply::CreationTime(chan, timestamp) => write!(f, "329 {} {}", chan, timestamp),
             Reply::NoTopic(chan) => write!(f, "331 {} :No topic is set", chan),
             Reply::Topic(chan, topic_msg) => write!(f, "332 {} :{}", chan, topic_msg),
             Reply::TopicSetBy(chan, usermask, timestamp) => write!(f, "333 {} {} {}", chan, usermask, timestamp),
+            Reply::Inviting(chan, nick) => write!(f, "341 {} {}", chan, nick),
+            Reply::InviteList(chan, mask, set_by, timestamp) => write!(f, "346 {} {} {} {}", chan, mask, set_by, timestamp),
+            Reply::EndofInviteList(chan) => write!(f, "347 {} :End of channel invite list", chan),
+            Reply::ExceptList(chan, mask, set_by, timestamp) => write!(f, "348 {} {} {} {}", chan, mask, set_by, timestamp),
+            Reply::EndofExceptList(chan) => write!(f, "349 {} :End of channel exception list", chan),
+            Reply::Version(ver, serv, comments) => write!(f, "351 {} {} :{}", ver, serv, comments),
+            Reply::Links(mask, serv, hops, info) => write!(f, "364 {} {} :{} {}", mask, serv, hops, info),
+            Reply::EndofLinks(mask) => write!(f, "365 {} :End of LINKS list", mask),
+            Reply::WhoReply(chan, user, host, server, nick, flags, real_name)
+                => write!(f, "352 {} {} {} {} {} {} :0 {}", chan, user, host, server, nick, flags, real_name),
             Reply::NameReply(chan, nicks) => write!(f, "353 {} :{}", chan, nicks.join(" ")),
             Reply::EndofNames(chan) => write!(f, "366 {} :End of /NAMES list", chan),
+            Reply::BanList(chan, mask, set_by, timestamp) => write!(f, "367 {} {} {} {}", chan, mask, set_by, timestamp),
+            Reply::EndofBanList(chan) => write!(f, "368 {} :End of channel ban list", chan),
+            Reply::Info(line) => write!(f, "371 :{}", line),
+            Reply::Motd(line) => write!(f, "372 :- {}", line),
+            Reply::EndofInfo => write!(f, "374 :End of INFO list"),
+            Reply::MotdStart(serv) => write!(f, "375 :- {} Message of the day - ", serv),
+            Reply::EndofMotd => write!(f, "376 :End of /MOTD command"),
+            Reply::YoureOper => write!(f, "381 :You are now an IRC operator"),
+            Reply::Rehashing(file) => write!(f, "382 {} :Rehashing", file),
+            Reply::Time(serv, time) => write!(f, "391 {} :{}", serv, time),
+            Reply::Knock(chan, prefix, None) => write!(f, "710 {} {} :has asked for an invite", chan, prefix),
+            Reply::Knock(chan, prefix, Some(reason)) => write!(f, "710 {} {} :has asked for an invite ({})", chan, prefix, reason),
+            Reply::KnockDelivered(chan) => write!(f, "711 {} :Your KNOCK has been delivered", chan),
         }
     }
 }
\ No newline at end of file
@@ -16,6 +16,9 @@
 */
 pub const MAX_MSG_SIZE: usize = 512;
 pub const MAX_MSG_PARAMS: usize = 15; // including tailing, but not including COMMAND
+pub const MAX_TAGS_SIZE: usize = 4096; // IRCv3 client tags, on top of MAX_MSG_SIZE
+pub const MAX_NICK_LEN: usize = 9;
+pub const MAX_CHAN_LEN: usize = 50; // including the prefix char
 pub const LETTER: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
 pub const UPPER: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
 pub const LOWER: &str = "abcdefghijklmnopqrstuvwxyz";
@@ -47,62 +50,6 @@ fn matches_disallowed(msg: &str, disallowed: &str) -> bool {
     false
 }
 
-/* dunno if we really need our own code for this...
- * surely there's some library shit for it...
- * according to the rfc, we should have:
- * aug BNF ipv4addr = 1*3(DIGIT) 3("." (1*3(DIGIT))
- * YES! there is, for the IRC proto! will work on that soon
- */
-pub fn valid_ipv4_addr(host_addr: &str) -> bool {
-    let toks: Vec<&str> = host_addr.split('.').collect();
-    if toks.len() == 4 {
-        // tokenizing 127...0 would give us empty string slices
-        // and we would consider that invalid
-        for item in toks.iter() {
-            if item.is_empty() || item.len() > 3 || !matches_allowed(item, DIGIT) {
-                return false;
-            }
-        }
-        true
-    } else {
-        false
-    }
-}
-
-// again, might be a library function for this?
-// also, this only checks if the string format is generally valid
-// to what the rfc 2812 says it should be,
-// so for example the ipv4 parts can be 352.437.999.325,
-// and we won't complain
-pub fn valid_ipv6_addr(host_addr: &str) -> bool {
-    let toks: Vec<&str> = host_addr.split(':').collect();
-    // ipv6 should have 8 tokens
-    if toks.len() == 8 {
-        for item in toks.iter() {
-            // no empty tokens please...
-            if item.is_empty() || !matches_allowed(item, HEXDIGIT) {
-                return false;
-            }
-        }
-        true
-    } else if toks.len() == 7 {
-        for (i, item) in toks.iter().enumerate() {
-            if item.is_empty() {
-                return false;
-            } else if i < 5 && &item[..] != "0" {
-                return false;
-            } else if i == 5 && !(&item[..] == "0" || &item[..] == "FFFF") {
-                return false;
-            } else if i == 6 && !valid_ipv4_addr(item) {
-                return false;
-            }
-        }
-        true
-    } else {
-        false
-    }
-}
-
 // valid hostname/shortname
 // hostname can have periods which separate shortnames
 // aug BNF = shortname *( "." shortname )
@@ -133,7 +80,7 @@ pub fn valid_shortname(shortname: &str) -> bool {
     if shortname.is_empty() {
         return false;
     }
-    if &shortname[..1] != "-" && &shortname[..shortname.len() - 1] != "-" {
+    if !shortname.starts_with('-') && !shortname.ends_with('-') {
         let mut allowed = String::new();
         allowed.push_str(LETTER);
         allowed.push_str(DIGIT);
@@ -173,15 +120,24 @@ pub fn valid_channel(channame: &str) -> bool {
     // a channel name can be split into two chanstrings with exactly one ':'
     // but otherwise chanstrings cannot contain ':' but are otherwise
     // quite permissive
-    if channame.len() < 2 {
+    // a lone prefix with nothing after it (`#`, `&`...) isn't a channel,
+    // and the first char might not be ASCII, so don't go slicing at 1 blindly
+    let first_char = match channame.chars().next() {
+        Some(c) => c,
+        None => return false,
+    };
+    let mut rest = &channame[first_char.len_utf8()..];
+    if rest.is_empty() || channame.len() > MAX_CHAN_LEN {
         return false;
     }
-    let (first_char, mut rest) = (channame.as_bytes()[0] as char, &channame[1..]);
     match first_char {
         '&' | '+' | '#' => (),
-        '!' if rest.len() > 5 => {
-            if !valid_channelid(&rest[..5]) {
-                return false;
+        '!' => {
+            // the channel id is ASCII, so anything else in the first 5 bytes
+            // would leave us slicing through the middle of a char
+            match rest.get(..5) {
+                Some(id) if rest.len() > 5 && valid_channelid(id) => (),
+                _ => return false,
             }
             rest = &rest[5..]; // in this case maybe easier to modify the rest slice
         }
@@ -217,18 +173,120 @@ pub fn valid_chanstring(chanstring: &str) -> bool {
     !matches_disallowed(chanstring, NOT_CHANSTRING)
 }
 
+// glob style matching for masks, '*' is any run of characters (including
+// none) and '?' is exactly one, compared case insensitively
+pub fn wildcard_match(mask: &str, name: &str) -> bool {
+    let mask: Vec<char> = to_irc_lower(mask).chars().collect();
+    let name: Vec<char> = to_irc_lower(name).chars().collect();
+    let (mut m, mut n) = (0, 0);
+    // where to backtrack to on a mismatch: the last '*' seen in the mask
+    // and the point in the name it's currently soaking up to
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if m < mask.len() && (mask[m] == '?' || mask[m] == name[n]) {
+            m += 1;
+            n += 1;
+        } else if m < mask.len() && mask[m] == '*' {
+            star = Some((m, n));
+            m += 1;
+        } else if let Some((star_m, star_n)) = star {
+            m = star_m + 1;
+            n = star_n + 1;
+            star = Some((star_m, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    mask[m..].iter().all(|c| *c == '*')
+}
+
+// fill out a partial mask to a full nick!user@host one, e.g.
+// `nick` -> `nick!*@*`, `user@host` -> `*!user@host`
+pub fn expand_mask(mask: &str) -> String {
+    match (mask.contains('!'), mask.contains('@')) {
+        (true, true) => mask.to_string(),
+        (true, false) => format!("{}@*", mask),
+        (false, true) => format!("*!{}", mask),
+        (false, false) => format!("{}!*@*", mask),
+    }
+}
+
+// CR, LF or NUL in the middle of a line would end it early for whoever we
+// relay it on to, letting a client slip in lines of its own (e.g.
+// "hi\rKILL x"), so they're stripped out of everything that comes in
+pub fn sanitize_message_text(text: &str) -> String {
+    text.chars().filter(|c| !matches!(c, '\r' | '\n' | '\0')).collect()
+}
+
+// drop CR, LF and the rest of the control chars (colour codes and all)
+// from free text that's going to be relayed on to other people
+pub fn strip_control(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control()).collect()
+}
+
+// rfc 2812 3.3.1: a $/# mask has to have a toplevel domain, and no
+// wildcards in it, so nobody can go messaging `$*`
+pub fn has_toplevel(mask: &str) -> bool {
+    mask.contains('.')
+}
+
+pub fn wild_toplevel(mask: &str) -> bool {
+    match mask.rfind('.') {
+        Some(idx) => mask[idx..].contains(['*', '?']),
+        None => false,
+    }
+}
+
+// rfc 2812 2.2: because of IRC's Scandinavian origin, {}|^ are the lower
+// case equivalents of []\~, on top of the usual ASCII letters
+pub fn to_irc_lower(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '[' => '{',
+            ']' => '}',
+            '\\' => '|',
+            '~' => '^',
+            _ => c.to_ascii_lowercase(),
+        }).collect()
+}
+
+// IRCv3 tag keys are an optional '+' for client-only tags, an optional
+// vendor/ prefix, then letters, digits and hyphens
+pub fn valid_tag_key(key: &str) -> bool {
+    let key = key.strip_prefix('+').unwrap_or(key);
+    let name = match key.rfind('/') {
+        Some(idx) => {
+            if !valid_hostname(&key[..idx]) {
+                return false;
+            }
+            &key[idx + 1..]
+        },
+        None => key,
+    };
+    if name.is_empty() {
+        return false;
+    }
+    let mut allowed = String::new();
+    allowed.push_str(LETTER);
+    allowed.push_str(DIGIT);
+    allowed.push('-');
+    matches_allowed(name, &allowed)
+}
+
 // rfc states nick should be max 9 in length,
 // pretty sure I've seen far longer nicks on most IRC servers though
 pub fn valid_nick(nick: &str) -> bool {
-    if nick.len() > 9 || nick.is_empty() {
+    if nick.len() > MAX_NICK_LEN || nick.is_empty() {
         return false;
     }
 
     let mut allowed = String::new();
     allowed.push_str(LETTER);
     allowed.push_str(SPECIAL);
-    // nick has different rules for the first char
-    if !matches_allowed(&nick[..1], &allowed) {
+    // nick has different rules for the first char, which might not be
+    // ASCII so don't go slicing at 1 blindly
+    let first_len = nick.chars().next().map_or(0, char::len_utf8);
+    if !matches_allowed(&nick[..first_len], &allowed) {
         return false;
     }
 
@@ -242,3 +300,43 @@ pub fn valid_nick(nick: &str) -> bool {
     allowed.push_str("-");
     matches_allowed(&nick[1..], &allowed)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brackets_fold_with_the_letters() {
+        assert_eq!(to_irc_lower("Bob[AWAY]"), "bob{away}");
+        assert_eq!(to_irc_lower("a\\b~c"), "a|b^c");
+        assert_eq!(to_irc_lower("{}|^"), "{}|^");
+        assert_eq!(to_irc_lower("#Chan"), "#chan");
+        // nothing else outside ASCII gets touched
+        assert_eq!(to_irc_lower("ÉTÉ"), "ÉtÉ");
+    }
+
+    #[test]
+    fn channels_need_a_name_after_the_prefix() {
+        for name in ["#", "&", "+", "!", "!ABCDE", ""].iter() {
+            assert!(!valid_channel(name), "{:?}", name);
+        }
+        assert!(valid_channel("#ok"));
+        assert!(valid_channel("&ok"));
+        assert!(valid_channel("!ABCDEok"));
+        assert!(!valid_channel("#bad\x07name"));
+        assert!(!valid_channel("#bad name"));
+        assert!(!valid_channel("#bad,name"));
+        assert!(valid_channel("#é"));
+        // and a first char that's not ASCII isn't sliced through
+        assert!(!valid_channel("é"));
+    }
+
+    #[test]
+    fn embedded_line_breaks_are_stripped() {
+        assert_eq!(sanitize_message_text("hi\r\nKILL x"), "hiKILL x");
+        assert_eq!(sanitize_message_text("a\0b\rc\nd"), "abcd");
+        // colour codes and the like are fine in messages
+        assert_eq!(sanitize_message_text("\x034red\x03"), "\x034red\x03");
+        assert_eq!(strip_control("\x034red\x03\x07"), "4red");
+    }
+}
@@ -0,0 +1,311 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::client::Host;
+use crate::irc::chan::{ChanFlags, Channel, ChanTopic, MaskEntry};
+use crate::irc::{Core, NamedEntity, User};
+use crate::parser::parse_message;
+
+use log::debug;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::{error, fmt};
+
+/* dumping the namespace out to a blob of text and reading it back into a
+ * fresh Core, the first half of doing binary upgrades without dropping
+ * everyone. Each record is a line shaped like an IRC message, so the
+ * usual parser can read it back:
+ *
+ *   USER <id> <nick> <user> <host> <server> <oper 0|1> <token|*> :<real name>
+ *   AWAY <id> :<message>
+ *   CHAN <chan> <modes> [<key>] [<limit>]
+ *   TOPIC <chan> <set by> <time> :<text>
+ *   MASK <chan> <b|e|I> <mask> <set by> <time>
+ *   MEMBER <chan> <id> <@|+|->
+ *
+ * What doesn't make it across: the sockets themselves (so every user
+ * comes back parked and needs a token to RESUME with - anyone without one
+ * is dropped again on import, and with RESUME_WINDOW_SECS at 0 they all
+ * expire on the next ping sweep), pending INVITEs, and whatever is still
+ * sitting in send queues */
+
+#[derive(Debug)]
+pub enum StateError {
+    BadRecord(String),
+    UnknownUser(u64),
+    UnknownChan(String),
+    NameTaken(String),
+}
+
+impl error::Error for StateError {}
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadRecord(line) => write!(f, "bad state record: {}", line),
+            StateError::UnknownUser(id) => write!(f, "state refers to unknown user id {}", id),
+            StateError::UnknownChan(chan) => write!(f, "state refers to unknown channel {}", chan),
+            StateError::NameTaken(name) => write!(f, "name {} from state is already taken", name),
+        }
+    }
+}
+
+pub fn export(irc: &Core) -> String {
+    let mut lines = Vec::new();
+    for user in irc.all_users().iter() {
+        let id = user.get_id();
+        lines.push(format!(
+            "USER {} {} {} {} {} {} {} :{}",
+            id,
+            user.get_nick(),
+            user.get_username(),
+            user.get_real_host_string(),
+            user.get_server(),
+            if user.is_oper() { 1 } else { 0 },
+            user.get_resume_token().unwrap_or_else(|| String::from("*")),
+            user.get_realname()
+        ));
+        if let Some(away_msg) = user.get_away() {
+            lines.push(format!("AWAY {} :{}", id, away_msg));
+        }
+    }
+    for chan in irc.list_chans_ptr().iter() {
+        let name = chan.get_name();
+        lines.push(format!("CHAN {} {}", name, chan.gen_mode_string(true)));
+        if let Some(topic) = chan.get_topic() {
+            lines.push(format!("TOPIC {} {} {} :{}", name, topic.usermask, topic.timestamp, topic.text));
+        }
+        for letter in ['b', 'e', 'I'].iter() {
+            for entry in chan.get_mask_list(*letter).iter() {
+                lines.push(format!("MASK {} {} {} {} {}", name, letter, entry.mask, entry.set_by, entry.timestamp));
+            }
+        }
+        for (id, flags) in chan.get_member_ids().iter() {
+            let badge = match flags {
+                ChanFlags::OpVoice => "@+",
+                ChanFlags::Op => "@",
+                ChanFlags::Voice => "+",
+                ChanFlags::None => "-",
+            };
+            lines.push(format!("MEMBER {} {} {}", name, id, badge));
+        }
+    }
+    let mut blob = lines.join("\n");
+    blob.push('\n');
+    blob
+}
+
+/* rebuild everything in the blob into irc, handing back how many users
+ * were restored. If a record is bad, whatever was imported before it is
+ * thrown away again */
+pub fn import(irc: &Arc<Core>, blob: &str) -> Result<usize, StateError> {
+    let mut users = HashMap::new();
+    let mut chans = Vec::new();
+    let result = read_records(irc, blob, &mut users, &mut chans);
+    let mut n_users = 0;
+    if result.is_ok() {
+        for (_id, user) in users.drain() {
+            if user.get_resume_token().is_some() {
+                n_users += 1;
+            } else {
+                debug!("no resume token for restored user {}, dropping", user.get_nick());
+            }
+            irc.park_user(user);
+        }
+    }
+    /* anyone left over drops here, taking themselves out of their
+     * channels, but channels nobody was in (and aren't +P) have to be
+     * swept up by hand */
+    drop(users);
+    for chan in chans.iter().filter(|chan| chan.is_disposable()) {
+        let _res = irc.remove_empty_chan(chan);
+    }
+    result.map(|_| n_users)
+}
+
+fn read_records(
+    irc: &Arc<Core>,
+    blob: &str,
+    users: &mut HashMap<u64, Arc<User>>,
+    chans: &mut Vec<Arc<Channel>>,
+) -> Result<(), StateError> {
+    for line in blob.lines().filter(|line| !line.is_empty()) {
+        let bad = || StateError::BadRecord(line.to_string());
+        let msg = parse_message(line).map_err(|_err| bad())?;
+        let params = msg.opt_params;
+        match (msg.command.as_str(), params.len()) {
+            ("USER", 8) => {
+                let id = params[0].parse::<u64>().map_err(|_err| bad())?;
+                let host = match params[3].parse::<IpAddr>() {
+                    Ok(ip_addr) => Host::HostAddr(ip_addr),
+                    Err(_err) => Host::Hostname(params[3].clone()),
+                };
+                let user = User::restore(
+                    id,
+                    irc,
+                    params[1].clone(),
+                    params[2].clone(),
+                    params[7].clone(),
+                    host,
+                    params[4].clone(),
+                );
+                user.set_oper(params[5] == "1");
+                if params[6] != "*" {
+                    user.set_resume_token(Some(params[6].clone()));
+                }
+                irc.insert_name(&params[1], NamedEntity::User(Arc::downgrade(&user)))
+                    .map_err(|_err| StateError::NameTaken(params[1].clone()))?;
+                irc.reserve_id(id);
+                users.insert(id, user);
+            },
+            ("AWAY", 2) => {
+                let id = params[0].parse::<u64>().map_err(|_err| bad())?;
+                let user = users.get(&id).ok_or(StateError::UnknownUser(id))?;
+                user.set_away(Some(params[1].clone()));
+            },
+            ("CHAN", 2..=4) => {
+                let chan = Arc::new(Channel::new(irc, &params[0]));
+                irc.insert_name(&params[0], NamedEntity::Chan(Arc::clone(&chan)))
+                    .map_err(|_err| StateError::NameTaken(params[0].clone()))?;
+                chans.push(Arc::clone(&chan));
+                /* new channels come with the defaults, so clear those first */
+                for letter in "tmins".chars() {
+                    chan.set_mode_flag(letter, false);
+                }
+                /* k and l take their arguments in the order the letters come */
+                let mut args = params[2..].iter();
+                for letter in params[1].chars() {
+                    match letter {
+                        'k' => chan.set_key(Some(args.next().ok_or_else(bad)?.clone())),
+                        'l' => {
+                            let limit = args.next().ok_or_else(bad)?.parse::<usize>().map_err(|_err| bad())?;
+                            chan.set_limit(Some(limit));
+                        },
+                        _ => {
+                            chan.set_mode_flag(letter, true);
+                        },
+                    }
+                }
+            },
+            ("TOPIC", 4) => {
+                let chan = find_chan(chans, &params[0])?;
+                chan.restore_topic(ChanTopic {
+                    text: params[3].clone(),
+                    usermask: params[1].clone(),
+                    timestamp: params[2].parse::<i64>().map_err(|_err| bad())?,
+                });
+            },
+            ("MASK", 5) => {
+                let chan = find_chan(chans, &params[0])?;
+                let letter = match params[1].as_str() {
+                    "b" => 'b',
+                    "e" => 'e',
+                    "I" => 'I',
+                    _ => return Err(bad()),
+                };
+                chan.restore_mask(letter, MaskEntry {
+                    mask: params[2].clone(),
+                    set_by: params[3].clone(),
+                    timestamp: params[4].parse::<i64>().map_err(|_err| bad())?,
+                });
+            },
+            ("MEMBER", 3) => {
+                let chan = find_chan(chans, &params[0])?;
+                let id = params[1].parse::<u64>().map_err(|_err| bad())?;
+                let user = users.get(&id).ok_or(StateError::UnknownUser(id))?;
+                let flags = match params[2].as_str() {
+                    "@+" => ChanFlags::OpVoice,
+                    "@" => ChanFlags::Op,
+                    "+" => ChanFlags::Voice,
+                    "-" => ChanFlags::None,
+                    _ => return Err(bad()),
+                };
+                chan.link_user(user, flags);
+            },
+            _ => return Err(bad()),
+        }
+    }
+    Ok(())
+}
+
+fn find_chan<'a>(chans: &'a [Arc<Channel>], name: &str) -> Result<&'a Arc<Channel>, StateError> {
+    chans
+        .iter()
+        .find(|chan| chan.get_name() == name)
+        .ok_or_else(|| StateError::UnknownChan(name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc::testing::{any_has, config, core_with, TestClient};
+    use crate::irc::Config;
+
+    fn resumable() -> Arc<Core> {
+        core_with(Config { resume_window_secs: 60, ..config() })
+    }
+
+    /* records come out in whatever order the namespace has them */
+    fn sorted(blob: &str) -> Vec<&str> {
+        let mut lines: Vec<&str> = blob.lines().collect();
+        lines.sort_unstable();
+        lines
+    }
+
+    #[tokio::test]
+    async fn state_survives_a_round_trip() {
+        let irc = resumable();
+        let (mut alice, token) = TestClient::register_resumable(&irc, "alice").await;
+        let (mut bob, _token) = TestClient::register_resumable(&irc, "bob").await;
+        alice.ask("JOIN #a,#b").await;
+        alice.ask("TOPIC #a :the topic").await;
+        alice.ask("MODE #a +ksl key 5").await;
+        alice.ask("MODE #a +b bad!*@*").await;
+        alice.ask("AWAY :out to lunch").await;
+        bob.ask("JOIN #a").await;
+        alice.ask("MODE #a +v bob").await;
+        let blob = export(&irc);
+        assert!(blob.contains("AWAY "), "{}", blob);
+        assert!(blob.contains("MASK #a b bad!*@* alice!alice@127.0.0.1 "), "{}", blob);
+
+        let restored = resumable();
+        assert_eq!(import(&restored, &blob).unwrap(), 2);
+        assert_eq!(sorted(&export(&restored)), sorted(&blob));
+
+        /* and the users are there waiting to be picked up */
+        let mut alice = TestClient::connect(&restored).await;
+        let lines = alice.ask(&format!("RESUME {}", token)).await;
+        assert!(any_has(&lines, &["RESUME SUCCESS alice"]), "{:?}", lines);
+        assert!(any_has(&lines, &[" 332 ", "#a", ":the topic"]), "{:?}", lines);
+        assert!(any_has(&lines, &[" 324 ", "#a", "+nstkl key 5"]), "{:?}", lines);
+        assert!(any_has(&lines, &[" 353 ", "#a", "@alice"]), "{:?}", lines);
+        assert!(any_has(&lines, &[":alice!", "JOIN #b"]), "{:?}", lines);
+    }
+
+    /* a bad record anywhere and nothing's left behind */
+    #[tokio::test]
+    async fn bad_state_imports_nothing() {
+        let blob = "USER 5 alice alice 127.0.0.1 irc.test 0 tok :Real alice\n\
+                    CHAN #a nt\n\
+                    MEMBER #a 5 @\n\
+                    MEMBER #a 6 -\n";
+        let irc = resumable();
+        assert!(matches!(import(&irc, blob), Err(StateError::UnknownUser(6))));
+        assert_eq!(irc.user_count(), 0);
+        assert_eq!(irc.channel_count(), 0);
+        assert!(matches!(import(&irc, "NONSENSE\n"), Err(StateError::BadRecord(_))));
+    }
+}
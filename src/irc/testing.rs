@@ -0,0 +1,178 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* bits and pieces for the tests: a Core with settings that stay out of the
+ * way, and a client that talks to it over a real socket, the same as
+ * main.rs sets one up but without the DNS lookup */
+use crate::client::{run_client_handler, run_write_task, Host};
+use crate::io::{ReadHalfWrap, WriteHalfWrap};
+use crate::irc::{password, Config, Core};
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+pub const SERVER: &str = "irc.test";
+/* long enough that nothing should ever get near it, a test that does
+ * has hung */
+const WAIT: Duration = Duration::from_secs(10);
+
+/* the defaults, except flood control's turned right up so tests can send
+ * as fast as they like, and every Core gets an audit log of its own */
+pub fn config() -> Config {
+    static N_LOGS: AtomicU64 = AtomicU64::new(0);
+    let log_name = format!("rusty-ircd-test-{}-{}.log", std::process::id(), N_LOGS.fetch_add(1, Ordering::Relaxed));
+    Config {
+        flood_rate: 1_000_000.0,
+        flood_burst: 1_000_000.0,
+        audit_log_path: env::temp_dir().join(log_name).to_string_lossy().into_owned(),
+        ..Config::default()
+    }
+}
+
+pub fn core() -> Arc<Core> {
+    core_with(config())
+}
+
+pub fn core_with(config: Config) -> Arc<Core> {
+    Core::new(SERVER.to_string(), String::from("rusty-ircd-test"), config)
+}
+
+/* an oper with the password "pw", hashed with the one round since tests
+ * have no need to be slow */
+pub fn with_oper(mut config: Config, name: &str) -> Config {
+    config.opers.insert(name.to_string(), password::hash_with_salt("pw", b"salt", 1));
+    config
+}
+
+pub struct TestClient {
+    lines: Lines<BufReader<ReadHalf<TcpStream>>>,
+    write: WriteHalf<TcpStream>,
+    n_syncs: u64,
+}
+
+impl TestClient {
+    pub async fn connect(irc: &Arc<Core>) -> TestClient {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (sock, addr) = listener.accept().await.unwrap();
+        let (tx, rx) = mpsc::channel(32);
+        let (read, write) = split(sock);
+        tokio::spawn(run_write_task(WriteHalfWrap::ClearText(write), rx));
+        tokio::spawn(run_client_handler(
+            irc.assign_id(),
+            Host::HostAddr(addr.ip()),
+            Arc::clone(irc),
+            tx,
+            ReadHalfWrap::ClearText(read),
+        ));
+        let (read, write) = split(stream);
+        TestClient { lines: BufReader::new(read).lines(), write, n_syncs: 0 }
+    }
+
+    /* connected, through NICK and USER, and with the welcome burst read */
+    pub async fn register(irc: &Arc<Core>, nick: &str) -> TestClient {
+        let mut client = TestClient::connect(irc).await;
+        client.send(&format!("NICK {}", nick)).await;
+        client.send(&format!("USER {} 0 * :Real {}", nick, nick)).await;
+        client.expect(" 001 ").await;
+        client.sync().await;
+        client
+    }
+
+    /* the same, with session resumption on, handing back the RESUME token */
+    pub async fn register_resumable(irc: &Arc<Core>, nick: &str) -> (TestClient, String) {
+        let mut client = TestClient::connect(irc).await;
+        client.send(&format!("NICK {}", nick)).await;
+        client.send(&format!("USER {} 0 * :Real {}", nick, nick)).await;
+        let line = client.expect(" RESUME TOKEN ").await;
+        client.sync().await;
+        let token = line.rsplit(' ').next().unwrap().to_string();
+        (client, token)
+    }
+
+    pub async fn send(&mut self, line: &str) {
+        self.send_raw(format!("{}\r\n", line).as_bytes()).await;
+    }
+
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
+        self.write.write_all(bytes).await.unwrap();
+    }
+
+    /* None once the server's hung up */
+    pub async fn next_line(&mut self) -> Option<String> {
+        match timeout(WAIT, self.lines.next_line()).await {
+            Ok(Ok(line)) => line,
+            Ok(Err(_err)) => None,
+            Err(_elapsed) => panic!("nothing from the server in {:?}", WAIT),
+        }
+    }
+
+    /* read up to and including the first line with `needle` in it */
+    pub async fn expect(&mut self, needle: &str) -> String {
+        let mut seen = Vec::new();
+        while let Some(line) = self.next_line().await {
+            if line.contains(needle) {
+                return line;
+            }
+            seen.push(line);
+        }
+        panic!("connection closed waiting for {:?}, got {:?}", needle, seen);
+    }
+
+    /* everything the server's sent that we haven't read yet. A PING goes
+     * out and everything up to its PONG comes back, which works because
+     * a connection's commands are handled in order, so once the PONG's
+     * here so is anything earlier commands sent us */
+    pub async fn sync(&mut self) -> Vec<String> {
+        self.n_syncs += 1;
+        let token = format!("sync-{}", self.n_syncs);
+        self.send(&format!("PING :{}", token)).await;
+        let pong = format!(" PONG {} :{}", SERVER, token);
+        let mut lines = Vec::new();
+        while let Some(line) = self.next_line().await {
+            if line.ends_with(&pong) {
+                return lines;
+            }
+            lines.push(line);
+        }
+        panic!("connection closed waiting for {}, got {:?}", token, lines);
+    }
+
+    /* send a line and get back whatever it caused */
+    pub async fn ask(&mut self, line: &str) -> Vec<String> {
+        self.send(line).await;
+        self.sync().await
+    }
+
+    /* everything left before the server hangs up */
+    pub async fn until_closed(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Some(line) = self.next_line().await {
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+/* whether any of the lines has all of the needles in it */
+pub fn any_has(lines: &[String], needles: &[&str]) -> bool {
+    lines.iter().any(|line| needles.iter().all(|needle| line.contains(needle)))
+}
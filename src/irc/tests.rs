@@ -0,0 +1,1471 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+/* end to end tests, real clients on real sockets talking to a Core */
+use super::PARAM_COUNTS;
+use super::testing::{any_has, config, core, core_with, with_oper, TestClient};
+use crate::client::{Client, GenError};
+use crate::irc::chan::Channel;
+use crate::irc::event::Observer;
+use crate::irc::{ircError, password, Config, Core, User};
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::time;
+
+fn user(irc: &Core, nick: &str) -> Arc<User> {
+    irc.get_nick(nick).and_then(|weak| Weak::upgrade(&weak)).unwrap()
+}
+
+/* a channel that lists a user who doesn't list it back gets them taken
+ * off, and then goes itself since it's empty. A channel the user lists
+ * that's long gone gets dropped from their list */
+#[tokio::test]
+async fn consistency_check_repairs_one_sided_membership() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    alice.ask("JOIN #a").await;
+    assert_eq!(irc.check_consistency(), 0);
+
+    let user = user(&irc, "alice");
+    user.channel_list.lock().unwrap().remove("#a");
+    user.channel_list.lock().unwrap().insert(String::from("#ghost"), Weak::new());
+    /* #ghost, alice on #a, and then #a itself */
+    assert_eq!(irc.check_consistency(), 3);
+    assert!(irc.get_chan("#a").is_err());
+    assert_eq!(irc.check_consistency(), 0);
+}
+
+#[tokio::test]
+async fn stats_c_reports_repairs_to_opers_only() {
+    let irc = core_with(with_oper(config(), "op"));
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let lines = alice.ask("STATS c").await;
+    assert!(any_has(&lines, &[" 481 "]), "{:?}", lines);
+
+    alice.ask("OPER op pw").await;
+    alice.ask("JOIN #a").await;
+    let user = user(&irc, "alice");
+    user.channel_list.lock().unwrap().remove("#a");
+    let lines = alice.ask("STATS c").await;
+    assert!(any_has(&lines, &["NOTICE alice", "made 2 repairs"]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 219 "]), "{:?}", lines);
+    let lines = alice.ask("STATS c").await;
+    assert!(any_has(&lines, &["made 0 repairs"]), "{:?}", lines);
+}
+
+/* keys go to the channels in the same positions, an empty one or one
+ * that's missing off the end is no key, and spares are ignored */
+#[tokio::test]
+async fn join_keys_are_positional() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    alice.ask("JOIN #a,#b,#c").await;
+    alice.ask("MODE #a +k k1").await;
+    alice.ask("MODE #b +k k2").await;
+    alice.ask("MODE #c +k k3").await;
+
+    let lines = bob.ask("JOIN #a,#b,#c k1,,k3").await;
+    assert!(any_has(&lines, &[":bob!", "JOIN #a"]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 475 ", "#b"]), "{:?}", lines);
+    assert!(any_has(&lines, &[":bob!", "JOIN #c"]), "{:?}", lines);
+    assert!(!any_has(&lines, &[":bob!", "JOIN #b"]), "{:?}", lines);
+
+    bob.ask("PART #a,#c").await;
+    let lines = bob.ask("JOIN #a,#c k1").await;
+    assert!(any_has(&lines, &[":bob!", "JOIN #a"]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 475 ", "#c"]), "{:?}", lines);
+
+    let lines = bob.ask("JOIN #d k1,k2,k3").await;
+    assert!(any_has(&lines, &[":bob!", "JOIN #d"]), "{:?}", lines);
+    assert_eq!(lines.iter().filter(|line| line.contains(" 475 ")).count(), 0, "{:?}", lines);
+}
+
+/* writes down everything it's told about */
+#[derive(Debug, Default)]
+struct Recorder {
+    events: Mutex<Vec<String>>,
+}
+
+impl Observer for Recorder {
+    fn on_connect(&self, _client: &Client) {
+        self.events.lock().unwrap().push(String::from("connect"));
+    }
+
+    fn on_register(&self, user: &User) {
+        let event = format!("register {} {} {} {}", user.get_nick(), user.get_username(), user.get_host_string(), user.get_realname());
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn on_join(&self, user: &User, chan: &Channel) {
+        self.events.lock().unwrap().push(format!("join {} {}", user.get_nick(), chan.get_name()));
+    }
+
+    fn on_quit(&self, user: &User, msg: &str) {
+        self.events.lock().unwrap().push(format!("quit {} {}", user.get_nick(), msg));
+    }
+}
+
+#[tokio::test]
+async fn observers_hear_about_each_step() {
+    let irc = core();
+    let recorder = Arc::new(Recorder::default());
+    irc.subscribe(Arc::clone(&recorder) as Arc<dyn Observer>);
+    let mut alice = TestClient::register(&irc, "alice").await;
+    alice.ask("JOIN #a").await;
+    alice.send("QUIT :bye").await;
+    alice.until_closed().await;
+    assert_eq!(
+        *recorder.events.lock().unwrap(),
+        vec![
+            String::from("connect"),
+            String::from("register alice alice 127.0.0.1 Real alice"),
+            String::from("join alice #a"),
+            String::from("quit alice Quit: bye"),
+        ]
+    );
+}
+
+/* hang up on the server and wait for it to park the user */
+async fn drop_connection(irc: &Core, client: TestClient, nick: &str) {
+    let id = user(irc, nick).get_id();
+    drop(client);
+    for _i in 0..500 {
+        if irc.is_parked(id) {
+            return;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{} never got parked", nick);
+}
+
+fn resumable_config() -> Config {
+    Config { resume_window_secs: 60, ..config() }
+}
+
+#[tokio::test]
+async fn resync_replays_every_channel() {
+    let irc = core_with(resumable_config());
+    let (mut alice, token) = TestClient::register_resumable(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    alice.ask("JOIN #a,#b").await;
+    alice.ask("TOPIC #a :the topic").await;
+    alice.ask("MODE #b +n").await;
+    bob.ask("JOIN #a").await;
+    drop_connection(&irc, alice, "alice").await;
+
+    let mut alice = TestClient::connect(&irc).await;
+    let lines = alice.ask(&format!("RESUME {}", token)).await;
+    assert!(any_has(&lines, &["RESUME SUCCESS alice"]), "{:?}", lines);
+    for chan in ["#a", "#b"].iter() {
+        assert!(any_has(&lines, &[":alice!", &format!("JOIN {}", chan)]), "{:?}", lines);
+        assert!(any_has(&lines, &[" 324 ", chan]), "{:?}", lines);
+        assert!(any_has(&lines, &[" 329 ", chan]), "{:?}", lines);
+        assert!(any_has(&lines, &[" 366 ", chan]), "{:?}", lines);
+    }
+    assert!(any_has(&lines, &[" 332 ", "#a", ":the topic"]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 353 ", "#a", "@alice", "bob"]), "{:?}", lines);
+
+    /* nothing's been joined twice over */
+    let lines = bob.ask("NAMES #a").await;
+    let names = lines.iter().find(|line| line.contains(" 353 ")).unwrap();
+    assert_eq!(names.matches("alice").count(), 1, "{:?}", lines);
+    assert_eq!(irc.get_chan("#a").unwrap().get_n_users(), 2);
+}
+
+#[tokio::test]
+async fn joining_a_case_variant_is_a_no_op() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    alice.ask("JOIN #foo[x]").await;
+    let lines = alice.ask("JOIN #FOO{X}").await;
+    assert!(lines.is_empty(), "{:?}", lines);
+    assert_eq!(irc.channel_count(), 1);
+    assert_eq!(irc.get_chan("#Foo{x}").unwrap().get_n_users(), 1);
+    assert_eq!(user(&irc, "alice").count_channels(), 1);
+}
+
+/* every command one short of its minimum gets the same 461, named the
+ * way the table names it whatever case it was sent in */
+#[tokio::test]
+async fn too_few_params_is_always_461() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    for (name, min, _max) in PARAM_COUNTS.iter().filter(|(_name, min, _max)| *min > 0) {
+        let mut line = name.to_ascii_lowercase();
+        for _i in 1..*min {
+            line.push_str(" x");
+        }
+        let lines = alice.ask(&line).await;
+        assert_eq!(lines, vec![format!(":irc.test 461 {} :Not enough parameters", name)], "{}", line);
+    }
+}
+
+#[tokio::test]
+async fn opers_can_message_by_host_and_server_mask() {
+    let irc = core_with(with_oper(config(), "op"));
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let lines = alice.ask("PRIVMSG $#*.0.1 :hello").await;
+    assert!(any_has(&lines, &[" 481 "]), "{:?}", lines);
+    assert!(bob.sync().await.is_empty());
+
+    alice.ask("OPER op pw").await;
+    alice.ask("PRIVMSG $#*.0.1 :by host").await;
+    alice.ask("PRIVMSG $#*.example.com :nobody").await;
+    alice.ask("PRIVMSG $$*.test :by server").await;
+    let lines = bob.sync().await;
+    assert_eq!(
+        lines,
+        vec![
+            String::from(":alice!alice@127.0.0.1 PRIVMSG $#*.0.1 :by host"),
+            String::from(":alice!alice@127.0.0.1 PRIVMSG $$*.test :by server"),
+        ]
+    );
+
+    let lines = alice.ask("PRIVMSG $#localhost :no toplevel").await;
+    assert!(any_has(&lines, &[" 413 "]), "{:?}", lines);
+    let lines = alice.ask("PRIVMSG $#*.* :wild toplevel").await;
+    assert!(any_has(&lines, &[" 414 "]), "{:?}", lines);
+}
+
+#[tokio::test]
+async fn resume_takes_back_the_session() {
+    let irc = core_with(resumable_config());
+    let (mut alice, token) = TestClient::register_resumable(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    alice.ask("JOIN #a").await;
+    bob.ask("JOIN #a").await;
+    let id = user(&irc, "alice").get_id();
+    drop_connection(&irc, alice, "alice").await;
+    /* a parked user hasn't quit as far as anyone else can tell */
+    assert!(bob.sync().await.is_empty());
+
+    let mut mallory = TestClient::connect(&irc).await;
+    let lines = mallory.ask("RESUME 0123456789abcdef0123456789abcdef").await;
+    assert!(any_has(&lines, &["FAIL RESUME INVALID_TOKEN"]), "{:?}", lines);
+
+    let mut alice = TestClient::connect(&irc).await;
+    let lines = alice.ask(&format!("RESUME {}", token)).await;
+    assert!(any_has(&lines, &["RESUME SUCCESS alice"]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 001 alice "]), "{:?}", lines);
+    assert!(any_has(&lines, &[":alice!", "JOIN #a"]), "{:?}", lines);
+    /* and a fresh token, the old one's used up */
+    assert!(any_has(&lines, &["RESUME TOKEN "]), "{:?}", lines);
+    assert!(!any_has(&lines, &[&token]), "{:?}", lines);
+    assert_eq!(user(&irc, "alice").get_id(), id);
+    assert!(!irc.is_parked(id));
+
+    let lines = mallory.ask(&format!("RESUME {}", token)).await;
+    assert!(any_has(&lines, &["FAIL RESUME INVALID_TOKEN"]), "{:?}", lines);
+
+    alice.ask("PRIVMSG #a :back").await;
+    let lines = bob.sync().await;
+    assert_eq!(lines, vec![String::from(":alice!alice@127.0.0.1 PRIVMSG #a :back")]);
+}
+
+/* RESUME_WINDOW_SECS = 0, the default, is off: no token, and a dropped
+ * connection's user goes rather than being parked */
+#[tokio::test]
+async fn resume_is_off_with_no_window() {
+    let irc = core();
+    let mut alice = TestClient::connect(&irc).await;
+    alice.send("NICK alice").await;
+    alice.send("USER alice 0 * :Real alice").await;
+    let lines = alice.sync().await;
+    assert!(any_has(&lines, &[" 001 "]), "{:?}", lines);
+    assert!(!any_has(&lines, &["RESUME"]), "{:?}", lines);
+
+    let weak = irc.get_nick("alice").unwrap();
+    drop(alice);
+    for _i in 0..500 {
+        if weak.strong_count() == 0 {
+            return;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("alice is still around");
+}
+
+fn audit_records(irc: &Core) -> Vec<String> {
+    let path = irc.config.read().unwrap().audit_log_path.clone();
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.split_once(' ').unwrap().1.to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn oper_actions_are_audited() {
+    let irc = core_with(with_oper(config(), "op"));
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    alice.ask("OPER op wrong").await;
+    alice.ask("OPER op pw").await;
+    alice.ask("KILL bob :go away").await;
+    bob.until_closed().await;
+    alice.ask("WALLOPS :hi").await;
+    alice.ask("STATS c").await;
+    assert_eq!(
+        audit_records(&irc),
+        vec![
+            String::from("actor=alice!alice@127.0.0.1 action=OPER-FAILED target=op"),
+            String::from("actor=alice!alice@127.0.0.1 action=OPER target=op"),
+            String::from("actor=alice!alice@127.0.0.1 action=KILL target=bob"),
+            String::from("actor=alice!alice@127.0.0.1 action=WALLOPS target=*"),
+            String::from("actor=alice!alice@127.0.0.1 action=STATS target=c"),
+        ]
+    );
+}
+
+/* no record, no action */
+#[tokio::test]
+async fn oper_actions_need_the_audit_log() {
+    let irc = core_with(with_oper(config(), "op"));
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    alice.ask("OPER op pw").await;
+    let broken = Config {
+        audit_log_path: String::from("/nonexistent/rusty-ircd/audit.log"),
+        ..irc.config.read().unwrap().clone()
+    };
+    irc.apply_config(broken);
+    let lines = alice.ask("KILL bob :go away").await;
+    assert!(any_has(&lines, &[" 424 ", "/nonexistent/rusty-ircd/audit.log"]), "{:?}", lines);
+    assert!(bob.sync().await.is_empty());
+    assert!(irc.get_nick("bob").is_some());
+}
+
+/* the default policy, Lossy, patches bad bytes up with U+FFFD on the way
+ * in, so nobody's passed on anything that isn't UTF-8 */
+#[tokio::test]
+async fn invalid_utf8_is_patched_up() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    alice.send_raw(b"PRIVMSG bob :caf\xe9!\r\n").await;
+    alice.sync().await;
+    let lines = bob.sync().await;
+    assert_eq!(lines, vec![String::from(":alice!alice@127.0.0.1 PRIVMSG bob :caf\u{fffd}!")]);
+}
+
+/* each list shows who set an entry and when */
+#[tokio::test]
+async fn mask_lists_show_setter_and_time() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    alice.ask("JOIN #a").await;
+    let before = Utc::now().timestamp();
+    alice.ask("MODE #a +b bad!*@*").await;
+    alice.ask("MODE #a +e good!*@*").await;
+    alice.ask("MODE #a +I friend!*@*").await;
+    let after = Utc::now().timestamp();
+    for (letter, numeric, mask) in [("b", "367", "bad!*@*"), ("e", "348", "good!*@*"), ("I", "346", "friend!*@*")].iter() {
+        let lines = alice.ask(&format!("MODE #a {}", letter)).await;
+        let entry = lines.iter().find(|line| line.contains(&format!(" {} ", numeric))).unwrap();
+        let fields: Vec<&str> = entry.split(' ').collect();
+        assert_eq!(fields[3..6], ["#a", mask, "alice!alice@127.0.0.1"], "{:?}", lines);
+        let stamp: i64 = fields[6].parse().unwrap();
+        assert!(before <= stamp && stamp <= after, "{:?}", lines);
+    }
+}
+
+#[tokio::test]
+async fn missing_targets_get_the_right_numeric() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let lines = alice.ask("PRIVMSG ##nonexistent :hi").await;
+    assert_eq!(lines, vec![String::from(":irc.test 403 ##nonexistent :No such channel")]);
+    let lines = alice.ask("NOTICE &nope :hi").await;
+    assert_eq!(lines, vec![String::from(":irc.test 403 &nope :No such channel")]);
+    let lines = alice.ask("PRIVMSG nobody :hi").await;
+    assert_eq!(lines, vec![String::from(":irc.test 401 nobody :No such nick/channel")]);
+}
+
+/* CAP after registering answers the same, and doesn't put anything on hold */
+#[tokio::test]
+async fn cap_after_registration() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let lines = alice.ask("CAP LS 302").await;
+    assert_eq!(lines, vec![String::from(":irc.test CAP alice LS :multi-prefix")]);
+    let lines = alice.ask("CAP LIST").await;
+    assert_eq!(lines, vec![String::from(":irc.test CAP alice LIST :")]);
+    let lines = alice.ask("CAP REQ :multi-prefix").await;
+    assert_eq!(lines, vec![String::from(":irc.test CAP alice ACK :multi-prefix")]);
+    let lines = alice.ask("CAP LIST").await;
+    assert_eq!(lines, vec![String::from(":irc.test CAP alice LIST :multi-prefix")]);
+    alice.ask("PRIVMSG bob :still here").await;
+    assert_eq!(bob.sync().await, vec![String::from(":alice!alice@127.0.0.1 PRIVMSG bob :still here")]);
+    let lines = alice.ask("CAP REQ :-multi-prefix").await;
+    assert_eq!(lines, vec![String::from(":irc.test CAP alice ACK :-multi-prefix")]);
+    let lines = alice.ask("CAP LIST").await;
+    assert_eq!(lines, vec![String::from(":irc.test CAP alice LIST :")]);
+}
+
+/* nicks and channels go by rfc1459 casemapping, where []\~ are the upper
+ * case of {}|^ */
+#[tokio::test]
+async fn names_fold_rfc1459_case() {
+    let irc = core();
+    let mut bob = TestClient::register(&irc, "Bob[1]").await;
+    let mut stranger = TestClient::connect(&irc).await;
+    let lines = stranger.ask("NICK bob{1}").await;
+    assert!(any_has(&lines, &[" 433 ", "bob{1}"]), "{:?}", lines);
+    let _other = TestClient::register(&irc, "x\\").await;
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let lines = alice.ask("NICK X|").await;
+    assert!(any_has(&lines, &[" 433 ", "X|"]), "{:?}", lines);
+
+    /* a change of case is no collision with yourself, and shows up */
+    let lines = bob.ask("NICK bOB{1}").await;
+    assert_eq!(lines, vec![String::from(":Bob[1]!Bob[1]@127.0.0.1 NICK :bOB{1}")]);
+    assert_eq!(irc.get_nick("BOB[1]").unwrap().upgrade().unwrap().get_nick(), "bOB{1}");
+
+    bob.ask("JOIN #Chan[x]~").await;
+    let lines = alice.ask("JOIN #chan{X}^").await;
+    assert!(any_has(&lines, &[":alice!", "JOIN #Chan[x]~"]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 353 ", "@bOB{1} alice"]), "{:?}", lines);
+    assert_eq!(irc.channel_count(), 1);
+}
+
+/* past max_targets the rest of the list isn't even looked at, the first
+ * one over gets a 407 and that's it */
+#[tokio::test]
+async fn long_target_lists_are_capped() {
+    let irc = core_with(Config { max_targets: 20, ..config() });
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let targets: Vec<String> = (0..100).map(|n| format!("#{}", n)).collect();
+    let lines = alice.ask(&format!("JOIN {}", targets.join(","))).await;
+    assert_eq!(lines.iter().filter(|line| line.starts_with(":alice!") && line.contains(" JOIN ")).count(), 20);
+    assert_eq!(lines.iter().filter(|line| line.contains(" 407 ")).count(), 1, "{:?}", lines);
+    assert!(any_has(&lines, &[":irc.test 407 #20 :Too many recipients"]), "{:?}", lines);
+    assert_eq!(irc.channel_count(), 20);
+    assert!(irc.get_chan("#21").is_err());
+
+    let lines = alice.ask(&format!("PART {}", targets.join(","))).await;
+    assert_eq!(lines.iter().filter(|line| line.starts_with(":alice!") && line.contains(" PART ")).count(), 20);
+    assert_eq!(lines.iter().filter(|line| line.contains(" 407 ")).count(), 1, "{:?}", lines);
+    assert_eq!(irc.channel_count(), 0);
+}
+
+/* an invite's only good for invite_expiry_secs, here nothing at all to
+ * start with, and pruning only clears out the stale ones */
+#[tokio::test]
+async fn stale_invites_dont_get_past_plus_i() {
+    let irc = core_with(Config { invite_expiry_secs: 0, ..config() });
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    alice.ask("JOIN #a").await;
+    alice.ask("MODE #a +i").await;
+    alice.ask("INVITE bob #a").await;
+    let lines = bob.ask("JOIN #a").await;
+    assert!(any_has(&lines, &[" 473 ", "#a"]), "{:?}", lines);
+
+    let fresh = Config { invite_expiry_secs: 60, ..irc.config.read().unwrap().clone() };
+    irc.apply_config(fresh);
+    alice.ask("INVITE bob #a").await;
+    irc.prune_invites();
+    let lines = bob.ask("JOIN #a").await;
+    assert!(any_has(&lines, &[":bob!", "JOIN #a"]), "{:?}", lines);
+}
+
+/* VERSION is 351 and then the 005s from the welcome burst, whether or not
+ * you've registered */
+#[tokio::test]
+async fn version_repeats_isupport() {
+    let irc = core();
+    let mut alice = TestClient::connect(&irc).await;
+    alice.send("NICK alice").await;
+    alice.send("USER alice 0 * :Real alice").await;
+    alice.expect(" 001 ").await;
+    let burst: Vec<String> = alice.sync().await.into_iter().filter(|line| line.contains(" 005 ")).collect();
+    assert!(!burst.is_empty());
+
+    let lines = alice.ask("VERSION").await;
+    assert!(lines[0].starts_with(":irc.test 351 alice rusty-ircd-"), "{:?}", lines);
+    assert_eq!(lines[1..], burst[..]);
+
+    let mut stranger = TestClient::connect(&irc).await;
+    let lines = stranger.ask("VERSION").await;
+    assert!(lines[0].starts_with(":irc.test 351 * rusty-ircd-"), "{:?}", lines);
+    assert_eq!(lines.len(), burst.len() + 1, "{:?}", lines);
+    assert!(lines[1..].iter().all(|line| line.starts_with(":irc.test 005 * ")), "{:?}", lines);
+}
+
+/* network_name and server_info come from ircd.conf, and show up in the
+ * welcome, 005 and VERSION. A network name with a space in it is no good */
+#[tokio::test]
+async fn network_name_and_server_info_are_configurable() {
+    let parsed = Config::parse("ircd.conf", "network_name Testnet\nserver_info \"a test box\"\nnetwork_name Two Words\n");
+    assert_eq!(parsed.network_name, "Testnet");
+    assert_eq!(parsed.server_info, "a test box");
+    let irc = core_with(Config { network_name: parsed.network_name, server_info: parsed.server_info, ..config() });
+
+    let mut alice = TestClient::connect(&irc).await;
+    alice.send("NICK alice").await;
+    alice.send("USER alice 0 * :Real alice").await;
+    let line = alice.expect(" 001 ").await;
+    assert_eq!(line, ":irc.test 001 alice :Welcome to the Testnet IRC Network alice!alice@127.0.0.1");
+    let lines = alice.sync().await;
+    assert!(any_has(&lines, &[" 005 ", " NETWORK=Testnet "]), "{:?}", lines);
+    let lines = alice.ask("VERSION").await;
+    assert!(lines[0].ends_with(" :a test box"), "{:?}", lines);
+}
+
+/* cloak_hosts does nothing without a cloak_secret to go with it, and
+ * with one an address comes out as 16 hex digits */
+#[tokio::test]
+async fn cloaks_need_a_secret() {
+    let parsed = Config::parse("ircd.conf", "cloak_hosts yes\ncloak_secret s3cret\n");
+    assert!(parsed.cloak_hosts);
+    assert_eq!(parsed.cloak_secret.as_deref(), Some("s3cret"));
+    let irc = core_with(Config { cloak_hosts: true, cloak_secret: None, ..config() });
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    bob.ask("PRIVMSG alice :hi").await;
+    alice.expect(":bob!bob@127.0.0.1 PRIVMSG alice :hi").await;
+
+    irc.apply_config(Config { cloak_hosts: true, cloak_secret: parsed.cloak_secret, ..config() });
+    let mut carol = TestClient::register(&irc, "carol").await;
+    carol.ask("PRIVMSG alice :hi").await;
+    let line = alice.expect(" PRIVMSG alice :hi").await;
+    let host = line.split(' ').next().unwrap().trim_start_matches(":carol!carol@");
+    let digest = host.strip_prefix("user-").and_then(|host| host.strip_suffix(".cloak")).unwrap_or_default();
+    assert!(digest.len() == 16 && digest.chars().all(|c| c.is_ascii_hexdigit()), "{}", line);
+}
+
+/* +n keeps out anyone who hasn't joined, +m anyone without a voice */
+#[tokio::test]
+async fn no_external_and_moderated_channels() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let mut carol = TestClient::register(&irc, "carol").await;
+    alice.ask("JOIN #a").await;
+    alice.ask("MODE #a +n").await;
+    bob.ask("JOIN #a").await;
+    alice.sync().await;
+
+    let lines = carol.ask("PRIVMSG #a :from outside").await;
+    assert_eq!(lines, vec![String::from(":irc.test 404 #a :Cannot send to channel")]);
+    bob.ask("PRIVMSG #a :from inside").await;
+    assert_eq!(alice.sync().await, vec![String::from(":bob!bob@127.0.0.1 PRIVMSG #a :from inside")]);
+
+    alice.ask("MODE #a +m").await;
+    bob.sync().await;
+    let lines = bob.ask("PRIVMSG #a :can't now").await;
+    assert_eq!(lines, vec![String::from(":irc.test 404 #a :Cannot send to channel")]);
+    alice.ask("MODE #a +v bob").await;
+    bob.sync().await;
+    bob.ask("PRIVMSG #a :voiced").await;
+    assert_eq!(alice.sync().await, vec![String::from(":bob!bob@127.0.0.1 PRIVMSG #a :voiced")]);
+    alice.ask("PRIVMSG #a :opped").await;
+    assert_eq!(bob.sync().await, vec![String::from(":alice!alice@127.0.0.1 PRIVMSG #a :opped")]);
+    assert!(carol.sync().await.is_empty());
+}
+
+/* an invisible user only turns up in someone else's WHO scan if they
+ * share a channel, or the one asking's an oper and opers_see_invisible is on */
+#[tokio::test]
+async fn opers_see_invisible_users_in_who() {
+    let irc = core_with(with_oper(config(), "op"));
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let mut carol = TestClient::register(&irc, "carol").await;
+    bob.ask("MODE bob +i").await;
+
+    let lines = carol.ask("WHO b*").await;
+    assert!(!any_has(&lines, &[" 352 ", " bob "]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 315 "]), "{:?}", lines);
+
+    alice.ask("OPER op pw").await;
+    let lines = alice.ask("WHO b*").await;
+    assert!(any_has(&lines, &[" 352 ", " bob "]), "{:?}", lines);
+
+    let strict = Config { opers_see_invisible: false, ..irc.config.read().unwrap().clone() };
+    irc.apply_config(strict);
+    let lines = alice.ask("WHO b*").await;
+    assert!(!any_has(&lines, &[" 352 ", " bob "]), "{:?}", lines);
+}
+
+/* +l stops joins past the limit, shrinking it doesn't kick anyone, -l
+ * lifts it, and only members get to see the number */
+#[tokio::test]
+async fn user_limit_blocks_joins() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let mut carol = TestClient::register(&irc, "carol").await;
+    alice.ask("JOIN #a").await;
+    alice.ask("MODE #a +l 2").await;
+    let lines = bob.ask("JOIN #a").await;
+    assert!(any_has(&lines, &[":bob!", "JOIN #a"]), "{:?}", lines);
+    let lines = carol.ask("JOIN #a").await;
+    assert_eq!(lines, vec![String::from(":irc.test 471 #a :Cannot join channel (+l)")]);
+
+    let lines = alice.ask("MODE #a").await;
+    assert!(any_has(&lines, &[" 324 alice #a +", "l 2"]), "{:?}", lines);
+    let lines = carol.ask("MODE #a").await;
+    assert!(any_has(&lines, &[" 324 carol #a +"]), "{:?}", lines);
+    assert!(!any_has(&lines, &[" 2"]), "{:?}", lines);
+
+    alice.ask("MODE #a +l 1").await;
+    assert_eq!(irc.get_chan("#a").unwrap().get_n_users(), 2);
+    let lines = carol.ask("JOIN #a").await;
+    assert!(any_has(&lines, &[" 471 ", "#a"]), "{:?}", lines);
+
+    alice.ask("MODE #a -l").await;
+    let lines = carol.ask("JOIN #a").await;
+    assert!(any_has(&lines, &[":carol!", "JOIN #a"]), "{:?}", lines);
+}
+
+#[tokio::test]
+async fn lone_prefixes_arent_channels() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    for name in ["#", "&"].iter() {
+        let lines = alice.ask(&format!("JOIN {}", name)).await;
+        assert_eq!(lines, vec![format!(":irc.test 403 {} :No such channel", name)]);
+    }
+    assert_eq!(irc.channel_count(), 0);
+}
+
+/* quit reasons lose their control chars and get quit_prefix stuck on the
+ * front, no reason at all is just "Client Quit" */
+#[tokio::test]
+async fn quit_reasons_are_cleaned_and_prefixed() {
+    let irc = core_with(Config { quit_prefix: String::from("Gone: "), ..config() });
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let mut carol = TestClient::register(&irc, "carol").await;
+    for client in [&mut alice, &mut bob, &mut carol].iter_mut() {
+        client.ask("JOIN #a").await;
+    }
+    bob.sync().await;
+    alice.send_raw(b"QUIT :\x034red\x03 \x07bell\x01\r\n").await;
+    alice.until_closed().await;
+    assert_eq!(bob.sync().await, vec![String::from(":alice!alice@127.0.0.1 QUIT :Gone: 4red bell")]);
+
+    carol.send("QUIT").await;
+    carol.until_closed().await;
+    assert_eq!(bob.sync().await, vec![String::from(":carol!carol@127.0.0.1 QUIT :Client Quit")]);
+}
+
+/* offline nicks are left out, but still count towards the five */
+#[tokio::test]
+async fn userhost_marks_opers_and_away() {
+    let irc = core_with(with_oper(config(), "op"));
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let _carol = TestClient::register(&irc, "carol").await;
+    let _dave = TestClient::register(&irc, "dave").await;
+    alice.ask("OPER op pw").await;
+    bob.ask("AWAY :lunch").await;
+    let lines = alice.ask("USERHOST alice ghost bob carol nobody dave").await;
+    assert_eq!(
+        lines,
+        vec![String::from(":irc.test 302 alice :alice*=+alice@127.0.0.1 bob=-bob@127.0.0.1 carol=+carol@127.0.0.1")]
+    );
+    let lines = alice.ask("USERHOST ghost").await;
+    assert_eq!(lines, vec![String::from(":irc.test 302 alice :")]);
+}
+
+/* a NAMES too long for one line gets split over several 353s, every nick
+ * in exactly one of them, and none of them over 512 bytes */
+#[tokio::test]
+async fn names_split_for_a_big_channel() {
+    let irc = core();
+    let mut clients = Vec::new();
+    for n in 0..200 {
+        let mut client = TestClient::register(&irc, &format!("u{}", n)).await;
+        client.ask("JOIN #big").await;
+        clients.push(client);
+    }
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let lines = alice.ask("NAMES #big").await;
+    let replies: Vec<&String> = lines.iter().filter(|line| line.contains(" 353 ")).collect();
+    assert!(replies.len() > 1, "{:?}", lines);
+    assert!(replies.iter().all(|line| line.len() + 2 <= 512), "{:?}", lines);
+    let mut names: Vec<String> = replies
+        .iter()
+        .flat_map(|line| line.split_once(" :").unwrap().1.split(' '))
+        .map(|name| name.trim_start_matches('@').to_string())
+        .collect();
+    names.sort_unstable();
+    let mut expected: Vec<String> = (0..200).map(|n| format!("u{}", n)).collect();
+    expected.sort_unstable();
+    assert_eq!(names, expected);
+    assert!(any_has(&lines, &[" 366 ", "#big"]), "{:?}", lines);
+}
+
+/* a line over 512 bytes gets a 417 and is thrown away whole, rather than
+ * the first 512 bytes of it being run as a command, and so is one that
+ * goes on past the tag allowance without a CRLF. Whatever comes after is
+ * read as normal */
+#[tokio::test]
+async fn oversized_lines_get_417() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let long = format!("PRIVMSG bob :{}", "x".repeat(600 - 13));
+    assert_eq!(long.len(), 600);
+    alice.send_raw(long.as_bytes()).await;
+    let lines = alice.ask("").await;
+    assert_eq!(lines, vec![String::from(":irc.test 417 :Input line was too long")]);
+
+    let endless = "PRIVMSG bob :".to_string() + &"y".repeat(5000);
+    alice.send_raw(endless.as_bytes()).await;
+    alice.send_raw(b"\r\nPRIVMSG bob :after\r\n").await;
+    let lines = alice.sync().await;
+    assert_eq!(lines, vec![String::from(":irc.test 417 :Input line was too long")]);
+    assert_eq!(bob.sync().await, vec![String::from(":alice!alice@127.0.0.1 PRIVMSG bob :after")]);
+}
+
+/* max_channels counts only the channels you're still on */
+#[tokio::test]
+async fn joins_stop_at_max_channels() {
+    let irc = core_with(Config { max_channels: 2, ..config() });
+    let mut alice = TestClient::register(&irc, "alice").await;
+    alice.ask("JOIN #a,#b").await;
+    let lines = alice.ask("JOIN #c").await;
+    assert_eq!(lines, vec![String::from(":irc.test 405 #c :You have joined too many channels")]);
+    assert!(irc.get_chan("#c").is_err());
+
+    /* a channel that's gone without the user hearing about it doesn't count */
+    user(&irc, "alice").channel_list.lock().unwrap().insert(String::from("#ghost"), Weak::new());
+    alice.ask("PART #b").await;
+    let lines = alice.ask("JOIN #c").await;
+    assert!(any_has(&lines, &[":alice!", "JOIN #c"]), "{:?}", lines);
+}
+
+/* TIME comes from us and is about now, registered or not */
+#[tokio::test]
+async fn time_is_ours_and_current() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut stranger = TestClient::connect(&irc).await;
+    for (client, nick) in [(&mut alice, "alice"), (&mut stranger, "*")].iter_mut() {
+        let lines = client.ask("TIME").await;
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        let prefix = format!(":{} 391 {} {} :", irc.get_host(), nick, irc.get_host());
+        let stamp = lines[0].strip_prefix(&prefix).unwrap_or_else(|| panic!("{:?}", lines));
+        let time = DateTime::parse_from_rfc2822(stamp).unwrap();
+        assert!((Utc::now().timestamp() - time.timestamp()).abs() <= 5, "{:?}", lines);
+    }
+}
+
+/* a channel's creation time is when it was made, and doesn't move when
+ * it's looked at later */
+#[tokio::test]
+async fn channels_report_their_creation_time() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let before = Utc::now().timestamp();
+    alice.ask("JOIN #a").await;
+    let after = Utc::now().timestamp();
+    let created = irc.get_chan("#a").unwrap().get_created();
+    assert!(before <= created && created <= after);
+    let lines = alice.ask("MODE #a").await;
+    assert!(any_has(&lines, &[&format!(" 329 alice #a {}", created)]), "{:?}", lines);
+}
+
+/* nicks and channels share the namespace, but taking out one sort never
+ * takes out the other */
+#[tokio::test]
+async fn removal_only_takes_the_right_sort_of_name() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    alice.ask("JOIN #a").await;
+    assert!(matches!(irc.remove_chan("alice"), Err(ircError::NoSuchChannel(name)) if name == "alice"));
+    assert!(irc.get_nick("alice").is_some());
+    assert!(matches!(irc.remove_user("#a"), Err(ircError::NoSuchNick(name)) if name == "#a"));
+    assert!(irc.get_chan("#a").is_ok());
+
+    assert!(irc.remove_chan("#A").is_ok());
+    assert!(irc.get_chan("#a").is_err());
+    assert!(irc.remove_user("ALICE").is_ok());
+    assert!(irc.get_nick("alice").is_none());
+}
+
+/* a burst's worth of lines go straight through, the next few are dropped
+ * with a NOTICE, and flood_excess of those in a row gets you thrown off.
+ * The PING behind TestClient::register's sync uses a token too. Opers
+ * don't get throttled at all */
+#[tokio::test]
+async fn flooding_is_throttled_then_thrown_off() {
+    let irc = core_with(Config { flood_rate: 0.0001, flood_burst: 4.0, flood_excess: 3, ..with_oper(config(), "op") });
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    for n in 0..6 {
+        alice.send(&format!("PRIVMSG bob :{}", n)).await;
+    }
+    let lines = alice.until_closed().await;
+    assert_eq!(lines.iter().filter(|line| line.contains("NOTICE alice :PRIVMSG dropped")).count(), 2, "{:?}", lines);
+    assert!(lines.last().unwrap().contains("Excess Flood"), "{:?}", lines);
+    let lines = bob.sync().await;
+    let expected: Vec<String> = (0..3).map(|n| format!(":alice!alice@127.0.0.1 PRIVMSG bob :{}", n)).collect();
+    assert_eq!(lines, expected);
+
+    /* lines that only get a 461 back cost a token all the same */
+    let mut dave = TestClient::register(&irc, "dave").await;
+    for _n in 0..6 {
+        dave.send("TOPIC").await;
+    }
+    let lines = dave.until_closed().await;
+    assert_eq!(lines.iter().filter(|line| line.contains(" 461 TOPIC ")).count(), 3, "{:?}", lines);
+    assert_eq!(lines.iter().filter(|line| line.contains("NOTICE dave :TOPIC dropped")).count(), 2, "{:?}", lines);
+    assert!(lines.last().unwrap().contains("Excess Flood"), "{:?}", lines);
+
+    let mut carol = TestClient::register(&irc, "carol").await;
+    carol.ask("OPER op pw").await;
+    for n in 0..20 {
+        carol.send(&format!("PRIVMSG bob :{}", n)).await;
+    }
+    assert!(!any_has(&carol.sync().await, &["dropped"]));
+    assert_eq!(bob.sync().await.len(), 20);
+}
+
+#[tokio::test]
+async fn info_lines_then_end_of_info() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut stranger = TestClient::connect(&irc).await;
+    for (client, nick) in [(&mut alice, "alice"), (&mut stranger, "*")].iter_mut() {
+        let lines = client.ask("INFO").await;
+        let (last, info) = lines.split_last().unwrap();
+        assert_eq!(last, &format!(":irc.test 374 {} :End of INFO list", nick));
+        assert!(!info.is_empty());
+        assert!(info.iter().all(|line| line.starts_with(&format!(":irc.test 371 {} :", nick))), "{:?}", lines);
+        assert!(any_has(info, &["rusty-ircd ", env!("CARGO_PKG_VERSION")]), "{:?}", lines);
+    }
+}
+
+/* a CR or NUL in the middle of a line can't become a line of its own by
+ * the time it gets to the recipient */
+#[tokio::test]
+async fn no_lines_smuggled_inside_messages() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    alice.send_raw(b"PRIVMSG bob :hi\rKILL x\0!\r\n").await;
+    alice.sync().await;
+    assert_eq!(bob.sync().await, vec![String::from(":alice!alice@127.0.0.1 PRIVMSG bob :hiKILL x!")]);
+    let lines = alice.ask("PRIVMSG bob :\r").await;
+    assert_eq!(lines, vec![String::from(":irc.test 412 :No text to send")]);
+}
+
+/* a silenced sender's messages just vanish, they aren't told */
+#[tokio::test]
+async fn silenced_senders_are_dropped_quietly() {
+    let irc = core_with(Config { max_silence: 2, ..config() });
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let mut carol = TestClient::register(&irc, "carol").await;
+    let lines = bob.ask("SILENCE +alice").await;
+    assert_eq!(lines, vec![String::from(":bob!bob@127.0.0.1 SILENCE +alice!*@*")]);
+
+    assert!(alice.ask("PRIVMSG bob :hello?").await.is_empty());
+    assert!(alice.ask("NOTICE bob :hello?").await.is_empty());
+    carol.ask("PRIVMSG bob :hi").await;
+    assert_eq!(bob.sync().await, vec![String::from(":carol!carol@127.0.0.1 PRIVMSG bob :hi")]);
+
+    let lines = bob.ask("SILENCE +x,+y").await;
+    assert_eq!(
+        lines,
+        vec![String::from(":bob!bob@127.0.0.1 SILENCE +x!*@*"), String::from(":irc.test 511 y!*@* :Your silence list is full")]
+    );
+    let lines = bob.ask("SILENCE").await;
+    assert!(any_has(&lines, &[" 271 bob bob alice!*@*"]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 271 bob bob x!*@*"]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 272 bob :End of Silence List"]), "{:?}", lines);
+
+    bob.ask("SILENCE -alice").await;
+    alice.ask("PRIVMSG bob :now?").await;
+    assert_eq!(bob.sync().await, vec![String::from(":alice!alice@127.0.0.1 PRIVMSG bob :now?")]);
+}
+
+/* the server's own NOTICEs come from the server, and once the connection's
+ * gone the user is reported dead rather than anything panicking */
+#[tokio::test]
+async fn server_notices_to_a_user() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let alice_user = user(&irc, "alice");
+    alice_user.send_notice("Your nick will be changed").await.unwrap();
+    assert_eq!(alice.sync().await, vec![String::from(":irc.test NOTICE alice :Your nick will be changed")]);
+
+    drop(alice);
+    for _i in 0..500 {
+        if let Err(GenError::DeadClient(dead)) = alice_user.send_notice("anyone there?").await {
+            assert_eq!(dead.get_nick(), "alice");
+            return;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("alice never turned up dead");
+}
+
+/* WHOWAS finds people who've gone, newest first, and only as far back as
+ * whowas_history goes */
+#[tokio::test]
+async fn whowas_remembers_who_quit() {
+    let irc = core_with(Config { whowas_history: 2, ..config() });
+    let mut alice = TestClient::register(&irc, "alice").await;
+    for real_name in ["first", "second", "third"].iter() {
+        let mut x = TestClient::connect(&irc).await;
+        x.send("NICK x").await;
+        x.send(&format!("USER x 0 * :{}", real_name)).await;
+        x.expect(" 001 ").await;
+        x.send("QUIT").await;
+        x.until_closed().await;
+    }
+    let lines = alice.ask("WHOWAS X").await;
+    let found: Vec<&String> = lines.iter().filter(|line| line.contains(" 314 ")).collect();
+    assert_eq!(found, vec![":irc.test 314 alice x x 127.0.0.1 * :third", ":irc.test 314 alice x x 127.0.0.1 * :second"]);
+    assert!(any_has(&lines, &[" 312 alice x irc.test "]), "{:?}", lines);
+    assert!(lines.last().unwrap().contains(" 369 alice X "), "{:?}", lines);
+
+    let lines = alice.ask("WHOWAS x 1").await;
+    assert_eq!(lines.iter().filter(|line| line.contains(" 314 ")).count(), 1, "{:?}", lines);
+    assert!(any_has(&lines, &[" 314 ", ":third"]), "{:?}", lines);
+
+    let lines = alice.ask("WHOWAS nobody").await;
+    assert!(any_has(&lines, &[" 406 nobody "]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 369 alice nobody "]), "{:?}", lines);
+}
+
+/* the 005s at registration, split so no line has more than 13 tokens,
+ * and with the configured limits in them */
+#[tokio::test]
+async fn registration_sends_isupport() {
+    let irc = core_with(Config { max_channels: 7, ..config() });
+    let mut alice = TestClient::connect(&irc).await;
+    alice.send("NICK alice").await;
+    alice.send("USER alice 0 * :Real alice").await;
+    alice.expect(" 001 ").await;
+    let lines: Vec<String> = alice.sync().await.into_iter().filter(|line| line.contains(" 005 ")).collect();
+    assert!(!lines.is_empty());
+    for line in lines.iter() {
+        let (tokens, trailing) = line[":irc.test 005 alice ".len()..].split_once(" :").unwrap();
+        assert_eq!(trailing, "are supported by this server");
+        assert!(tokens.split(' ').count() <= 13, "{:?}", lines);
+    }
+    for token in ["CHANTYPES=&#+!", "NICKLEN=9", "CHANNELLEN=50", "CHANLIMIT=&#+!:7", "PREFIX=(ov)@+"].iter() {
+        assert!(any_has(&lines, &[&format!(" {} ", token)]), "{} in {:?}", token, lines);
+    }
+}
+
+/* the realname is everything from the fourth param on, ':' or not */
+#[tokio::test]
+async fn user_takes_the_rest_as_realname() {
+    let irc = core();
+    for (nick, line, real_name) in [
+        ("a", "USER u 0 * :Real Name With Spaces", "Real Name With Spaces"),
+        ("b", "USER u 0 * Forgot The Colon", "Forgot The Colon"),
+        ("c", "USER u 0 * :", ""),
+    ].iter() {
+        let mut client = TestClient::connect(&irc).await;
+        client.send(&format!("NICK {}", nick)).await;
+        client.send(line).await;
+        client.expect(" 001 ").await;
+        client.sync().await;
+        assert_eq!(user(&irc, nick).get_realname(), *real_name);
+        assert_eq!(user(&irc, nick).get_username(), "u");
+    }
+    let mut client = TestClient::connect(&irc).await;
+    let lines = client.ask("USER u 0 *").await;
+    assert_eq!(lines, vec![String::from(":irc.test 461 USER :Not enough parameters")]);
+}
+
+#[tokio::test]
+async fn tiny_lines_leave_the_connection_up() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    alice.send_raw(b":\r\n:x\r\n\r\n").await;
+    alice.sync().await;
+    let lines = alice.ask("PRIVMSG alice :still here").await;
+    assert_eq!(lines, vec![String::from(":alice!alice@127.0.0.1 PRIVMSG alice :still here")]);
+}
+
+/* b with no mask is a query anyone on the channel can make, with a mask
+ * it's a change and needs op */
+#[tokio::test]
+async fn ban_list_queries_and_changes() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    alice.ask("JOIN #a").await;
+    bob.ask("JOIN #a").await;
+    alice.sync().await;
+    let lines = alice.ask("MODE #a +b bad!*@*").await;
+    assert_eq!(lines, vec![String::from(":alice!alice@127.0.0.1 MODE #a +b bad!*@*")]);
+    bob.sync().await;
+
+    for query in ["MODE #a b", "MODE #a +b"].iter() {
+        let lines = bob.ask(query).await;
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].starts_with(":irc.test 367 bob #a bad!*@* alice!alice@127.0.0.1 "), "{:?}", lines);
+        assert_eq!(lines[1], ":irc.test 368 bob #a :End of channel ban list");
+    }
+    let lines = bob.ask("MODE #a +b worse!*@*").await;
+    assert!(any_has(&lines, &[" 482 ", "#a"]), "{:?}", lines);
+    assert_eq!(irc.get_chan("#a").unwrap().get_mask_list('b').len(), 1);
+
+    let lines = alice.ask("MODE #a").await;
+    assert!(any_has(&lines, &[" 324 alice #a +"]), "{:?}", lines);
+    assert!(!any_has(&lines, &[" 367 "]), "{:?}", lines);
+}
+
+/* +e gets you past a ban, +I past +i without needing an INVITE */
+#[tokio::test]
+async fn exceptions_beat_bans_and_invite_only() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let mut carol = TestClient::register(&irc, "carol").await;
+    alice.ask("JOIN #a,#b").await;
+    alice.ask("MODE #a +b *!*@127.0.0.1").await;
+    let lines = bob.ask("JOIN #a").await;
+    assert!(any_has(&lines, &[" 474 ", "#a"]), "{:?}", lines);
+    alice.ask("MODE #a +e bob!*@*").await;
+    let lines = bob.ask("JOIN #a").await;
+    assert!(any_has(&lines, &[":bob!", "JOIN #a"]), "{:?}", lines);
+    let lines = carol.ask("JOIN #a").await;
+    assert!(any_has(&lines, &[" 474 ", "#a"]), "{:?}", lines);
+
+    alice.ask("MODE #b +i").await;
+    let lines = carol.ask("JOIN #b").await;
+    assert!(any_has(&lines, &[" 473 ", "#b"]), "{:?}", lines);
+    alice.ask("MODE #b +I carol!*@*").await;
+    let lines = carol.ask("JOIN #b").await;
+    assert!(any_has(&lines, &[":carol!", "JOIN #b"]), "{:?}", lines);
+    let lines = bob.ask("JOIN #b").await;
+    assert!(any_has(&lines, &[" 473 ", "#b"]), "{:?}", lines);
+
+    let lines = alice.ask("MODE #a e").await;
+    assert!(any_has(&lines, &[" 348 alice #a bob!*@* "]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 349 alice #a "]), "{:?}", lines);
+    let lines = alice.ask("MODE #b I").await;
+    assert!(any_has(&lines, &[" 346 alice #b carol!*@* "]), "{:?}", lines);
+    assert!(any_has(&lines, &[" 347 alice #b "]), "{:?}", lines);
+}
+
+/* a KNOCK goes to the channel's ops and nobody else on it. Each channel
+ * only passes one on every knock_channel_delay_secs, and each user only
+ * gets knock_limit of them a window */
+#[tokio::test]
+async fn knocks_reach_the_ops() {
+    let irc = core_with(Config { knock_limit: 2, knock_channel_delay_secs: 60, ..config() });
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let mut carol = TestClient::register(&irc, "carol").await;
+    let mut dave = TestClient::register(&irc, "dave").await;
+    alice.ask("JOIN #a,#open").await;
+    bob.ask("JOIN #a").await;
+    alice.ask("MODE #a +i").await;
+    alice.sync().await;
+    bob.sync().await;
+
+    let lines = carol.ask("KNOCK #open").await;
+    assert_eq!(lines, vec![String::from(":irc.test 480 :Cannot knock on #open (channel is open)")]);
+    let lines = bob.ask("KNOCK #a").await;
+    assert_eq!(lines, vec![String::from(":irc.test 480 :Cannot knock on #a (you're already on it)")]);
+
+    let lines = carol.ask("KNOCK #a :let me in").await;
+    assert_eq!(lines, vec![String::from(":irc.test 711 carol #a :Your KNOCK has been delivered")]);
+    assert_eq!(
+        alice.sync().await,
+        vec![String::from(":irc.test 710 alice #a carol!carol@127.0.0.1 :has asked for an invite (let me in)")]
+    );
+    assert!(bob.sync().await.is_empty());
+
+    let lines = dave.ask("KNOCK #a").await;
+    assert_eq!(lines, vec![String::from(":irc.test 713 #a :Too many KNOCKs (channel)")]);
+    assert!(alice.sync().await.is_empty());
+
+    let quick = Config { knock_channel_delay_secs: 0, ..irc.config.read().unwrap().clone() };
+    irc.apply_config(quick);
+    carol.ask("KNOCK #a").await;
+    let lines = carol.ask("KNOCK #a").await;
+    assert_eq!(lines, vec![String::from(":irc.test 713 #a :Too many KNOCKs (user)")]);
+    assert_eq!(alice.sync().await.len(), 1);
+
+    alice.ask("MODE #a +b dave!*@*").await;
+    let lines = dave.ask("KNOCK #a").await;
+    assert!(any_has(&lines, &[" 474 ", "#a"]), "{:?}", lines);
+}
+
+/* nick_change_limit changes a window, the next is refused and the nick
+ * stays put. Opers can change as often as they like */
+#[tokio::test]
+async fn nick_changes_are_rate_limited() {
+    let irc = core_with(Config { nick_change_limit: 2, nick_change_window_secs: 60, ..with_oper(config(), "op") });
+    let mut alice = TestClient::register(&irc, "alice").await;
+    alice.ask("NICK a1").await;
+    alice.ask("NICK a2").await;
+    let lines = alice.ask("NICK a3").await;
+    assert_eq!(lines.len(), 1, "{:?}", lines);
+    let wait: u64 = lines[0]
+        .strip_prefix(":irc.test 438 a3 :Nick change too fast. Please wait ")
+        .and_then(|rest| rest.strip_suffix(" seconds"))
+        .unwrap_or_else(|| panic!("{:?}", lines))
+        .parse()
+        .unwrap();
+    assert!(wait > 0 && wait <= 61, "{:?}", lines);
+    assert!(irc.get_nick("a2").is_some());
+    assert!(irc.get_nick("a3").is_none());
+
+    let mut carol = TestClient::register(&irc, "carol").await;
+    carol.ask("OPER op pw").await;
+    for n in 0..5 {
+        let lines = carol.ask(&format!("NICK c{}", n)).await;
+        assert!(any_has(&lines, &[&format!(" NICK :c{}", n)]), "{:?}", lines);
+    }
+}
+
+/* a +P channel stays put once everyone's gone, topic, modes, bans and all */
+#[tokio::test]
+async fn permanent_channels_outlive_their_members() {
+    let irc = core_with(with_oper(config(), "op"));
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    bob.ask("JOIN #b").await;
+    let lines = bob.ask("MODE #b +P").await;
+    assert!(any_has(&lines, &[" 481 "]), "{:?}", lines);
+    assert!(!irc.get_chan("#b").unwrap().get_modes().permanent);
+
+    alice.ask("OPER op pw").await;
+    alice.ask("JOIN #p").await;
+    alice.ask("MODE #p +Pt").await;
+    alice.ask("TOPIC #p :here to stay").await;
+    alice.ask("MODE #p +b bad!*@*").await;
+    alice.ask("PART #p").await;
+    assert_eq!(irc.get_chan("#p").unwrap().get_n_users(), 0);
+
+    let lines = bob.ask("JOIN #p").await;
+    assert!(any_has(&lines, &[" 332 bob #p :here to stay"]), "{:?}", lines);
+    let lines = bob.ask("MODE #p").await;
+    assert!(any_has(&lines, &[" 324 bob #p +", "P"]), "{:?}", lines);
+    assert_eq!(irc.get_chan("#p").unwrap().get_mask_list('b').len(), 1);
+
+    alice.ask("JOIN #p").await;
+    alice.ask("MODE #p -P").await;
+    alice.ask("PART #p").await;
+    bob.ask("PART #p").await;
+    assert!(irc.get_chan("#p").is_err());
+}
+
+/* NICK and USER during CAP negotiation don't register you, CAP END does */
+#[tokio::test]
+async fn cap_negotiation_holds_registration() {
+    let irc = core();
+    let mut alice = TestClient::connect(&irc).await;
+    let lines = alice.ask("CAP LS 302").await;
+    assert_eq!(lines, vec![String::from(":irc.test CAP * LS :multi-prefix")]);
+    alice.send("NICK alice").await;
+    assert!(alice.ask("USER alice 0 * :Real alice").await.is_empty());
+    assert!(irc.get_nick("alice").is_none());
+
+    let lines = alice.ask("CAP REQ :multi-prefix bogus").await;
+    assert_eq!(lines, vec![String::from(":irc.test CAP * NAK :multi-prefix bogus")]);
+    let lines = alice.ask("CAP REQ :multi-prefix").await;
+    assert_eq!(lines, vec![String::from(":irc.test CAP * ACK :multi-prefix")]);
+    assert!(irc.get_nick("alice").is_none());
+
+    let lines = alice.ask("CAP END").await;
+    assert!(lines[0].starts_with(":irc.test 001 alice "), "{:?}", lines);
+    assert!(irc.get_nick("alice").is_some());
+    let lines = alice.ask("CAP LIST").await;
+    assert_eq!(lines, vec![String::from(":irc.test CAP alice LIST :multi-prefix")]);
+
+    /* a REQ on its own holds things up too */
+    let mut bob = TestClient::connect(&irc).await;
+    bob.ask("CAP REQ :multi-prefix").await;
+    bob.send("NICK bob").await;
+    assert!(bob.ask("USER bob 0 * :Real bob").await.is_empty());
+    bob.send("CAP END").await;
+    bob.expect(" 001 bob ").await;
+}
+
+/* bob's opped and voiced: with multi-prefix you see both, without just
+ * the highest */
+#[tokio::test]
+async fn multi_prefix_shows_every_prefix() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let mut carol = TestClient::register(&irc, "carol").await;
+    alice.ask("JOIN #a").await;
+    bob.ask("JOIN #a").await;
+    carol.ask("JOIN #a").await;
+    alice.ask("MODE #a +ov bob bob").await;
+    carol.sync().await;
+
+    let lines = carol.ask("NAMES #a").await;
+    assert_eq!(lines[0], ":irc.test 353 carol #a :@alice @bob carol");
+    let lines = carol.ask("WHO #a").await;
+    assert!(any_has(&lines, &[" 352 carol #a bob ", " H@ "]), "{:?}", lines);
+
+    carol.ask("CAP REQ :multi-prefix").await;
+    let lines = carol.ask("NAMES #a").await;
+    assert_eq!(lines[0], ":irc.test 353 carol #a :@alice @+bob carol");
+    let lines = carol.ask("WHO #a").await;
+    assert!(any_has(&lines, &[" 352 carol #a bob ", " H@+ "]), "{:?}", lines);
+}
+
+/* links with peer.test, which has to send "in" and gets sent "out" */
+fn linked_config() -> Config {
+    let mut config = config();
+    config.links.insert(String::from("peer.test"), (password::hash_with_salt("in", b"salt", 1), String::from("out")));
+    config
+}
+
+async fn link_peer(irc: &Arc<Core>) -> (TestClient, Vec<String>) {
+    let mut peer = TestClient::connect(irc).await;
+    peer.send("PASS in").await;
+    let lines = peer.ask("SERVER peer.test 1 :Peer").await;
+    (peer, lines)
+}
+
+/* on linking we send our own users and who's in which channel, and the
+ * peer's burst comes in the other way, with the JOINs for our users */
+#[tokio::test]
+async fn linking_swaps_bursts() {
+    let irc = core_with(linked_config());
+    let mut alice = TestClient::register(&irc, "alice").await;
+    alice.ask("JOIN #a").await;
+    let signon = user(&irc, "alice").get_signon();
+
+    let (mut peer, lines) = link_peer(&irc).await;
+    assert_eq!(lines[0], "PASS out");
+    assert!(lines[1].starts_with(":irc.test SERVER irc.test 1 :"), "{:?}", lines);
+    assert!(
+        lines.contains(&format!(":irc.test NICK alice 1 {} + alice 127.0.0.1 irc.test :Real alice", signon)),
+        "{:?}",
+        lines
+    );
+    assert!(lines.contains(&String::from(":irc.test NJOIN #a :@alice")), "{:?}", lines);
+
+    peer.send(&format!("NICK carol 1 {} + carol remote.host peer.test :Carol", signon)).await;
+    peer.ask("NJOIN #a :+carol").await;
+    assert_eq!(alice.sync().await, vec![String::from(":carol!carol@remote.host JOIN #a")]);
+    assert!(user(&irc, "carol").is_remote());
+    let lines = alice.ask("NAMES #a").await;
+    assert_eq!(lines[0], ":irc.test 353 alice #a :@alice +carol");
+
+    /* and when the link goes, so do they */
+    drop(peer);
+    alice.expect(":carol!carol@remote.host QUIT :irc.test peer.test").await;
+    assert!(irc.get_nick("carol").is_none());
+}
+
+/* whoever signed on first keeps the nick, both go if it's a tie. The peer
+ * is sent a KILL for the nick whichever way it goes */
+#[tokio::test]
+async fn burst_nick_collisions_keep_the_older() {
+    let irc = core_with(linked_config());
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let mut dave = TestClient::register(&irc, "dave").await;
+    let (mut peer, _lines) = link_peer(&irc).await;
+
+    /* theirs is older, ours goes */
+    let lines = peer.ask("NICK bob 1 0 + bob remote.host peer.test :Bob").await;
+    assert_eq!(lines, vec![String::from(":irc.test KILL bob :irc.test (Nick collision)")]);
+    let lines = bob.until_closed().await;
+    assert!(any_has(&lines, &[" 436 bob :Nickname collision KILL"]), "{:?}", lines);
+    assert!(user(&irc, "bob").is_remote());
+
+    /* ours is older, theirs never gets in */
+    let lines = peer.ask(&format!("NICK alice 1 {} + alice remote.host peer.test :Alice", i64::MAX)).await;
+    assert_eq!(lines, vec![String::from(":irc.test KILL alice :irc.test (Nick collision)")]);
+    assert!(!user(&irc, "alice").is_remote());
+    assert!(alice.sync().await.is_empty());
+
+    /* a tie, neither */
+    let signon = user(&irc, "dave").get_signon();
+    let lines = peer.ask(&format!("NICK dave 1 {} + dave remote.host peer.test :Dave", signon)).await;
+    assert_eq!(lines, vec![String::from(":irc.test KILL dave :irc.test (Nick collision)")]);
+    dave.until_closed().await;
+    assert!(irc.get_nick("dave").is_none());
+}
+
+/* an oper's KILL of a remote user goes down the link, and one from an oper
+ * over there takes out ours. A KILL from just the server can't */
+#[tokio::test]
+async fn kills_cross_the_link() {
+    let irc = core_with(with_oper(linked_config(), "op"));
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    alice.ask("OPER op pw").await;
+    let (mut peer, _lines) = link_peer(&irc).await;
+    peer.send("NICK carol 1 0 +o carol remote.host peer.test :Carol").await;
+    peer.ask("NICK erin 1 0 + erin remote.host peer.test :Erin").await;
+
+    alice.ask("KILL erin :bye").await;
+    let lines = peer.sync().await;
+    assert!(lines.contains(&String::from(":alice!alice@127.0.0.1 KILL erin :bye")), "{:?}", lines);
+    assert!(irc.get_nick("erin").is_none());
+
+    /* the server itself, or someone it didn't introduce, gets ignored */
+    peer.send(":peer.test KILL alice :nope").await;
+    peer.ask(":bob!bob@127.0.0.1 KILL alice :nope").await;
+    assert!(alice.sync().await.is_empty());
+
+    peer.ask(":carol!carol@remote.host KILL bob :go away").await;
+    let lines = bob.until_closed().await;
+    assert!(lines.contains(&String::from(":carol!carol@remote.host KILL bob :go away")), "{:?}", lines);
+    assert!(any_has(&lines, &["Killed (carol (go away))"]), "{:?}", lines);
+    assert!(irc.get_nick("bob").is_none());
+}
+
+/* max_targets is fine, one more and that one gets a 407 and no message */
+#[tokio::test]
+async fn privmsg_targets_are_capped() {
+    let irc = core_with(Config { max_targets: 3, ..config() });
+    let mut alice = TestClient::connect(&irc).await;
+    alice.send("NICK alice").await;
+    alice.send("USER alice 0 * :Real alice").await;
+    alice.expect(" 001 ").await;
+    let lines = alice.sync().await;
+    assert!(any_has(&lines, &[" 005 ", " TARGMAX=JOIN:3,PART:3,PRIVMSG:3,NOTICE:3 "]), "{:?}", lines);
+
+    let mut others = Vec::new();
+    for nick in ["b", "c", "d", "e"].iter() {
+        others.push(TestClient::register(&irc, nick).await);
+    }
+    let lines = alice.ask("PRIVMSG b,c,d :at the limit").await;
+    assert!(lines.is_empty(), "{:?}", lines);
+    let lines = alice.ask("PRIVMSG b,c,d,e :over it").await;
+    assert_eq!(lines, vec![String::from(":irc.test 407 e :Too many recipients. The rest were ignored")]);
+    for (n, other) in others.iter_mut().enumerate() {
+        let lines = other.sync().await;
+        let expected = if n < 3 { 2 } else { 0 };
+        assert_eq!(lines.len(), expected, "{:?}", lines);
+    }
+    let lines = alice.ask("JOIN #1,#2,#3").await;
+    assert!(!any_has(&lines, &[" 407 "]), "{:?}", lines);
+}
+
+/* an over-long nick's refused by default. With truncate_long_nicks it's
+ * cut down to NICKLEN, unless that's taken, then it's refused after all */
+#[tokio::test]
+async fn long_nicks_rejected_or_truncated() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let lines = alice.ask("NICK abcdefghijkl").await;
+    assert_eq!(lines, vec![String::from(":irc.test 432 abcdefghijkl :Erroneous nickname")]);
+
+    let irc = core_with(Config { truncate_long_nicks: true, ..config() });
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let lines = alice.ask("NICK abcdefghijkl").await;
+    assert_eq!(lines, vec![String::from(":alice!alice@127.0.0.1 NICK :abcdefghi")]);
+    let mut bob = TestClient::register(&irc, "bob").await;
+    let lines = bob.ask("NICK ABCDEFGHIxyz").await;
+    assert_eq!(lines, vec![String::from(":irc.test 432 ABCDEFGHIxyz :Erroneous nickname")]);
+    let lines = bob.ask("NICK bobbybobbyb").await;
+    assert_eq!(lines, vec![String::from(":bob!bob@127.0.0.1 NICK :bobbybobb")]);
+}
+
+/* an empty TOPIC clears it, and JOIN and TOPIC go back to 331 */
+#[tokio::test]
+async fn empty_topic_unsets_it() {
+    let irc = core();
+    let mut alice = TestClient::register(&irc, "alice").await;
+    let mut bob = TestClient::register(&irc, "bob").await;
+    alice.ask("JOIN #a").await;
+    alice.ask("TOPIC #a :something").await;
+    let lines = bob.ask("JOIN #a").await;
+    assert!(any_has(&lines, &[" 332 bob #a :something"]), "{:?}", lines);
+    alice.sync().await;
+
+    let lines = alice.ask("TOPIC #a :").await;
+    assert_eq!(lines, vec![String::from(":alice!alice@127.0.0.1 TOPIC #a :")]);
+    assert!(irc.get_chan("#a").unwrap().get_topic().is_none());
+    let lines = bob.sync().await;
+    assert_eq!(lines, vec![String::from(":alice!alice@127.0.0.1 TOPIC #a :")]);
+    let lines = bob.ask("TOPIC #a").await;
+    assert_eq!(lines, vec![String::from(":irc.test 331 bob #a :No topic is set.")]);
+    bob.ask("PART #a").await;
+    let lines = bob.ask("JOIN #a").await;
+    assert!(any_has(&lines, &[" 331 bob #a "]), "{:?}", lines);
+    assert!(!any_has(&lines, &[" 332 "]), "{:?}", lines);
+
+    alice.ask("TOPIC #a :back again").await;
+    let lines = bob.ask("TOPIC #a").await;
+    assert!(any_has(&lines, &[" 332 bob #a :back again"]), "{:?}", lines);
+}
+
+/* clients changing nick and hopping in and out of the same few channels
+ * all at once. A lock taken in the wrong order somewhere would jam the
+ * runtime's threads for good, so it runs on a runtime of its own and if
+ * that hasn't finished in time it's a deadlock */
+#[test]
+fn concurrent_nick_changes_and_joins() {
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(4).enable_all().build().unwrap();
+        runtime.block_on(nick_and_join_stress());
+        done_tx.send(()).unwrap();
+    });
+    done_rx.recv_timeout(Duration::from_secs(60)).expect("nick changes and joins deadlocked");
+}
+
+async fn nick_and_join_stress() {
+    let irc = core_with(Config { nick_change_limit: usize::MAX, ..config() });
+    let mut tasks = Vec::new();
+    for n in 0..8 {
+        let mut client = TestClient::register(&irc, &format!("s{}", n)).await;
+        tasks.push(tokio::spawn(async move {
+            for round in 0..25 {
+                for k in 0..4 {
+                    client.send(&format!("NICK s{}r{}k{}", n, round % 3, k)).await;
+                    client.send(&format!("JOIN #stress{}", (n + k) % 3)).await;
+                    client.send(&format!("PART #stress{}", (n + k + 1) % 3)).await;
+                }
+                /* reading as we go, so nobody's socket fills up */
+                client.sync().await;
+            }
+            client
+        }));
+    }
+    let mut clients = Vec::new();
+    for task in tasks {
+        clients.push(task.await.unwrap());
+    }
+
+    assert_eq!(irc.check_consistency(), 0);
+    for n in 0..8 {
+        let nick = format!("s{}r{}k3", n, 24 % 3);
+        let user = user(&irc, &nick);
+        for chan in user.get_channel_list().iter().filter_map(Weak::upgrade) {
+            assert!(chan.is_joined(&nick), "{} lost track of {}", chan.get_name(), nick);
+        }
+    }
+    for chan_name in ["#stress0", "#stress1", "#stress2"].iter() {
+        if let Ok(chan) = irc.get_chan(chan_name) {
+            for member in chan.gen_user_ptr_vec().iter() {
+                assert!(chan.is_joined(&member.get_nick()));
+                assert!(irc.get_nick(&member.get_nick()).is_some());
+            }
+        }
+    }
+}
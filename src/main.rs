@@ -22,25 +22,121 @@ pub mod irc;
 pub mod client;
 pub mod io;
 pub mod parser;
-use crate::client::{run_client_handler, run_write_task, Host, GenError};
+use crate::client::{run_client_handler, run_ping_task, run_write_task, Host, GenError, Utf8Policy};
 use crate::io::{ReadHalfWrap, WriteHalfWrap};
-use crate::irc::Core;
+use crate::irc::{password, state, Config, Core, ShutdownKind};
 use dns_lookup::lookup_addr;
+use log::{info, warn};
+use std::env;
+use std::fs;
 use std::fs::File;
 use std::io::Error as ioError;
 use std::io::Read;
 use std::net::IpAddr;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::split;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
 use tokio::task;
+use tokio::time;
 use tokio_native_tls::TlsAcceptor;
 use tokio_native_tls::native_tls::Identity;
 use tokio_native_tls::native_tls::TlsAcceptor as NativeTlsAcc;
 
-pub const USER_MODES: &str = "";
-pub const CHAN_MODES: &str = "+o";
+/* read at startup and again on REHASH, one `key value` a line. Most of
+ * the settings below can be set there, under the same name in lower case
+ * (plus `oper <name> <hash>` and `link <name> <hash> <password>` lines and
+ * `password` for SERVER_PASSWORD), and the consts are just the defaults
+ * for anything it leaves out. See Config in irc.rs for the full list */
+pub const CONFIG_PATH: &str = "ircd.conf";
+pub const USER_MODES: &str = "iow";
+/* the network we say we're part of in ISUPPORT (NETWORK=), and a line
+ * about this server for VERSION and WHOIS */
+pub const NETWORK_NAME: &str = "Rusty";
+pub const SERVER_INFO: &str = "a rusty-ircd server";
+pub const CHAN_MODES: &str = "beIiklmnoPstv";
+/* IRCv3 capabilities offered in CAP LS */
+pub const CAPABILITIES: &[&str] = &["multi-prefix"];
+pub const CONSISTENCY_CHECK_SECS: u64 = 300;
+/* an INVITE that hasn't been used after this long no longer gets anyone
+ * past +i, stale ones are swept up with the consistency check */
+pub const INVITE_EXPIRY_SECS: u64 = 3600;
+/* whether opers get to see +i users in WHO, turn off for networks that
+ * would rather opers didn't */
+pub const OPERS_SEE_INVISIBLE: bool = true;
+/* what to do with a NICK longer than NICKLEN - refuse it, or cut it down
+ * to size and take that if it's free (some gateways expect the latter) */
+pub const TRUNCATE_LONG_NICKS: bool = false;
+/* most targets a single JOIN, PART, PRIVMSG or NOTICE will go through,
+ * the rest get ERR_TOOMANYTARGETS and are ignored */
+pub const MAX_TARGETS: usize = 20;
+/* how many departed users WHOWAS remembers, oldest forgotten first */
+pub const WHOWAS_HISTORY: usize = 100;
+/* how many masks a user can have on their SILENCE list */
+pub const MAX_SILENCE: usize = 15;
+/* non-opers get NICK_CHANGE_LIMIT nick changes every
+ * NICK_CHANGE_WINDOW_SECS, any more are refused with a 438 */
+pub const NICK_CHANGE_LIMIT: usize = 3;
+pub const NICK_CHANGE_WINDOW_SECS: u64 = 60;
+/* the same for KNOCK, KNOCK_LIMIT every KNOCK_WINDOW_SECS per user, and
+ * on top of that a channel's ops only get one every KNOCK_CHANNEL_DELAY_SECS */
+pub const KNOCK_LIMIT: usize = 3;
+pub const KNOCK_WINDOW_SECS: u64 = 300;
+pub const KNOCK_CHANNEL_DELAY_SECS: u64 = 60;
+/* how many channels one user can be in at once */
+pub const MAX_CHANNELS: usize = 20;
+/* put in front of the reason a user gives in their QUIT, so nobody can
+ * make their quit look like a server one (e.g. "Ping timeout"), "" for none */
+pub const QUIT_PREFIX: &str = "Quit: ";
+/* flood control: a registered client gets FLOOD_BURST lines straight off,
+ * then FLOOD_RATE a second. Lines over that are dropped with a NOTICE, and
+ * once FLOOD_EXCESS have been dropped in a row the client is thrown off */
+pub const FLOOD_RATE: f64 = 2.0;
+pub const FLOOD_BURST: f64 = 10.0;
+pub const FLOOD_EXCESS: u32 = 20;
+pub const PING_INTERVAL_SECS: u64 = 120;
+pub const PING_TIMEOUT_SECS: u64 = 60;
+/* how long a dropped connection's session is held for a RESUME (give or
+ * take a PING interval). Resumption is opt-in: the default of 0 leaves it
+ * switched off, so no RESUME TOKEN is handed out at registration */
+pub const RESUME_WINDOW_SECS: u64 = 0;
+/* lines that aren't valid UTF-8 are either patched up with U+FFFD (Lossy)
+ * or thrown away with a NOTICE to the sender (Strict) */
+pub const UTF8_POLICY: Utf8Policy = Utf8Policy::Lossy;
+/* OPER <name> <password> credentials, the password hashed rather than in
+ * the clear, as printed by `rusty-ircd --hash-password <password>` (see
+ * irc::password). Empty means nobody can become an oper */
+pub const OPERATORS: &[(&str, &str)] = &[];
+/* servers allowed to link with us: (server name, hash of the password
+ * they send us with PASS, the password we send them back with ours).
+ * Empty means nobody gets to link */
+pub const SERVER_LINKS: &[(&str, &str, &str)] = &[];
+/* a connection password clients have to give with PASS before they can
+ * register, hashed the same way as the OPERATORS ones. None for no password */
+pub const SERVER_PASSWORD: Option<&str> = None;
+/* show everyone a hashed cloak (e.g. user-1a2b3c4d5e6f7a8b.cloak) instead
+ * of a user's real host, opers still get to see the real one in WHOIS */
+pub const CLOAK_HOSTS: bool = false;
+/* mixed into the cloak hash so nobody can work a cloak back to an IP by
+ * hashing every address themselves. Cloaks stay off until there is one,
+ * set it as cloak_secret in ircd.conf rather than here */
+pub const CLOAK_SECRET: Option<&str> = None;
+/* what ADMIN tells people: where the server is, anything else worth
+ * saying about it, and who to email. None gets a placeholder instead */
+pub const ADMIN_LOCATION: Option<&str> = None;
+pub const ADMIN_LOCATION2: Option<&str> = None;
+pub const ADMIN_EMAIL: Option<&str> = None;
+/* where operator actions are recorded */
+pub const AUDIT_LOG_PATH: &str = "audit.log";
+/* read once at startup, a missing file just means no MOTD */
+pub const MOTD_PATH: &str = "motd.txt";
+/* SIGUSR1 dumps users and channels here, and if it's present at startup
+ * it's read back in (and removed), see irc::state */
+pub const STATE_FILE_PATH: &str = "ircd.state";
 
 fn get_host(ip_addr: IpAddr) -> Result<Host, ioError> {
     match lookup_addr(&ip_addr) {
@@ -97,11 +193,86 @@ async fn process_socket(sock: TcpStream, irc: Arc<Core>, acceptor: Arc<TlsAccept
     Ok(())
 }
 
+/* periodically look for and repair any user<->channel relationships that
+ * have ended up one-sided */
+async fn consistency_check(irc: Arc<Core>) {
+    let mut timer = time::interval(Duration::from_secs(CONSISTENCY_CHECK_SECS));
+    loop {
+        timer.tick().await;
+        let repairs = irc.check_consistency();
+        if repairs > 0 {
+            warn!("consistency check made {} repairs", repairs);
+        }
+        irc.prune_invites();
+    }
+}
+
+/* write out the namespace whenever we get a SIGUSR1, ready for the next
+ * process to pick up */
+async fn dump_state_on_signal(irc: Arc<Core>) -> Result<(), ioError> {
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+    while sigusr1.recv().await.is_some() {
+        match fs::write(STATE_FILE_PATH, state::export(&irc)) {
+            Ok(()) => info!("state written to {}", STATE_FILE_PATH),
+            Err(err) => warn!("couldn't write state to {}: {}", STATE_FILE_PATH, err),
+        }
+    }
+    Ok(())
+}
+
+/* on SIGTERM, tell everyone we're going and give the write tasks a
+ * moment to get that out before exiting */
+async fn shutdown_on_signal(irc: Arc<Core>) -> Result<(), ioError> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    if sigterm.recv().await.is_some() {
+        info!("got SIGTERM, shutting down");
+        irc.shutdown("Terminated").await;
+        time::sleep(Duration::from_secs(1)).await;
+        std::process::exit(0);
+    }
+    Ok(())
+}
+
+/* same again for an oper's DIE or RESTART, which has already told
+ * everyone. RESTART execs ourselves again with the same arguments, our
+ * sockets are all close-on-exec so the new process can bind afresh */
+async fn exit_on_die(irc: Arc<Core>) {
+    let kind = irc.wait_for_shutdown().await;
+    time::sleep(Duration::from_secs(1)).await;
+    if kind == ShutdownKind::Restart {
+        info!("got RESTART, re-executing");
+        let mut args = env::args_os();
+        let exe = match env::current_exe() {
+            Ok(exe) => exe,
+            Err(err) => {
+                warn!("can't find our own executable to restart: {}", err);
+                std::process::exit(1);
+            }
+        };
+        args.next();
+        let err = Command::new(exe).args(args).exec();
+        warn!("couldn't restart: {}", err);
+        std::process::exit(1);
+    }
+    info!("got DIE, shutting down");
+    std::process::exit(0);
+}
+
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let version = env!("CARGO_PKG_NAME").to_string() + ", version: " + env!("CARGO_PKG_VERSION");
     env_logger::init();
 
+    // `--hash-password <password>` prints the hash to put in the config
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--hash-password") {
+        match args.get(2) {
+            Some(password) => println!("{}", password::hash_password(password)?),
+            None => eprintln!("usage: {} --hash-password <password>", args[0]),
+        }
+        return Ok(());
+    }
+
     // is this even necessary?
     let server_host = if let Ok(ip) = "127.0.1.1".parse::<IpAddr>() {
         if let Host::Hostname(h) = task::spawn_blocking(move ||get_host(ip)).await?? {
@@ -112,7 +283,20 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     } else {
         "localhost".to_string()
     };
-    let irc_core = Core::new(server_host, version);
+    let irc_core = Core::new(server_host, version, Config::load());
+
+    // pick up where a previous process left off, if it left us anything
+    if let Ok(blob) = fs::read_to_string(STATE_FILE_PATH) {
+        match state::import(&irc_core, &blob) {
+            Ok(n_users) => info!("restored {} users from {}", n_users, STATE_FILE_PATH),
+            Err(err) => warn!("couldn't restore state from {}: {}", STATE_FILE_PATH, err),
+        }
+        /* not being able to remove it isn't worth refusing to start over,
+         * but it will be read in again next time */
+        if let Err(err) = fs::remove_file(STATE_FILE_PATH) {
+            warn!("couldn't remove {} after restoring from it: {}", STATE_FILE_PATH, err);
+        }
+    }
 
     // encryption key stuff
     let mut file = File::open("identity.pfx").unwrap();
@@ -127,6 +311,22 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // spawn routine to deal with plaintext clients
     tokio::spawn(plain_listen(plain_listener, Arc::clone(&irc_core)));
 
+    tokio::spawn(dump_state_on_signal(Arc::clone(&irc_core)));
+
+    tokio::spawn(shutdown_on_signal(Arc::clone(&irc_core)));
+
+    tokio::spawn(exit_on_die(Arc::clone(&irc_core)));
+
+    // and the routine bookkeeping check
+    tokio::spawn(consistency_check(Arc::clone(&irc_core)));
+
+    // keep connections alive and weed out the dead ones
+    tokio::spawn(run_ping_task(
+        Arc::clone(&irc_core),
+        Duration::from_secs(PING_INTERVAL_SECS),
+        Duration::from_secs(PING_TIMEOUT_SECS),
+    ));
+
     // first create the non-async TlsAcceptor
     let acceptor = NativeTlsAcc::new(identity).unwrap();
 
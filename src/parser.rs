@@ -16,6 +16,8 @@
 */
 use crate::irc::rfc_defs as rfc;
 use std::{error, fmt};
+use std::collections::HashMap;
+use std::net::IpAddr;
 
 #[derive(Debug)]
 pub enum ParseError {
@@ -29,6 +31,7 @@ pub enum ParseError {
     EmptyNick,
     EmptyHost,
     EmptyUser,
+    InvalidTags(String),
 }
 
 impl error::Error for ParseError {}
@@ -52,6 +55,7 @@ impl fmt::Display for ParseError {
             ParseError::InvalidNick(nick) => write!(f, "Invalid nick: {}", &nick),
             ParseError::InvalidUser(user) => write!(f, "Invalid user string: {}", &user),
             ParseError::InvalidHost(host) => write!(f, "Invalid host string: {}", &host),
+            ParseError::InvalidTags(tags) => write!(f, "Invalid message tags: {}", &tags),
         }
     }
 }
@@ -65,6 +69,8 @@ pub enum MsgPrefix {
 }
 
 pub struct ParsedMsg {
+    // IRCv3 message tags, a bare `key` with no `=value` maps to None
+    pub tags: HashMap<String, Option<String>>,
     pub opt_prefix: Option<MsgPrefix>,
     pub command: String,
     // NB: our parser first makes a Vec<&str>, where things will still point to stuff
@@ -78,20 +84,31 @@ pub struct ParsedMsg {
 // Use iterators to tokenize on SPACE but note also
 // the position of the first " :" -- important
 //    Augmented BNF notation for general message strcture
-//    message    =  [ ":" prefix SPACE ] command [ params ]
+//    message    =  [ "@" tags SPACE ] [ ":" prefix SPACE ] command [ params ]
 pub fn parse_message(message: &str) -> Result<ParsedMsg, ParseError> {
-    let mut line = message;
+    let message = rfc::sanitize_message_text(message);
+    let mut line = &message[..];
     if line.is_empty() {
         return Err(ParseError::EmptyMessage);
     }
-    let opt_prefix = if &message[..1] == ":" {
+    let tags = if line.starts_with('@') {
+        let vec: Vec<&str> = line.splitn(2, ' ').collect();
+        if vec.len() < 2 || vec[1].is_empty() {
+            return Err(ParseError::NoCommand);
+        }
+        line = vec[1];
+        parse_tags(&vec[0][1..])?
+    } else {
+        HashMap::new()
+    };
+    let opt_prefix = if line.starts_with(':') {
         // try for prefix
         let vec: Vec<&str> = line.splitn(2, ' ').collect();
         if vec.len() < 2 {
             return Err(ParseError::NoCommand);
         }
         line = vec[1];
-        Some(parse_prefix(&vec[0])?)
+        Some(parse_prefix(&vec[0][1..])?)
     } else {
         None
     };
@@ -112,7 +129,7 @@ pub fn parse_message(message: &str) -> Result<ParsedMsg, ParseError> {
         // is reached
         if line.is_empty() {
             break;
-        } else if &line[..1] == ":" {
+        } else if line.starts_with(':') {
             line = &line[1..line.len()];
             params.push(line.to_string());
             break;
@@ -121,17 +138,63 @@ pub fn parse_message(message: &str) -> Result<ParsedMsg, ParseError> {
             break;
         }
     }
-    /* should be safe - above code ensure non-zero length of params */
+    /* should be safe - above code ensure non-zero length of params, but
+     * a line like ":x " leaves it with an empty command */
     let command = params.remove(0);
+    if command.is_empty() {
+        return Err(ParseError::NoCommand);
+    }
 
     // return the stuff
     Ok(ParsedMsg {
+        tags,
         opt_prefix,
         command,
         opt_params: params,
     })
 }
 
+// parse the `key=value;key2` tag part of a message, with the leading '@'
+// and delimiting space stripped off
+//    tags       =  tag *[ ";" tag ]
+//    tag        =  key [ "=" escaped_value ]
+fn parse_tags(msg: &str) -> Result<HashMap<String, Option<String>>, ParseError> {
+    let mut tags = HashMap::new();
+    for tag in msg.split(';') {
+        let pair: Vec<&str> = tag.splitn(2, '=').collect();
+        let key = pair[0];
+        if !rfc::valid_tag_key(key) {
+            return Err(ParseError::InvalidTags(msg.to_string()));
+        }
+        // an empty value is the same as no value at all
+        let value = pair.get(1).filter(|value| !value.is_empty()).map(|value| unescape_tag_value(value));
+        tags.insert(key.to_string(), value);
+    }
+    Ok(tags)
+}
+
+// values can't contain ';', spaces or CR/LF as-is so they get backslash escaped,
+// unknown escapes just lose the backslash and a trailing lone one is dropped
+fn unescape_tag_value(value: &str) -> String {
+    let mut ret = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => ret.push(';'),
+            Some('s') => ret.push(' '),
+            Some('r') => ret.push('\r'),
+            Some('n') => ret.push('\n'),
+            Some(other) => ret.push(other),
+            None => (),
+        }
+    }
+    ret
+}
+
 // parse the prefix part of an IRC message
 // with preceding colon and delimiting space stripped off
 fn parse_prefix(msg: &str) -> Result<MsgPrefix, ParseError> {
@@ -184,17 +247,78 @@ fn parse_prefix(msg: &str) -> Result<MsgPrefix, ParseError> {
 
 // this host parsing code will assign whether we have a regular hostname (and if it's valid),
 // or an ipv4/ipv6 address
-// decided not to use net::IpAddr here in the parser,
-// addresses may possibly be converted into proper formats elsewhere if needed
+// std's IpAddr parsing does the address checks for us, but we still hand
+// back the string, addresses may possibly be converted into proper formats
+// elsewhere if needed
 fn parse_host(host_string: &str) -> Result<HostType, ParseError> {
     let host = host_string.to_string();
-    if rfc::valid_ipv4_addr(&host) {
-        Ok(HostType::HostAddrV4(host))
-    } else if rfc::valid_ipv6_addr(&host) {
-        Ok(HostType::HostAddrV6(host))
-    } else if rfc::valid_hostname(&host) {
-        Ok(HostType::HostName(host))
-    } else {
-        Err(ParseError::InvalidHost(host))
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_addr)) => Ok(HostType::HostAddrV4(host)),
+        Ok(IpAddr::V6(_addr)) => Ok(HostType::HostAddrV6(host)),
+        Err(_) if rfc::valid_hostname(&host) => Ok(HostType::HostName(host)),
+        Err(_) => Err(ParseError::InvalidHost(host)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(line: &str) -> MsgPrefix {
+        parse_message(line).ok().and_then(|msg| msg.opt_prefix).unwrap()
+    }
+
+    #[test]
+    fn prefixes_of_every_shape() {
+        // good as a nick or a host, so we can't say which
+        assert!(matches!(prefix(":nick PING x"), MsgPrefix::Name(name) if name == "nick"));
+        // [] aren't allowed in hostnames
+        assert!(matches!(prefix(":ni[ck] PING x"), MsgPrefix::Nick(nick) if nick == "ni[ck]"));
+        assert!(matches!(
+            prefix(":nick@host.com PING x"),
+            MsgPrefix::NickHost(nick, HostType::HostName(host)) if nick == "nick" && host == "host.com"
+        ));
+        assert!(matches!(
+            prefix(":nick!user@127.0.0.1 PING x"),
+            MsgPrefix::NickUserHost(nick, user, HostType::HostAddrV4(host))
+                if nick == "nick" && user == "user" && host == "127.0.0.1"
+        ));
+        assert!(matches!(
+            prefix(":nick!user@::1 PING x"),
+            MsgPrefix::NickUserHost(_nick, _user, HostType::HostAddrV6(host)) if host == "::1"
+        ));
+        assert!(matches!(prefix(":server.name PING x"), MsgPrefix::Host(HostType::HostName(host)) if host == "server.name"));
+    }
+
+    #[test]
+    fn broken_prefixes_are_errors() {
+        assert!(matches!(parse_message(": PING x"), Err(ParseError::EmptyName)));
+        assert!(matches!(parse_message(":!user@host PING x"), Err(ParseError::EmptyNick)));
+        assert!(matches!(parse_message(":nick!@host PING x"), Err(ParseError::EmptyUser)));
+        assert!(matches!(parse_message(":nick!user@ PING x"), Err(ParseError::EmptyHost)));
+        assert!(matches!(parse_message(":9nick@host PING x"), Err(ParseError::InvalidNick(_nick))));
+        assert!(matches!(parse_message(":bad_host! PING x"), Err(ParseError::InvalidHost(_host))));
+    }
+
+    /* nothing in a parsed message can end the line early for whoever it's
+     * relayed on to */
+    #[test]
+    fn no_line_breaks_in_params() {
+        let msg = parse_message("PRIVMSG bob :hi\r\nKILL x").unwrap();
+        assert_eq!(msg.opt_params, vec!["bob", "hiKILL x"]);
+        let msg = parse_message("PRIVMSG b\0ob :\r\n").unwrap();
+        assert_eq!(msg.opt_params, vec!["bob", ""]);
+    }
+
+    /* short and empty lines are errors, never a panic */
+    #[test]
+    fn tiny_lines_dont_panic() {
+        assert!(matches!(parse_message(""), Err(ParseError::EmptyMessage)));
+        assert!(matches!(parse_message(":"), Err(ParseError::NoCommand)));
+        assert!(matches!(parse_message(":x"), Err(ParseError::NoCommand)));
+        assert!(matches!(parse_message(":x "), Err(ParseError::NoCommand)));
+        assert!(matches!(parse_message("@"), Err(ParseError::NoCommand)));
+        assert!(matches!(parse_message("\r\n"), Err(ParseError::EmptyMessage)));
+        assert_eq!(parse_message("x").unwrap().command, "x");
     }
 }